@@ -0,0 +1,589 @@
+use crate::bytecode::*;
+use crate::compiler::Globals;
+use crate::constants::*;
+use crate::program::Program;
+use std::collections::HashMap;
+
+/**
+ * Token of the assembly. Comments start with ';' and are skipped.
+ */
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Word(String),
+    Index(ConstantPoolIndex),
+    String(String),
+    Equals,
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => break,
+            '=' => tokens.push(Token::Equals),
+            '"' => {
+                let mut str = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(String::from("Unterminated string.")),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => str.push('\n'),
+                            Some('t') => str.push('\t'),
+                            Some('r') => str.push('\r'),
+                            Some('0') => str.push('\0'),
+                            Some(c @ ('\\' | '"' | '\'')) => str.push(c),
+                            Some('u') => {
+                                let hex: String = chars
+                                    .by_ref()
+                                    .skip_while(|c| *c == '{')
+                                    .take_while(|c| *c != '}')
+                                    .collect();
+                                let c = u32::from_str_radix(&hex, 16)
+                                    .ok()
+                                    .and_then(char::from_u32)
+                                    .ok_or_else(|| format!("Invalid unicode escape '{}'.", hex))?;
+                                str.push(c);
+                            }
+                            c => return Err(format!("Invalid escape sequence {:?}.", c)),
+                        },
+                        Some(c) => str.push(c),
+                    }
+                }
+                tokens.push(Token::String(str));
+            }
+            c if c.is_whitespace() => (),
+            c => {
+                let mut word = String::from(c);
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, ';' | '=' | '"') {
+                        break;
+                    }
+                    word.push(*c);
+                    chars.next();
+                }
+                match word.strip_prefix('#') {
+                    Some(index) => tokens.push(Token::Index(
+                        index
+                            .parse()
+                            .map_err(|_| format!("Invalid constant index '{}'.", word))?,
+                    )),
+                    None => tokens.push(Token::Word(word)),
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/**
+ * Operand referring to the constant pool, resolved once all constants are known.
+ */
+#[derive(Debug, Clone)]
+enum Operand {
+    Index(ConstantPoolIndex),
+    Name(String),
+    String(String),
+}
+
+struct Assembler {
+    pool: ConstantPool,
+    names: HashMap<String, ConstantPoolIndex>,
+}
+
+impl Assembler {
+    /**
+     * Bare words are names of constants, or names of labels, which are strings.
+     */
+    fn resolve(&mut self, operand: &Operand, is_label: bool) -> Result<ConstantPoolIndex, String> {
+        let str = match operand {
+            Operand::Index(index) if *index < self.pool.len() => return Ok(*index),
+            Operand::Index(index) => return Err(format!("Constant #{} doesn't exist.", index)),
+            Operand::Name(name) => match self.names.get(name) {
+                Some(index) => return Ok(*index),
+                None if is_label => name,
+                None => return Err(format!("Constant '{}' is not defined.", name)),
+            },
+            Operand::String(str) => str,
+        };
+        Ok(self.pool.push(Constant::from(str.clone())))
+    }
+}
+
+fn operand(token: Option<&Token>) -> Result<Operand, String> {
+    match token {
+        Some(Token::Index(index)) => Ok(Operand::Index(*index)),
+        Some(Token::Word(name)) => Ok(Operand::Name(name.clone())),
+        Some(Token::String(str)) => Ok(Operand::String(str.clone())),
+        _ => Err(String::from("Expected a constant.")),
+    }
+}
+
+fn parse<T: std::str::FromStr>(token: Option<&Token>) -> Result<T, String> {
+    match token {
+        Some(Token::Word(word)) => word
+            .parse()
+            .map_err(|_| format!("Invalid value '{}'.", word)),
+        _ => Err(String::from("Expected a value.")),
+    }
+}
+
+fn keyword(token: Option<&Token>, expected: &str) -> Result<(), String> {
+    match token {
+        Some(Token::Word(word)) if word == expected => Ok(()),
+        _ => Err(format!("Expected '{}'.", expected)),
+    }
+}
+
+/**
+ * Instruction with operands not resolved yet. The closure builds it from
+ * the resolved constant indexes.
+ */
+type PendingInstruction = (
+    Vec<(Operand, bool)>,
+    Box<dyn Fn(&[ConstantPoolIndex]) -> Bytecode>,
+);
+
+fn instruction(tokens: &[Token]) -> Result<PendingInstruction, String> {
+    let mnemonic = match tokens.first() {
+        Some(Token::Word(word)) => word.as_str(),
+        _ => return Err(String::from("Expected an instruction.")),
+    };
+    let constant = || operand(tokens.get(1));
+    let label = || Ok::<_, String>(vec![(operand(tokens.get(1))?, true)]);
+    let arguments = || parse::<ArgsCount>(tokens.get(2));
+    let local = || parse::<LocalFrameIndex>(tokens.get(1));
+    let target = || parse::<u32>(tokens.get(1));
+
+    let expected_len = match mnemonic {
+        "array" | "return" | "drop" | "throw" => 1,
+        "add" | "sub" | "mul" | "div" | "mod" | "lt" | "le" | "gt" | "ge" | "eq" | "neq" => 1,
+        "call_method" | "call_function" | "print" => 3,
+        "inc_local" | "compare_locals_branch" => 3,
+        _ => 2,
+    };
+    if tokens.len() != expected_len {
+        return Err(format!(
+            "Instruction '{}' takes {} operands.",
+            mnemonic,
+            expected_len - 1
+        ));
+    }
+
+    let pending: PendingInstruction = match mnemonic {
+        "literal" => (
+            vec![(constant()?, false)],
+            Box::new(|i| Bytecode::Literal { index: i[0] }),
+        ),
+        "get_local" => {
+            let index = local()?;
+            (vec![], Box::new(move |_| Bytecode::GetLocal { index }))
+        }
+        "set_local" => {
+            let index = local()?;
+            (vec![], Box::new(move |_| Bytecode::SetLocal { index }))
+        }
+        "get_global" => (
+            vec![(constant()?, false)],
+            Box::new(|i| Bytecode::GetGlobal { name: i[0] }),
+        ),
+        "set_global" => (
+            vec![(constant()?, false)],
+            Box::new(|i| Bytecode::SetGlobal { name: i[0] }),
+        ),
+        "object" => (
+            vec![(constant()?, false)],
+            Box::new(|i| Bytecode::Object { class: i[0] }),
+        ),
+        "array" => (vec![], Box::new(|_| Bytecode::Array)),
+        "get_field" => (
+            vec![(constant()?, false)],
+            Box::new(|i| Bytecode::GetField { name: i[0] }),
+        ),
+        "set_field" => (
+            vec![(constant()?, false)],
+            Box::new(|i| Bytecode::SetField { name: i[0] }),
+        ),
+        "call_method" => {
+            let arguments = arguments()?;
+            (
+                vec![(constant()?, false)],
+                Box::new(move |i| Bytecode::CallMethod {
+                    name: i[0],
+                    arguments,
+                }),
+            )
+        }
+        "call_function" => {
+            let arguments = arguments()?;
+            (
+                vec![(constant()?, false)],
+                Box::new(move |i| Bytecode::CallFunction {
+                    name: i[0],
+                    arguments,
+                }),
+            )
+        }
+        "print" => {
+            let arguments = arguments()?;
+            (
+                vec![(constant()?, false)],
+                Box::new(move |i| Bytecode::Print {
+                    format: i[0],
+                    arguments,
+                }),
+            )
+        }
+        "label" => (label()?, Box::new(|i| Bytecode::Label { name: i[0] })),
+        "jump" => (label()?, Box::new(|i| Bytecode::Jump { label: i[0] })),
+        "branch" => (label()?, Box::new(|i| Bytecode::Branch { label: i[0] })),
+        "branch_false" => (
+            label()?,
+            Box::new(|i| Bytecode::BranchFalse { label: i[0] }),
+        ),
+        "jump_to" => {
+            let target = target()?;
+            (vec![], Box::new(move |_| Bytecode::JumpTo { target }))
+        }
+        "branch_to" => {
+            let target = target()?;
+            (vec![], Box::new(move |_| Bytecode::BranchTo { target }))
+        }
+        "branch_false_to" => {
+            let target = target()?;
+            (vec![], Box::new(move |_| Bytecode::BranchFalseTo { target }))
+        }
+        "return" => (vec![], Box::new(|_| Bytecode::Return)),
+        "drop" => (vec![], Box::new(|_| Bytecode::Drop)),
+        "throw" => (vec![], Box::new(|_| Bytecode::Throw)),
+        "add" => (vec![], Box::new(|_| Bytecode::Add)),
+        "sub" => (vec![], Box::new(|_| Bytecode::Sub)),
+        "mul" => (vec![], Box::new(|_| Bytecode::Mul)),
+        "div" => (vec![], Box::new(|_| Bytecode::Div)),
+        "mod" => (vec![], Box::new(|_| Bytecode::Mod)),
+        "lt" => (vec![], Box::new(|_| Bytecode::Lt)),
+        "le" => (vec![], Box::new(|_| Bytecode::Le)),
+        "gt" => (vec![], Box::new(|_| Bytecode::Gt)),
+        "ge" => (vec![], Box::new(|_| Bytecode::Ge)),
+        "eq" => (vec![], Box::new(|_| Bytecode::Eq)),
+        "neq" => (vec![], Box::new(|_| Bytecode::Neq)),
+        "inc_local" => {
+            let index = local()?;
+            (
+                vec![(operand(tokens.get(2))?, false)],
+                Box::new(move |i| Bytecode::IncLocal {
+                    index,
+                    amount: i[0],
+                }),
+            )
+        }
+        "compare_locals_branch" => {
+            let left = local()?;
+            let right = parse::<LocalFrameIndex>(tokens.get(2))?;
+            (
+                vec![],
+                Box::new(move |_| Bytecode::CompareLocalsBranch { left, right }),
+            )
+        }
+        _ => return Err(format!("Unknown instruction '{}'.", mnemonic)),
+    };
+    Ok(pending)
+}
+
+enum Pending {
+    Ready(Constant),
+    Slot(Operand),
+    Object(Vec<Operand>),
+    Function {
+        name: Operand,
+        parameters: u8,
+        locals: u16,
+        code: Vec<PendingInstruction>,
+        // Start, end and handler labels, and the stack height
+        handlers: Vec<([Operand; 3], u16)>,
+        resolved_handlers: Vec<ResolvedHandler>,
+    },
+}
+
+/**
+ * Parses one constant definition, without the instructions of functions.
+ */
+fn constant(tokens: &[Token]) -> Result<Pending, String> {
+    let kind = match tokens.first() {
+        Some(Token::Word(kind)) => kind.as_str(),
+        _ => return Err(String::from("Expected a constant kind.")),
+    };
+    let pending = match kind {
+        "integer" => Pending::Ready(Constant::Integer(parse(tokens.get(1))?)),
+        "long" => Pending::Ready(Constant::Long(parse(tokens.get(1))?)),
+        "float" => Pending::Ready(Constant::Float(parse(tokens.get(1))?)),
+        "boolean" => Pending::Ready(Constant::Boolean(parse(tokens.get(1))?)),
+        "null" => Pending::Ready(Constant::Null),
+        "string" => match tokens.get(1) {
+            Some(Token::String(str)) => Pending::Ready(Constant::String(str.clone())),
+            _ => return Err(String::from("Expected a string.")),
+        },
+        "slot" => Pending::Slot(operand(tokens.get(1))?),
+        "object" => Pending::Object(
+            tokens[1..]
+                .iter()
+                .map(|token| operand(Some(token)))
+                .collect::<Result<_, _>>()?,
+        ),
+        "function" => {
+            keyword(tokens.get(2), "params")?;
+            keyword(tokens.get(4), "locals")?;
+            Pending::Function {
+                name: operand(tokens.get(1))?,
+                parameters: parse(tokens.get(3))?,
+                locals: parse(tokens.get(5))?,
+                code: Vec::new(),
+                handlers: Vec::new(),
+                resolved_handlers: Vec::new(),
+            }
+        }
+        _ => return Err(format!("Unknown constant kind '{}'.", kind)),
+    };
+    Ok(pending)
+}
+
+/**
+ * Builds a program from the assembly, in the format printed by the disassembler:
+ *
+ *   constant <name> = <kind> <operands>
+ *       <instructions of a function>
+ *       handler <start> <end> <handler> stack <height>
+ *   end
+ *   globals <constants>
+ *   entry <constant>
+ *
+ * Constants are referred to as `#index`, by their name, or by a string
+ * literal, which adds the string to the pool. Labels can also be given
+ * by their bare names.
+ */
+pub fn assemble(text: &str) -> Result<Program, String> {
+    let mut constants: Vec<Pending> = Vec::new();
+    let mut names = HashMap::new();
+    let mut globals = Vec::new();
+    let mut entry = None;
+    let mut in_function = false;
+
+    for (line_number, line) in text.lines().enumerate() {
+        let error = |err: String| format!("Line {}: {}", line_number + 1, err);
+        let tokens = tokenize(line).map_err(error)?;
+        let first = match tokens.first() {
+            Some(Token::Word(word)) => word.as_str(),
+            Some(_) => return Err(error(String::from("Unexpected token."))),
+            None => continue,
+        };
+
+        if in_function {
+            let (code, handlers, resolved_handlers) = match constants.last_mut() {
+                Some(Pending::Function {
+                    code,
+                    handlers,
+                    resolved_handlers,
+                    ..
+                }) => (code, handlers, resolved_handlers),
+                _ => unreachable!(),
+            };
+            match first {
+                "end" => in_function = false,
+                "handler" => {
+                    keyword(tokens.get(4), "stack").map_err(error)?;
+                    let labels = [1, 2, 3].map(|i| operand(tokens.get(i)));
+                    let [start, end, handler] = labels;
+                    handlers.push((
+                        [
+                            start.map_err(error)?,
+                            end.map_err(error)?,
+                            handler.map_err(error)?,
+                        ],
+                        parse(tokens.get(5)).map_err(error)?,
+                    ));
+                }
+                "handler_at" => {
+                    keyword(tokens.get(4), "stack").map_err(error)?;
+                    resolved_handlers.push(ResolvedHandler {
+                        start: parse(tokens.get(1)).map_err(error)?,
+                        end: parse(tokens.get(2)).map_err(error)?,
+                        handler: parse(tokens.get(3)).map_err(error)?,
+                        stack: parse(tokens.get(5)).map_err(error)?,
+                    });
+                }
+                _ => code.push(instruction(&tokens).map_err(error)?),
+            }
+            continue;
+        }
+
+        match first {
+            "constant" => {
+                if tokens.get(2) != Some(&Token::Equals) {
+                    return Err(error(String::from("Expected 'constant <name> = ...'.")));
+                }
+                let name = match &tokens[1] {
+                    Token::Word(name) => name.clone(),
+                    _ => return Err(error(String::from("Expected a constant name."))),
+                };
+                // Numbered constants of the disassembler must stay where they were.
+                if name.parse::<usize>().is_ok_and(|i| i != constants.len()) {
+                    return Err(error(format!("Constant {} is out of order.", name)));
+                }
+                let pending = constant(&tokens[3..]).map_err(error)?;
+                in_function = matches!(pending, Pending::Function { .. });
+                names.insert(name, constants.len() as ConstantPoolIndex);
+                constants.push(pending);
+            }
+            "globals" => {
+                for token in tokens[1..].iter() {
+                    globals.push(operand(Some(token)).map_err(error)?);
+                }
+            }
+            "entry" => entry = Some(operand(tokens.get(1)).map_err(error)?),
+            _ => return Err(error(format!("Unexpected '{}'.", first))),
+        }
+    }
+    if in_function {
+        return Err(String::from("Function is missing its 'end'."));
+    }
+
+    // Declared constants keep their positions, the strings used as
+    // operands are added behind them.
+    let mut assembler = Assembler {
+        pool: ConstantPool::new(),
+        names,
+    };
+    for _ in constants.iter() {
+        assembler.pool.add(Constant::Null);
+    }
+    for (i, pending) in constants.iter().enumerate() {
+        let constant = match pending {
+            Pending::Ready(constant) => constant.clone(),
+            Pending::Slot(name) => Constant::Slot {
+                name: assembler.resolve(name, false)?,
+            },
+            Pending::Object(members) => Constant::Object {
+                members: members
+                    .iter()
+                    .map(|member| assembler.resolve(member, false))
+                    .collect::<Result<_, _>>()?,
+            },
+            Pending::Function {
+                name,
+                parameters,
+                locals,
+                code,
+                handlers,
+                resolved_handlers,
+            } => {
+                let name = assembler.resolve(name, false)?;
+                let mut function_code = Code::new();
+                for (operands, build) in code.iter() {
+                    let indexes = operands
+                        .iter()
+                        .map(|(operand, is_label)| assembler.resolve(operand, *is_label))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    function_code.write_inst(build(&indexes));
+                }
+                for ([start, end, handler], stack) in handlers.iter() {
+                    function_code.add_handler(ExceptionHandler {
+                        start: assembler.resolve(start, true)?,
+                        end: assembler.resolve(end, true)?,
+                        handler: assembler.resolve(handler, true)?,
+                        stack: *stack,
+                    });
+                }
+                function_code.resolved_handlers = resolved_handlers.clone();
+                Constant::Function {
+                    name,
+                    parameters: *parameters,
+                    locals: *locals,
+                    code: function_code,
+                }
+            }
+        };
+        assembler.pool.replace(i as ConstantPoolIndex, constant);
+    }
+
+    let mut program_globals = Globals::new();
+    for global in globals.iter() {
+        program_globals.introduce_variable(assembler.resolve(global, false)?);
+    }
+    let entry = match entry {
+        Some(entry) => assembler.resolve(&entry, false)?,
+        None => return Err(String::from("Entry point is missing.")),
+    };
+    Ok(Program {
+        pool: assembler.pool,
+        globals: program_globals,
+        entry,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::AST;
+    use crate::compiler::{compile, CompilerOptions};
+    use crate::debug::disassemble;
+    use crate::serializer::Serializable;
+
+    #[test]
+    fn round_trip_test() {
+        let ast: AST = serde_json::from_str(
+            r#"{"Top": [
+                {"Variable": {"name": "o", "value": {"Object": {"extends": "Null", "members": [
+                    {"Variable": {"name": "x", "value": {"Float": 0.5}}},
+                    {"Function": {"name": "m", "parameters": [], "body": {"Try": {
+                        "body": {"Throw": {"value": {"String": "a \"b\"\n"}}},
+                        "catch_var": "e", "handler": {"AccessVariable": {"name": "e"}},
+                        "finally": {"Print": {"format": "~\\n", "arguments": [{"Integer": 1}]}}}}}}
+                ]}}}},
+                {"Loop": {"condition": {"Boolean": false}, "body": {"CallMethod": {
+                    "object": {"AccessVariable": {"name": "o"}}, "name": "m", "arguments": []}}}}
+            ]}"#,
+        )
+        .unwrap();
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+        let assembled = assemble(&disassemble(&program)).unwrap();
+
+        let mut expected = Vec::new();
+        program.serializable_byte(&mut expected).unwrap();
+        let mut bytes = Vec::new();
+        assembled.serializable_byte(&mut bytes).unwrap();
+        assert_eq!(bytes, expected);
+
+        let ast: AST = serde_json::from_str(
+            r#"{"Top": [{"CallMethod": {"object": {"Integer": 1}, "name": "<",
+                "arguments": [{"Integer": 2}]}}]}"#,
+        )
+        .unwrap();
+        let options = CompilerOptions {
+            operators: true,
+            ..CompilerOptions::default()
+        };
+        let program = compile(&ast, &options).unwrap();
+        let assembled = assemble(&disassemble(&program)).unwrap();
+        let mut expected = Vec::new();
+        program.serializable_byte(&mut expected).unwrap();
+        let mut bytes = Vec::new();
+        assembled.serializable_byte(&mut bytes).unwrap();
+        assert_eq!(bytes, expected);
+
+        let handwritten = assemble(
+            "constant main = function \"main\" params 0 locals 0
+                label loop
+                literal one
+                print \"~\\n\" 1
+                drop
+                jump loop
+            end
+            constant one = integer 1
+            entry main",
+        )
+        .unwrap();
+        assert_eq!(handwritten.pool.len(), 5);
+        assert!(matches!(handwritten.pool.get(2), Constant::String(name) if name == "main"));
+    }
+}
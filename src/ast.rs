@@ -1,5 +1,7 @@
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
+use std::io::Read;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Identifier(pub String);
@@ -12,8 +14,10 @@ impl Identifier {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum AST {
-    Integer(i32),
+    Integer(i64),
+    Float(f64),
     Boolean(bool),
+    String(String),
     Null,
 
     Variable {
@@ -72,12 +76,45 @@ pub enum AST {
         arguments: Vec<Box<AST>>,
     },
 
+    And {
+        left: Box<AST>,
+        right: Box<AST>,
+    },
+    Or {
+        left: Box<AST>,
+        right: Box<AST>,
+    },
+    Not {
+        operand: Box<AST>,
+    },
+
     Top(Vec<Box<AST>>),
+    // Path to the module, relative to the importing file.
+    Import {
+        path: String,
+    },
     Block(Vec<Box<AST>>),
     Loop {
         condition: Box<AST>,
         body: Box<AST>,
     },
+    // for variable in from..to body
+    For {
+        variable: Identifier,
+        from: Box<AST>,
+        to: Box<AST>,
+        body: Box<AST>,
+    },
+    // for variable in array body, the array has to understand 'length'.
+    ForEach {
+        variable: Identifier,
+        array: Box<AST>,
+        body: Box<AST>,
+    },
+    Break {
+        value: Option<Box<AST>>,
+    },
+    Continue,
     Conditional {
         condition: Box<AST>,
         consequent: Box<AST>,
@@ -88,6 +125,238 @@ pub enum AST {
         format: String,
         arguments: Vec<Box<AST>>,
     },
+
+    Throw {
+        value: Box<AST>,
+    },
+    Try {
+        body: Box<AST>,
+        catch_var: Identifier,
+        handler: Box<AST>,
+        finally: Option<Box<AST>>,
+    },
+}
+
+impl AST {
+    /**
+     * Parses the tree from JSON. Machine-generated programs can be nested
+     * arbitrarily deep, so the usual recursion limit is lifted and the stack
+     * grows as needed instead.
+     */
+    pub fn from_json(text: &str) -> serde_json::Result<AST> {
+        let mut deserializer = serde_json::Deserializer::from_str(text);
+        deserializer.disable_recursion_limit();
+        let ast = AST::deserialize(serde_stacker::Deserializer::new(&mut deserializer))?;
+        deserializer.end()?;
+        Ok(ast)
+    }
+
+    /**
+     * Prints the tree as JSON. Like parsing, the stack grows as needed, so trees
+     * of any depth can be printed. Indentation would grow with the depth too,
+     * so the output is compact.
+     */
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        let mut output = Vec::new();
+        let mut serializer = serde_json::Serializer::new(&mut output);
+        self.serialize(serde_stacker::Serializer::new(&mut serializer))?;
+        Ok(String::from_utf8(output).expect("JSON is always valid UTF-8"))
+    }
+
+    /**
+     * Reads the program, `{"Top": [...]}`, from JSON and passes its items to
+     * the callback one by one as they are parsed. Only one item is in memory
+     * at a time. Errors of the callback are reported with the position of the
+     * item's end.
+     */
+    pub fn for_each_item<R: Read>(
+        input: R,
+        callback: impl FnMut(&AST) -> Result<(), String>,
+    ) -> serde_json::Result<()> {
+        let mut deserializer = serde_json::Deserializer::from_reader(input);
+        deserializer.disable_recursion_limit();
+        serde_stacker::Deserializer::new(&mut deserializer).deserialize_map(TopItems(callback))?;
+        deserializer.end()
+    }
+
+    /**
+     * Returns direct subtrees of the node in evaluation order.
+     */
+    pub fn children(&self) -> Vec<&AST> {
+        match self {
+            AST::Integer(_) | AST::Float(_) | AST::Boolean(_) | AST::String(_) | AST::Null => {
+                vec![]
+            }
+            AST::AccessVariable { .. } | AST::Import { .. } => vec![],
+            AST::Variable { value, .. } => vec![value],
+            AST::Array { size, value } => vec![size, value],
+            AST::Object { extends, members } => std::iter::once(&**extends)
+                .chain(members.iter().map(|m| &**m))
+                .collect(),
+            AST::AccessField { object, .. } => vec![object],
+            AST::AccessArray { array, index } => vec![array, index],
+            AST::AssignVariable { value, .. } => vec![value],
+            AST::AssignField { object, value, .. } => vec![object, value],
+            AST::AssignArray {
+                array,
+                index,
+                value,
+            } => vec![array, index, value],
+            AST::Function { body, .. } => vec![body],
+            AST::CallFunction { arguments, .. } => arguments.iter().map(|a| &**a).collect(),
+            AST::CallMethod {
+                object, arguments, ..
+            } => std::iter::once(&**object)
+                .chain(arguments.iter().map(|a| &**a))
+                .collect(),
+            AST::And { left, right } | AST::Or { left, right } => vec![left, right],
+            AST::Not { operand } => vec![operand],
+            AST::Top(asts) | AST::Block(asts) => asts.iter().map(|a| &**a).collect(),
+            AST::Loop { condition, body } => vec![condition, body],
+            AST::For { from, to, body, .. } => vec![from, to, body],
+            AST::ForEach { array, body, .. } => vec![array, body],
+            AST::Break { value } => value.iter().map(|v| &**v).collect(),
+            AST::Continue => vec![],
+            AST::Conditional {
+                condition,
+                consequent,
+                alternative,
+            } => vec![condition, consequent, alternative],
+            AST::Print { arguments, .. } => arguments.iter().map(|a| &**a).collect(),
+            AST::Throw { value } => vec![value],
+            AST::Try {
+                body,
+                handler,
+                finally,
+                ..
+            } => [body, handler]
+                .into_iter()
+                .chain(finally.iter())
+                .map(|a| &**a)
+                .collect(),
+        }
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<AST>> {
+        match self {
+            AST::Integer(_) | AST::Float(_) | AST::Boolean(_) | AST::String(_) | AST::Null => {
+                vec![]
+            }
+            AST::AccessVariable { .. } | AST::Import { .. } => vec![],
+            AST::Variable { value, .. } => vec![value],
+            AST::Array { size, value } => vec![size, value],
+            AST::Object { extends, members } => {
+                std::iter::once(extends).chain(members.iter_mut()).collect()
+            }
+            AST::AccessField { object, .. } => vec![object],
+            AST::AccessArray { array, index } => vec![array, index],
+            AST::AssignVariable { value, .. } => vec![value],
+            AST::AssignField { object, value, .. } => vec![object, value],
+            AST::AssignArray {
+                array,
+                index,
+                value,
+            } => vec![array, index, value],
+            AST::Function { body, .. } => vec![body],
+            AST::CallFunction { arguments, .. } => arguments.iter_mut().collect(),
+            AST::CallMethod {
+                object, arguments, ..
+            } => std::iter::once(object)
+                .chain(arguments.iter_mut())
+                .collect(),
+            AST::And { left, right } | AST::Or { left, right } => vec![left, right],
+            AST::Not { operand } => vec![operand],
+            AST::Top(asts) | AST::Block(asts) => asts.iter_mut().collect(),
+            AST::Loop { condition, body } => vec![condition, body],
+            AST::For { from, to, body, .. } => vec![from, to, body],
+            AST::ForEach { array, body, .. } => vec![array, body],
+            AST::Break { value } => value.iter_mut().collect(),
+            AST::Continue => vec![],
+            AST::Conditional {
+                condition,
+                consequent,
+                alternative,
+            } => vec![condition, consequent, alternative],
+            AST::Print { arguments, .. } => arguments.iter_mut().collect(),
+            AST::Throw { value } => vec![value],
+            AST::Try {
+                body,
+                handler,
+                finally,
+                ..
+            } => [body, handler]
+                .into_iter()
+                .chain(finally.iter_mut())
+                .collect(),
+        }
+    }
+
+    /**
+     * Moves the subtrees out of the node, leaving nulls behind.
+     */
+    fn take_children(&mut self) -> Vec<AST> {
+        self.children_mut()
+            .into_iter()
+            .map(|child| std::mem::replace(&mut **child, AST::Null))
+            .collect()
+    }
+}
+
+/**
+ * Subtrees are moved out to a list before they are dropped, dropping a deeply
+ * nested tree recursively would overflow the stack.
+ */
+impl Drop for AST {
+    fn drop(&mut self) {
+        let mut subtrees: Vec<AST> = self.take_children();
+        while let Some(mut subtree) = subtrees.pop() {
+            subtrees.extend(subtree.take_children());
+        }
+    }
+}
+
+/**
+ * Visits the only entry of the top level object, the items of the program.
+ */
+struct TopItems<F>(F);
+
+impl<'de, F: FnMut(&AST) -> Result<(), String>> Visitor<'de> for TopItems<F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a program with top level items")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<(), A::Error> {
+        match map.next_key::<String>()? {
+            Some(key) if key == "Top" => map.next_value_seed(&mut self)?,
+            _ => {
+                return Err(de::Error::custom(
+                    "expected `Top` with the items of the program",
+                ))
+            }
+        }
+        match map.next_key::<IgnoredAny>()? {
+            Some(_) => Err(de::Error::custom("expected only `Top` in the program")),
+            None => Ok(()),
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let TopItems(mut callback) = self;
+        while let Some(item) = seq.next_element::<AST>()? {
+            callback(&item).map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de, F: FnMut(&AST) -> Result<(), String>> DeserializeSeed<'de> for &mut TopItems<F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(TopItems(&mut self.0))
+    }
 }
 
 pub trait IntoBoxed {
@@ -99,3 +368,12 @@ impl IntoBoxed for AST {
         Box::new(self)
     }
 }
+
+/**
+ * Runs the callback, moving to a new stack segment first if the current one
+ * is about to run out. Functions recursing over the tree call it on every
+ * level, so they handle trees of any depth.
+ */
+pub fn ensure_stack<R>(callback: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(64 * 1024, 1024 * 1024, callback)
+}
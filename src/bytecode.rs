@@ -1,6 +1,8 @@
-use crate::constants::ConstantPoolIndex;
-use crate::serializer::Serializable;
-use std::io::Write;
+use crate::constants::{Constant, ConstantPool, ConstantPoolIndex};
+use crate::debug::{LabelName, LineEntry, LocalName, Span};
+use crate::serializer::*;
+use std::collections::HashMap;
+use std::io::{Read, Write};
 
 pub type LocalFrameIndex = u16;
 pub type ArgsCount = u8;
@@ -53,80 +55,366 @@ pub enum Bytecode {
     Branch {
         label: ConstantPoolIndex,
     },
+    // Not part of the standard FML instruction set.
+    BranchFalse {
+        label: ConstantPoolIndex,
+    },
     Return,
     Drop,
+    // Not part of the standard FML instruction set.
+    Throw,
+    // Jumps to instruction offsets within the function, used instead of labels
+    // when they are resolved. Not part of the standard FML instruction set.
+    JumpTo {
+        target: u32,
+    },
+    BranchTo {
+        target: u32,
+    },
+    BranchFalseTo {
+        target: u32,
+    },
+    // Binary operators, which call the operator method of the receiver, unless
+    // both operands are primitive. Not part of the standard FML instruction set.
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Neq,
+    // Superinstructions, which stand in place of the first instruction of the sequence
+    // they fuse, the rest of which stays behind them. With integer operands the whole
+    // sequence runs at once, otherwise only the first instruction, `GetLocal`, does.
+    // Not part of the standard FML instruction set.
+    //
+    //   GetLocal index; Literal amount; CallMethod "+" 2; SetLocal index; Drop
+    IncLocal {
+        index: LocalFrameIndex,
+        amount: ConstantPoolIndex,
+    },
+    //   GetLocal left; GetLocal right; CallMethod "<" 2; Branch label
+    CompareLocalsBranch {
+        left: LocalFrameIndex,
+        right: LocalFrameIndex,
+    },
+}
+
+/**
+ * Operator instructions with the names of the methods they call.
+ */
+const OPERATORS: [(Bytecode, &str); 11] = [
+    (Bytecode::Add, "+"),
+    (Bytecode::Sub, "-"),
+    (Bytecode::Mul, "*"),
+    (Bytecode::Div, "/"),
+    (Bytecode::Mod, "%"),
+    (Bytecode::Lt, "<"),
+    (Bytecode::Le, "<="),
+    (Bytecode::Gt, ">"),
+    (Bytecode::Ge, ">="),
+    (Bytecode::Eq, "=="),
+    (Bytecode::Neq, "!="),
+];
+
+impl Bytecode {
+    /**
+     * Returns the instruction of the binary operator with the method name.
+     */
+    pub fn operator(name: &str) -> Option<Bytecode> {
+        OPERATORS
+            .iter()
+            .find(|(_, operator)| *operator == name)
+            .map(|(inst, _)| *inst)
+    }
+
+    /**
+     * Returns the name of the operator method the instruction calls, if it's an operator.
+     */
+    pub fn operator_name(&self) -> Option<&'static str> {
+        OPERATORS
+            .iter()
+            .find(|(inst, _)| inst == self)
+            .map(|(_, name)| *name)
+    }
+
+    /**
+     * Returns the number of instructions the superinstruction fuses, 1 for others.
+     */
+    pub fn fused_length(&self) -> usize {
+        match self {
+            Bytecode::IncLocal { .. } => 5,
+            Bytecode::CompareLocalsBranch { .. } => 4,
+            _ => 1,
+        }
+    }
+
+    /**
+     * Returns the instruction with constant pool indexes replaced by the result
+     * of the mapping, which is also told whether the index refers to a label.
+     */
+    pub fn map_indices<F>(&self, mut f: F) -> Bytecode
+    where
+        F: FnMut(ConstantPoolIndex, bool) -> ConstantPoolIndex,
+    {
+        match *self {
+            Bytecode::Literal { index } => Bytecode::Literal {
+                index: f(index, false),
+            },
+            Bytecode::GetGlobal { name } => Bytecode::GetGlobal {
+                name: f(name, false),
+            },
+            Bytecode::SetGlobal { name } => Bytecode::SetGlobal {
+                name: f(name, false),
+            },
+            Bytecode::Object { class } => Bytecode::Object {
+                class: f(class, false),
+            },
+            Bytecode::GetField { name } => Bytecode::GetField {
+                name: f(name, false),
+            },
+            Bytecode::SetField { name } => Bytecode::SetField {
+                name: f(name, false),
+            },
+            Bytecode::CallMethod { name, arguments } => Bytecode::CallMethod {
+                name: f(name, false),
+                arguments,
+            },
+            Bytecode::CallFunction { name, arguments } => Bytecode::CallFunction {
+                name: f(name, false),
+                arguments,
+            },
+            Bytecode::Print { format, arguments } => Bytecode::Print {
+                format: f(format, false),
+                arguments,
+            },
+            Bytecode::Label { name } => Bytecode::Label {
+                name: f(name, true),
+            },
+            Bytecode::Jump { label } => Bytecode::Jump {
+                label: f(label, true),
+            },
+            Bytecode::Branch { label } => Bytecode::Branch {
+                label: f(label, true),
+            },
+            Bytecode::BranchFalse { label } => Bytecode::BranchFalse {
+                label: f(label, true),
+            },
+            Bytecode::IncLocal { index, amount } => Bytecode::IncLocal {
+                index,
+                amount: f(amount, false),
+            },
+            Bytecode::GetLocal { .. }
+            | Bytecode::SetLocal { .. }
+            | Bytecode::Array
+            | Bytecode::Return
+            | Bytecode::Drop
+            | Bytecode::Throw
+            | Bytecode::JumpTo { .. }
+            | Bytecode::BranchTo { .. }
+            | Bytecode::BranchFalseTo { .. }
+            | Bytecode::Add
+            | Bytecode::Sub
+            | Bytecode::Mul
+            | Bytecode::Div
+            | Bytecode::Mod
+            | Bytecode::Lt
+            | Bytecode::Le
+            | Bytecode::Gt
+            | Bytecode::Ge
+            | Bytecode::Eq
+            | Bytecode::Neq
+            | Bytecode::CompareLocalsBranch { .. } => *self,
+        }
+    }
+
+    /**
+     * Returns how many values the instruction pops from the operand stack
+     * and how many it pushes.
+     */
+    pub fn stack_effect(&self, pool: &ConstantPool) -> (u16, u16) {
+        match self {
+            Bytecode::Literal { .. } | Bytecode::GetLocal { .. } | Bytecode::GetGlobal { .. } => {
+                (0, 1)
+            }
+            // The rest of the fused sequence follows, so they count as `GetLocal`
+            Bytecode::IncLocal { .. } | Bytecode::CompareLocalsBranch { .. } => (0, 1),
+            // Setters only peek the value
+            Bytecode::SetLocal { .. } | Bytecode::SetGlobal { .. } => (1, 1),
+            Bytecode::Object { class } => match pool.get(*class) {
+                // Pops the parent and initial values of the fields
+                Constant::Object { members } => {
+                    let fields = members
+                        .iter()
+                        .filter(|member| matches!(pool.get(**member), Constant::Slot { .. }))
+                        .count();
+                    (fields as u16 + 1, 1)
+                }
+                _ => panic!("Object instruction doesn't refer to an object."),
+            },
+            Bytecode::Array => (2, 1),
+            Bytecode::GetField { .. } => (1, 1),
+            Bytecode::SetField { .. } => (2, 1),
+            Bytecode::CallMethod { arguments, .. }
+            | Bytecode::CallFunction { arguments, .. }
+            | Bytecode::Print { arguments, .. } => (*arguments as u16, 1),
+            Bytecode::Label { .. } | Bytecode::Jump { .. } | Bytecode::JumpTo { .. } => (0, 0),
+            Bytecode::Branch { .. }
+            | Bytecode::BranchFalse { .. }
+            | Bytecode::BranchTo { .. }
+            | Bytecode::BranchFalseTo { .. } => (1, 0),
+            Bytecode::Return | Bytecode::Drop | Bytecode::Throw => (1, 0),
+            Bytecode::Add
+            | Bytecode::Sub
+            | Bytecode::Mul
+            | Bytecode::Div
+            | Bytecode::Mod
+            | Bytecode::Lt
+            | Bytecode::Le
+            | Bytecode::Gt
+            | Bytecode::Ge
+            | Bytecode::Eq
+            | Bytecode::Neq => (2, 1),
+        }
+    }
 }
 
 impl Serializable for Bytecode {
     fn serializable_byte<W: Write>(&self, output: &mut W) -> std::io::Result<()> {
         match self {
             Bytecode::Literal { index } => {
-                output.write(&0x01u8.to_le_bytes())?;
-                output.write(&index.to_le_bytes())?;
+                output.write_all(&0x01u8.to_le_bytes())?;
+                output.write_all(&index.to_le_bytes())?;
             }
             Bytecode::GetLocal { index } => {
-                output.write(&0x0Au8.to_le_bytes())?;
-                output.write(&index.to_le_bytes())?;
+                output.write_all(&0x0Au8.to_le_bytes())?;
+                output.write_all(&index.to_le_bytes())?;
             }
             Bytecode::SetLocal { index } => {
-                output.write(&0x09u8.to_le_bytes())?;
-                output.write(&index.to_le_bytes())?;
+                output.write_all(&0x09u8.to_le_bytes())?;
+                output.write_all(&index.to_le_bytes())?;
             }
             Bytecode::GetGlobal { name } => {
-                output.write(&0x0Cu8.to_le_bytes())?;
-                output.write(&name.to_le_bytes())?;
+                output.write_all(&0x0Cu8.to_le_bytes())?;
+                output.write_all(&name.to_le_bytes())?;
             }
             Bytecode::SetGlobal { name } => {
-                output.write(&0x0Bu8.to_le_bytes())?;
-                output.write(&name.to_le_bytes())?;
+                output.write_all(&0x0Bu8.to_le_bytes())?;
+                output.write_all(&name.to_le_bytes())?;
             }
             Bytecode::Object { class } => {
-                output.write(&0x04u8.to_le_bytes())?;
-                output.write(&class.to_le_bytes())?;
+                output.write_all(&0x04u8.to_le_bytes())?;
+                output.write_all(&class.to_le_bytes())?;
             }
             Bytecode::Array => {
-                output.write(&0x03u8.to_le_bytes())?;
+                output.write_all(&0x03u8.to_le_bytes())?;
             },
             Bytecode::GetField { name } => {
-                output.write(&0x05u8.to_le_bytes())?;
-                output.write(&name.to_le_bytes())?;
+                output.write_all(&0x05u8.to_le_bytes())?;
+                output.write_all(&name.to_le_bytes())?;
             }
             Bytecode::SetField { name } => {
-                output.write(&0x06u8.to_le_bytes())?;
-                output.write(&name.to_le_bytes())?;
+                output.write_all(&0x06u8.to_le_bytes())?;
+                output.write_all(&name.to_le_bytes())?;
             }
             Bytecode::CallMethod { name, arguments } => {
-                output.write(&0x07u8.to_le_bytes())?;
-                output.write(&name.to_le_bytes())?;
-                output.write(&arguments.to_le_bytes())?;
+                output.write_all(&0x07u8.to_le_bytes())?;
+                output.write_all(&name.to_le_bytes())?;
+                output.write_all(&arguments.to_le_bytes())?;
             },
             Bytecode::CallFunction { name, arguments } => {
-                output.write(&0x08u8.to_le_bytes())?;
-                output.write(&name.to_le_bytes())?;
-                output.write(&arguments.to_le_bytes())?;
+                output.write_all(&0x08u8.to_le_bytes())?;
+                output.write_all(&name.to_le_bytes())?;
+                output.write_all(&arguments.to_le_bytes())?;
             }
             Bytecode::Label { name } => {
-                output.write(&0x00u8.to_le_bytes())?;
-                output.write(&name.to_le_bytes())?;
+                output.write_all(&0x00u8.to_le_bytes())?;
+                output.write_all(&name.to_le_bytes())?;
             }
             Bytecode::Print { format, arguments } => {
-                output.write(&[0x02 as u8])?;
-                output.write(&format.to_le_bytes())?;
-                output.write(&arguments.to_le_bytes())?;
+                output.write_all(&0x02u8.to_le_bytes())?;
+                output.write_all(&format.to_le_bytes())?;
+                output.write_all(&arguments.to_le_bytes())?;
             }
             Bytecode::Jump { label } => {
-                output.write(&0x0Eu8.to_le_bytes())?;
-                output.write(&label.to_le_bytes())?;
+                output.write_all(&0x0Eu8.to_le_bytes())?;
+                output.write_all(&label.to_le_bytes())?;
             }
             Bytecode::Branch { label } => {
-                output.write(&0x0Du8.to_le_bytes())?;
-                output.write(&label.to_le_bytes())?;
+                output.write_all(&0x0Du8.to_le_bytes())?;
+                output.write_all(&label.to_le_bytes())?;
+            }
+            Bytecode::BranchFalse { label } => {
+                output.write_all(&0x11u8.to_le_bytes())?;
+                output.write_all(&label.to_le_bytes())?;
             }
             Bytecode::Return => {
-                output.write(&0x0Fu8.to_le_bytes())?;
+                output.write_all(&0x0Fu8.to_le_bytes())?;
             }
             Bytecode::Drop => {
-                output.write(&0x10u8.to_le_bytes())?;
+                output.write_all(&0x10u8.to_le_bytes())?;
+            }
+            Bytecode::Throw => {
+                output.write_all(&0x12u8.to_le_bytes())?;
+            }
+            Bytecode::JumpTo { target } => {
+                output.write_all(&0x13u8.to_le_bytes())?;
+                output.write_all(&target.to_le_bytes())?;
+            }
+            Bytecode::BranchTo { target } => {
+                output.write_all(&0x14u8.to_le_bytes())?;
+                output.write_all(&target.to_le_bytes())?;
+            }
+            Bytecode::BranchFalseTo { target } => {
+                output.write_all(&0x15u8.to_le_bytes())?;
+                output.write_all(&target.to_le_bytes())?;
+            }
+            Bytecode::Add => {
+                output.write_all(&0x16u8.to_le_bytes())?;
+            }
+            Bytecode::Sub => {
+                output.write_all(&0x17u8.to_le_bytes())?;
+            }
+            Bytecode::Mul => {
+                output.write_all(&0x18u8.to_le_bytes())?;
+            }
+            Bytecode::Div => {
+                output.write_all(&0x19u8.to_le_bytes())?;
+            }
+            Bytecode::Mod => {
+                output.write_all(&0x1Au8.to_le_bytes())?;
+            }
+            Bytecode::Lt => {
+                output.write_all(&0x1Bu8.to_le_bytes())?;
+            }
+            Bytecode::Le => {
+                output.write_all(&0x1Cu8.to_le_bytes())?;
+            }
+            Bytecode::Gt => {
+                output.write_all(&0x1Du8.to_le_bytes())?;
+            }
+            Bytecode::Ge => {
+                output.write_all(&0x1Eu8.to_le_bytes())?;
+            }
+            Bytecode::Eq => {
+                output.write_all(&0x1Fu8.to_le_bytes())?;
+            }
+            Bytecode::Neq => {
+                output.write_all(&0x20u8.to_le_bytes())?;
+            }
+            Bytecode::IncLocal { index, amount } => {
+                output.write_all(&0x21u8.to_le_bytes())?;
+                output.write_all(&index.to_le_bytes())?;
+                output.write_all(&amount.to_le_bytes())?;
+            }
+            Bytecode::CompareLocalsBranch { left, right } => {
+                output.write_all(&0x22u8.to_le_bytes())?;
+                output.write_all(&left.to_le_bytes())?;
+                output.write_all(&right.to_le_bytes())?;
             }
         };
 
@@ -134,15 +422,209 @@ impl Serializable for Bytecode {
     }
 }
 
+/**
+ * Entry of the exception handler table. Exceptions thrown between the start
+ * and end labels are caught by jumping to the handler, after the operand stack
+ * of the frame is truncated to the given height and the exception is pushed.
+ * Inner handlers come before outer ones in the table.
+ */
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub struct ExceptionHandler {
+    pub start: ConstantPoolIndex,
+    pub end: ConstantPoolIndex,
+    pub handler: ConstantPoolIndex,
+    pub stack: u16,
+}
+
+impl Serializable for ExceptionHandler {
+    fn serializable_byte<W: Write>(&self, output: &mut W) -> std::io::Result<()> {
+        output.write_all(&self.start.to_le_bytes())?;
+        output.write_all(&self.end.to_le_bytes())?;
+        output.write_all(&self.handler.to_le_bytes())?;
+        output.write_all(&self.stack.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+/**
+ * Entry of the exception handler table of code with resolved labels, the same
+ * as `ExceptionHandler` with instruction offsets in place of the labels.
+ */
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub struct ResolvedHandler {
+    pub start: u32,
+    pub end: u32,
+    pub handler: u32,
+    pub stack: u16,
+}
+
+impl Serializable for ResolvedHandler {
+    fn serializable_byte<W: Write>(&self, output: &mut W) -> std::io::Result<()> {
+        output.write_all(&self.start.to_le_bytes())?;
+        output.write_all(&self.end.to_le_bytes())?;
+        output.write_all(&self.handler.to_le_bytes())?;
+        output.write_all(&self.stack.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+impl Deserializable for ResolvedHandler {
+    fn deserialize<R: Read>(input: &mut R) -> std::io::Result<Self> {
+        Ok(ResolvedHandler {
+            start: read_u32(input)?,
+            end: read_u32(input)?,
+            handler: read_u32(input)?,
+            stack: read_u16(input)?,
+        })
+    }
+}
+
+impl Deserializable for Bytecode {
+    fn deserialize<R: Read>(input: &mut R) -> std::io::Result<Self> {
+        let inst = match read_u8(input)? {
+            0x00 => Bytecode::Label {
+                name: read_u16(input)?,
+            },
+            0x01 => Bytecode::Literal {
+                index: read_u16(input)?,
+            },
+            0x02 => Bytecode::Print {
+                format: read_u16(input)?,
+                arguments: read_u8(input)?,
+            },
+            0x03 => Bytecode::Array,
+            0x04 => Bytecode::Object {
+                class: read_u16(input)?,
+            },
+            0x05 => Bytecode::GetField {
+                name: read_u16(input)?,
+            },
+            0x06 => Bytecode::SetField {
+                name: read_u16(input)?,
+            },
+            0x07 => Bytecode::CallMethod {
+                name: read_u16(input)?,
+                arguments: read_u8(input)?,
+            },
+            0x08 => Bytecode::CallFunction {
+                name: read_u16(input)?,
+                arguments: read_u8(input)?,
+            },
+            0x09 => Bytecode::SetLocal {
+                index: read_u16(input)?,
+            },
+            0x0A => Bytecode::GetLocal {
+                index: read_u16(input)?,
+            },
+            0x0B => Bytecode::SetGlobal {
+                name: read_u16(input)?,
+            },
+            0x0C => Bytecode::GetGlobal {
+                name: read_u16(input)?,
+            },
+            0x0D => Bytecode::Branch {
+                label: read_u16(input)?,
+            },
+            0x0E => Bytecode::Jump {
+                label: read_u16(input)?,
+            },
+            0x0F => Bytecode::Return,
+            0x10 => Bytecode::Drop,
+            0x11 => Bytecode::BranchFalse {
+                label: read_u16(input)?,
+            },
+            0x12 => Bytecode::Throw,
+            0x13 => Bytecode::JumpTo {
+                target: read_u32(input)?,
+            },
+            0x14 => Bytecode::BranchTo {
+                target: read_u32(input)?,
+            },
+            0x15 => Bytecode::BranchFalseTo {
+                target: read_u32(input)?,
+            },
+            0x16 => Bytecode::Add,
+            0x17 => Bytecode::Sub,
+            0x18 => Bytecode::Mul,
+            0x19 => Bytecode::Div,
+            0x1A => Bytecode::Mod,
+            0x1B => Bytecode::Lt,
+            0x1C => Bytecode::Le,
+            0x1D => Bytecode::Gt,
+            0x1E => Bytecode::Ge,
+            0x1F => Bytecode::Eq,
+            0x20 => Bytecode::Neq,
+            0x21 => Bytecode::IncLocal {
+                index: read_u16(input)?,
+                amount: read_u16(input)?,
+            },
+            0x22 => Bytecode::CompareLocalsBranch {
+                left: read_u16(input)?,
+                right: read_u16(input)?,
+            },
+            _ => return Err(invalid_data("Unknown instruction opcode.")),
+        };
+        Ok(inst)
+    }
+}
+
+impl Deserializable for ExceptionHandler {
+    fn deserialize<R: Read>(input: &mut R) -> std::io::Result<Self> {
+        Ok(ExceptionHandler {
+            start: read_u16(input)?,
+            end: read_u16(input)?,
+            handler: read_u16(input)?,
+            stack: read_u16(input)?,
+        })
+    }
+}
+
+/**
+ * Height of the operand stack at the end of the code, relative to the
+ * beginning of the function. Only the instructions written since the last
+ * query are counted.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct StackDepth {
+    // Number of the instructions already counted
+    counted: usize,
+    depth: u16,
+    unreachable: bool,
+    // Heights at labels which are jumped to
+    labels: HashMap<ConstantPoolIndex, u16>,
+}
+
+// The depth is derived from the instructions, so it doesn't make codes different.
+impl PartialEq for StackDepth {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct Code {
     pub insert_point: Vec<Bytecode>,
+    pub handlers: Vec<ExceptionHandler>,
+    // Handlers of the code whose labels are resolved, instead of `handlers`.
+    pub resolved_handlers: Vec<ResolvedHandler>,
+    // Debug information, written only in the debug section of the program.
+    pub lines: Vec<LineEntry>,
+    pub locals: Vec<LocalName>,
+    pub labels: Vec<LabelName>,
+    // Tracked while compiling, for the jumps out of expressions.
+    stack: StackDepth,
 }
 
 impl Code {
     pub fn new() -> Code {
         Code {
             insert_point: Vec::new(),
+            handlers: Vec::new(),
+            resolved_handlers: Vec::new(),
+            lines: Vec::new(),
+            locals: Vec::new(),
+            labels: Vec::new(),
+            stack: StackDepth::default(),
         }
     }
 
@@ -157,7 +639,135 @@ impl Code {
     }
 
     pub fn write_insts(&mut self, insts: Code) {
+        let offset = self.len();
         self.insert_point.extend(insts.insert_point);
+        self.handlers.extend(insts.handlers);
+        self.lines
+            .extend(insts.lines.into_iter().map(|entry| LineEntry {
+                instruction: entry.instruction + offset,
+                ..entry
+            }));
+        self.locals
+            .extend(insts.locals.into_iter().map(|local| LocalName {
+                start: local.start + offset,
+                end: local.end + offset,
+                ..local
+            }));
+        self.labels
+            .extend(insts.labels.into_iter().map(|label| LabelName {
+                instruction: label.instruction + offset,
+                ..label
+            }));
+    }
+
+    /**
+     * Computes height of the operand stack at the end of the code.
+     */
+    pub fn stack_depth(&mut self, pool: &ConstantPool) -> u16 {
+        let stack = &mut self.stack;
+        for inst in self.insert_point[stack.counted..].iter() {
+            match inst {
+                Bytecode::Label { name } if stack.unreachable => {
+                    // Labels jumped to only backwards keep the height before the jump
+                    stack.depth = *stack.labels.get(name).unwrap_or(&stack.depth);
+                    stack.unreachable = false;
+                }
+                Bytecode::Jump { label } => {
+                    stack.labels.insert(*label, stack.depth);
+                    stack.unreachable = true;
+                }
+                Bytecode::Branch { label } | Bytecode::BranchFalse { label } => {
+                    stack.depth = stack.depth.saturating_sub(1);
+                    stack.labels.insert(*label, stack.depth);
+                }
+                Bytecode::Return | Bytecode::Throw => {
+                    stack.unreachable = true;
+                }
+                _ => {
+                    let (pops, pushes) = inst.stack_effect(pool);
+                    stack.depth = stack.depth.saturating_sub(pops) + pushes;
+                }
+            }
+        }
+        stack.counted = self.insert_point.len();
+        stack.depth
+    }
+
+    pub fn add_handler(&mut self, handler: ExceptionHandler) {
+        self.handlers.push(handler)
+    }
+
+    /**
+     * Following instructions are compiled from the span.
+     */
+    pub fn add_line(&mut self, span: Span) {
+        let instruction = self.len();
+        match self.lines.last_mut() {
+            Some(last) if last.span == span => (),
+            Some(last) if last.instruction == instruction => last.span = span,
+            _ => self.lines.push(LineEntry { instruction, span }),
+        }
+    }
+
+    /**
+     * Names the local slot from the next instruction until it's ended.
+     */
+    pub fn add_local(&mut self, index: LocalFrameIndex, name: String) {
+        self.locals.push(LocalName {
+            index,
+            name,
+            start: self.len(),
+            end: u32::MAX,
+        });
+    }
+
+    /**
+     * Ends the names of the slots which are still named.
+     */
+    pub fn end_locals<F: Fn(LocalFrameIndex) -> bool>(&mut self, ended: F) {
+        let end = self.len();
+        for local in self.locals.iter_mut() {
+            if local.end == u32::MAX && ended(local.index) {
+                local.end = end;
+            }
+        }
+    }
+
+    /**
+     * Moves the debug information after instructions were removed or added,
+     * the mapping gets old positions of instructions and returns the new ones.
+     */
+    pub fn map_positions<F: Fn(u32) -> u32>(&mut self, f: F) {
+        for entry in self.lines.iter_mut() {
+            entry.instruction = f(entry.instruction);
+        }
+        for local in self.locals.iter_mut() {
+            local.start = f(local.start);
+            local.end = f(local.end);
+        }
+        for label in self.labels.iter_mut() {
+            label.instruction = f(label.instruction);
+        }
+        // Entries of removed instructions may now overlap
+        self.lines
+            .dedup_by(|next, entry| next.instruction == entry.instruction && {
+                entry.span = next.span;
+                true
+            });
+    }
+
+    pub fn span_at(&self, instruction: u32) -> Option<Span> {
+        let entries = self
+            .lines
+            .partition_point(|entry| entry.instruction <= instruction);
+        entries.checked_sub(1).map(|i| self.lines[i].span)
+    }
+
+    pub fn local_name(&self, index: LocalFrameIndex, instruction: u32) -> Option<&str> {
+        self.locals
+            .iter()
+            .find(|local| local.index == index && local.start <= instruction && instruction < local.end)
+            .map(|local| local.name.as_str())
     }
 
     pub fn len(&self) -> u32 {
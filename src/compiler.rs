@@ -1,44 +1,49 @@
 use crate::ast::Identifier;
 use crate::ast::IntoBoxed;
 use crate::ast::AST;
+use crate::ast::ensure_stack;
 use crate::bytecode::*;
 use crate::constants::*;
-use crate::serializer::Serializable;
-use std::collections::HashMap;
-use std::io;
-use std::io::Write;
+use crate::debug::Spans;
+use crate::optimizer;
+use crate::program::Program;
+use crate::serializer::*;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
 
 struct RandomNameGenerator {
-    cnt: usize,
+    // Shared by the whole compilation, so it's counted through a shared reference.
+    cnt: Cell<usize>,
 }
 
 impl RandomNameGenerator {
     fn new() -> Self {
-        RandomNameGenerator { cnt: 0 }
+        RandomNameGenerator { cnt: Cell::new(0) }
     }
 
-    fn generate(&mut self, str: &'static str) -> String {
+    fn generate(&self, str: &'static str) -> String {
         if str.chars().any(char::is_numeric) {
             panic!("String can't contain number!");
         }
         let mut new_str = String::from(str);
-        new_str.push_str(&format!("_{}", self.cnt));
-        self.cnt += 1;
+        new_str.push_str(&format!("_{}", self.cnt.get()));
+        self.cnt.set(self.cnt.get() + 1);
         new_str
     }
 }
 
-trait Environments {
+pub(crate) trait Environments {
     fn enter_scope(&mut self);
     fn leave_scope(&mut self) -> Result<(), &'static str>;
     fn introduce_variable(&mut self, str: String) -> Result<LocalFrameIndex, &'static str>;
-    fn has_variable(&self, str: &String) -> Option<LocalFrameIndex>;
+    fn has_variable(&self, str: &str) -> Option<LocalFrameIndex>;
     fn is_topmost(&self) -> bool;
 }
 
-#[derive(Debug)]
-struct Globals {
-    globals: Vec<ConstantPoolIndex>,
+#[derive(Debug, Default)]
+pub struct Globals {
+    pub globals: Vec<ConstantPoolIndex>,
 }
 
 impl Globals {
@@ -55,22 +60,79 @@ impl Globals {
     pub fn len(&self) -> u16 {
         self.globals.len().try_into().unwrap()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.globals.is_empty()
+    }
 }
 
 impl Serializable for Globals {
     fn serializable_byte<W: Write>(&self, output: &mut W) -> std::io::Result<()> {
-        output.write(&self.len().to_le_bytes())?;
+        output.write_all(&self.len().to_le_bytes())?;
         for global in self.globals.iter() {
-            output.write(&global.to_le_bytes())?;
+            output.write_all(&global.to_le_bytes())?;
         }
         Ok(())
     }
 }
 
+impl Deserializable for Globals {
+    fn deserialize<R: Read>(input: &mut R) -> std::io::Result<Self> {
+        let mut globals = Globals::new();
+        for _ in 0..read_u16(input)? {
+            globals.introduce_variable(read_u16(input)?);
+        }
+        Ok(globals)
+    }
+}
+
+/**
+ * Labels of an enclosing loop, targets of `break` and `continue`.
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+struct LoopLabels {
+    // Condition of the loop, or the step of the `for` loop.
+    next: ConstantPoolIndex,
+    // Placed after the value of the loop is pushed.
+    exit: ConstantPoolIndex,
+    // Number of enclosing try blocks with finally.
+    finally_depth: usize,
+    // Height of the operand stack in the body, operands above it are dropped
+    // when the loop is left from the middle of an expression.
+    stack: u16,
+}
+
+/**
+ * How a node is compiled, passed down the tree alongside the state.
+ */
+#[derive(Clone, Copy)]
+struct Context<'a> {
+    // Spans of the nodes, where their instructions come from.
+    spans: &'a Spans,
+    // Names of the labels.
+    generator: &'a RandomNameGenerator,
+    // Branch with `BranchFalse` where the condition is negated.
+    branch_false: bool,
+    // Drop the value of the node, it's a statement.
+    drop: bool,
+}
+
+impl<'a> Context<'a> {
+    fn with_drop(self, drop: bool) -> Self {
+        Context { drop, ..self }
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub struct VecEnvironments {
     envs: Vec<HashMap<String, LocalFrameIndex>>,
     var_cnt: u16,
+    // Loops are tracked per function, so methods can't break out of
+    // a loop in which the object is created.
+    loops: Vec<LoopLabels>,
+    // Number of try blocks with finally the compiled code is in, jumping
+    // out of them would skip the finally block.
+    finally_depth: usize,
 }
 
 #[derive(PartialEq)]
@@ -86,10 +148,12 @@ impl VecEnvironments {
     /**
      * Initializes environments with one env present.
      */
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         VecEnvironments {
             envs: vec![HashMap::new(); 1],
             var_cnt: 0,
+            loops: Vec::new(),
+            finally_depth: 0,
         }
     }
 }
@@ -120,7 +184,7 @@ impl Environments for VecEnvironments {
         Ok(self.var_cnt - 1)
     }
 
-    fn has_variable(&self, str: &String) -> Option<LocalFrameIndex> {
+    fn has_variable(&self, str: &str) -> Option<LocalFrameIndex> {
         // Check if variable is located in any environment, start
         // from the last.
         for env in self.envs.iter().rev() {
@@ -138,69 +202,459 @@ impl Environments for VecEnvironments {
     }
 }
 
-pub fn compile(ast: &AST) -> std::io::Result<()> {
+/**
+ * Options that change the emitted code. By default, only the standard
+ * FML instruction set is used.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct CompilerOptions {
+    // Use `BranchFalse` instead of the branch-jump pairs in conditionals and logical operators.
+    pub branch_false: bool,
+    // Use `Add`, `Lt` and the other operator instructions instead of calling the operator methods.
+    pub operators: bool,
+    // Fuse the counters and loop conditions into `IncLocal` and `CompareLocalsBranch`.
+    pub superinstructions: bool,
+    // Emit debug information, with line tables when spans of the source are known.
+    pub debug: Option<Spans>,
+}
+
+pub fn compile(ast: &AST, options: &CompilerOptions) -> Result<Program, &'static str> {
     let mut pool = ConstantPool::new();
     let mut code_dummy = Code::new();
     let mut frame = Frame::Global;
     let mut global_env = VecEnvironments::new();
     let mut globals = Globals::new();
-    let mut generator = RandomNameGenerator::new();
+    let generator = RandomNameGenerator::new();
+    let no_spans = Spans::default();
+    let context = Context {
+        spans: options.debug.as_ref().unwrap_or(&no_spans),
+        generator: &generator,
+        branch_false: options.branch_false,
+        drop: true,
+    };
 
     _compile(
-        ast,
-        &mut pool,
-        &mut code_dummy,
-        &mut frame,
-        &mut globals,
-        &mut global_env,
-        &mut generator,
-        true,
-    )
-    .expect("Compilation failed");
-
-    let mut f = io::stdout();
-    pool.serializable_byte(&mut f)?;
-
-    // Serialize globals
-    // f.write(&0u16.to_le_bytes());
-    globals.serializable_byte(&mut f)?;
-
-    // Entry point: Main function is always added last.
-    f.write(&(pool.len() - 1 as u16).to_le_bytes())?;
-
-    // println!("{:?}\n{:?}", pool, globals);
-    // println!("EP: {0}", pool.len() - 1);
+        ast, &mut pool, &mut code_dummy, &mut frame, &mut globals, &mut global_env, context,
+    )?;
+
+    Ok(finish(pool, globals, options))
+}
+
+/**
+ * Applies the options to the compiled functions and puts the program together.
+ */
+fn finish(mut pool: ConstantPool, globals: Globals, options: &CompilerOptions) -> Program {
+    for code in pool.codes_mut() {
+        if options.debug.is_none() {
+            code.lines.clear();
+            code.locals.clear();
+        }
+    }
+    if options.operators {
+        optimizer::use_operators(&mut pool);
+    }
+    if options.superinstructions {
+        optimizer::use_superinstructions(&mut pool);
+    }
+
+    Program {
+        // Entry point: Main function is always added last.
+        entry: pool.len() - 1,
+        pool,
+        globals,
+    }
+}
+
+/**
+ * Compiles top level items one by one as they come, into the same program
+ * `compile` makes of `AST::Top` with all of them. Only the item being compiled
+ * has to be in memory.
+ */
+pub struct StreamingCompiler {
+    pool: ConstantPool,
+    code_main: Code,
+    globals: Globals,
+    global_env: VecEnvironments,
+    generator: RandomNameGenerator,
+    options: CompilerOptions,
+}
+
+impl StreamingCompiler {
+    pub fn new(options: &CompilerOptions) -> Self {
+        StreamingCompiler {
+            pool: ConstantPool::new(),
+            code_main: Code::new(),
+            globals: Globals::new(),
+            global_env: VecEnvironments::new(),
+            generator: RandomNameGenerator::new(),
+            options: options.clone(),
+        }
+    }
+
+    pub fn compile_item(&mut self, ast: &AST) -> Result<(), &'static str> {
+        // Imports are resolved before compilation, which needs the whole program.
+        if matches!(ast, AST::Import { .. }) {
+            return Err("Imports can't be compiled item by item.");
+        }
+        let no_spans = Spans::default();
+        _compile(
+            ast,
+            &mut self.pool,
+            &mut self.code_main,
+            &mut Frame::Global,
+            &mut self.globals,
+            &mut self.global_env,
+            Context {
+                spans: self.options.debug.as_ref().unwrap_or(&no_spans),
+                generator: &self.generator,
+                branch_false: self.options.branch_false,
+                drop: true,
+            },
+        )
+    }
+
+    /**
+     * Adds the main function, made of the items compiled so far.
+     */
+    pub fn finish(mut self) -> Program {
+        self.code_main.end_locals(|_| true);
+        let name = self.pool.push(Constant::from(String::from("λ:")));
+        self.pool.push(Constant::Function {
+            name,
+            parameters: 0,
+            locals: self.global_env.var_cnt,
+            code: self.code_main,
+        });
+        finish(self.pool, self.globals, &self.options)
+    }
+}
+
+/**
+ * Compiler which keeps the constant pool and globals between compilations,
+ * so top level statements can be compiled one by one, as in REPL.
+ */
+pub struct IncrementalCompiler {
+    pub pool: ConstantPool,
+    pub globals: Globals,
+    global_env: VecEnvironments,
+    generator: RandomNameGenerator,
+}
+
+impl IncrementalCompiler {
+    pub fn new() -> Self {
+        IncrementalCompiler {
+            pool: ConstantPool::new(),
+            globals: Globals::new(),
+            global_env: VecEnvironments::new(),
+            generator: RandomNameGenerator::new(),
+        }
+    }
+
+    /**
+     * Compiles the top level statement into a function without parameters,
+     * which returns the value of the statement. Returns index of the function.
+     */
+    pub fn compile_statement(&mut self, ast: &AST) -> Result<ConstantPoolIndex, &'static str> {
+        let mut code = Code::new();
+        // Function definitions have no value.
+        let is_definition = matches!(ast, AST::Function { .. });
+
+        let result = _compile(
+            ast,
+            &mut self.pool,
+            &mut code,
+            &mut Frame::Global,
+            &mut self.globals,
+            &mut self.global_env,
+            Context {
+                spans: &Spans::default(),
+                generator: &self.generator,
+                branch_false: false,
+                drop: is_definition,
+            },
+        );
+        if let Err(err) = result {
+            // Failed compilation might have left some scopes open.
+            let var_cnt = self.global_env.var_cnt;
+            self.global_env = VecEnvironments::new();
+            self.global_env.var_cnt = var_cnt;
+            return Err(err);
+        }
+
+        if is_definition {
+            let index = self.pool.push(Constant::Null);
+            code.write_inst(Bytecode::Literal { index });
+        }
+        code.write_inst(Bytecode::Return);
+
+        let name = self.pool.push(Constant::from(String::from("λ:")));
+        Ok(self.pool.push(Constant::Function {
+            name,
+            parameters: 0,
+            locals: self.global_env.var_cnt,
+            code,
+        }))
+    }
+}
+
+impl Default for IncrementalCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ * Fields which object literals declare and which are accessed or assigned,
+ * gathered item by item, so a streamed program can be checked too.
+ */
+#[derive(Default)]
+pub struct FieldUsage {
+    declared: HashSet<String>,
+    used: HashSet<String>,
+}
+
+impl FieldUsage {
+    pub fn add_item(&mut self, item: &AST) {
+        match item {
+            AST::Object { members, .. } => {
+                for member in members.iter() {
+                    if let AST::Variable { name, .. } = &**member {
+                        if !self.declared.contains(name.as_str()) {
+                            self.declared.insert(String::from(name.as_str()));
+                        }
+                    }
+                }
+            }
+            AST::AccessField { field, .. } | AST::AssignField { field, .. }
+                if !self.used.contains(field.as_str()) =>
+            {
+                self.used.insert(String::from(field.as_str()));
+            }
+            _ => (),
+        }
+        for child in item.children() {
+            ensure_stack(|| self.add_item(child));
+        }
+    }
+
+    /**
+     * Returns names of the used fields which no object declares, sorted.
+     */
+    pub fn undeclared(&self) -> Vec<String> {
+        let mut undeclared: Vec<String> = self
+            .used
+            .iter()
+            .filter(|field| !self.declared.contains(*field))
+            .cloned()
+            .collect();
+        undeclared.sort();
+        undeclared
+    }
+}
+
+/**
+ * Returns names of fields which are accessed or assigned somewhere in the
+ * program, but aren't declared by any object literal. Such accesses will
+ * always fail at runtime, unless the field comes from an object created
+ * outside of the program.
+ */
+pub fn undeclared_fields(ast: &AST) -> Vec<String> {
+    let mut usage = FieldUsage::default();
+    usage.add_item(ast);
+    usage.undeclared()
+}
+
+/**
+ * Checks escape sequences of the print format and returns the number
+ * of `~` placeholders in it.
+ */
+fn count_placeholders(format: &str) -> Result<usize, &'static str> {
+    let mut count = 0;
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '~' => count += 1,
+            '\\' => match chars.next() {
+                Some('~' | 'n' | 't' | '\\' | '"') => (),
+                Some(_) => return Err("Unknown escape sequence in print format."),
+                None => return Err("Print format ends with unfinished escape sequence."),
+            },
+            _ => (),
+        }
+    }
+    Ok(count)
+}
+
+/**
+ * Leaves the innermost scope, ending the names of its variables.
+ */
+fn leave_scope(env: &mut VecEnvironments, code: &mut Code) -> Result<(), &'static str> {
+    if let Some(scope) = env.envs.last() {
+        code.end_locals(|index| scope.values().any(|slot| *slot == index));
+    }
+    env.leave_scope()
+}
+
+/**
+ * Returns the environment of the code that is currently compiled.
+ */
+fn current_env<'a>(
+    frame: &'a mut Frame,
+    global_env: &'a mut VecEnvironments,
+) -> &'a mut VecEnvironments {
+    match frame {
+        Frame::Global => global_env,
+        Frame::Local(env) => env,
+    }
+}
+
+/**
+ * Drops the operands above the given height of the stack, before a jump
+ * out of the middle of an expression.
+ */
+fn drop_operands(height: u16, pool: &ConstantPool, code: &mut Code) {
+    for _ in height..code.stack_depth(pool) {
+        code.write_inst(Bytecode::Drop);
+    }
+}
+
+/**
+ * Labels of a loop that is being compiled. The body comes first, then the step,
+ * if the loop has one, and the condition at the end, so each iteration needs only
+ * one branch, if it's false, we just fall through. The parts are compiled by the
+ * caller in this order, in place, between the calls of the methods.
+ */
+struct LoopLayout {
+    begin: ConstantPoolIndex,
+    step: Option<ConstantPoolIndex>,
+    cond: ConstantPoolIndex,
+    end: ConstantPoolIndex,
+}
+
+impl LoopLayout {
+    /**
+     * Starts the loop, its body is compiled next.
+     */
+    fn begin(
+        step: bool,
+        pool: &mut ConstantPool,
+        code: &mut Code,
+        env: &mut VecEnvironments,
+        generator: &RandomNameGenerator,
+    ) -> LoopLayout {
+        let begin = pool.push(Constant::from(generator.generate("while_begin")));
+        let cond = pool.push(Constant::from(generator.generate("while_cond")));
+        let step = step.then(|| pool.push(Constant::from(generator.generate("while_step"))));
+        let end = pool.push(Constant::from(generator.generate("while_end")));
+
+        code.write_inst(Bytecode::Jump { label: cond });
+        code.write_inst(Bytecode::Label { name: begin });
+        // The step is evaluated after each iteration, even when `continue` is used.
+        env.loops.push(LoopLabels {
+            next: step.unwrap_or(cond),
+            exit: end,
+            finally_depth: env.finally_depth,
+            stack: code.stack_depth(pool),
+        });
+        LoopLayout {
+            begin,
+            step,
+            cond,
+            end,
+        }
+    }
+
+    /**
+     * Ends the body, the step is compiled next, if the loop has one.
+     */
+    fn end_body(&self, code: &mut Code, env: &mut VecEnvironments) {
+        env.loops.pop();
+        if let Some(step) = self.step {
+            code.write_inst(Bytecode::Label { name: step });
+        }
+    }
+
+    /**
+     * Starts the condition.
+     */
+    fn begin_condition(&self, code: &mut Code) {
+        code.write_inst(Bytecode::Label { name: self.cond });
+    }
 
+    /**
+     * Ends the loop after the condition, leaving its value on the stack. The value
+     * is null, unless the loop is exited with `break` with a value.
+     */
+    fn end(&self, pool: &mut ConstantPool, code: &mut Code) {
+        code.write_inst(Bytecode::Branch { label: self.begin });
+        // Loop that ends normally evaluates to null, break jumps
+        // behind this with its own value.
+        let null = pool.push(Constant::Null);
+        code.write_inst(Bytecode::Literal { index: null });
+        code.write_inst(Bytecode::Label { name: self.end });
+    }
+}
+
+/**
+ * Introduces the variable in the current scope and stores the value
+ * from the top of the stack in it.
+ */
+fn define_variable(
+    name: &str,
+    pool: &mut ConstantPool,
+    code: &mut Code,
+    frame: &mut Frame,
+    globals: &mut Globals,
+    global_env: &mut VecEnvironments,
+) -> Result<(), &'static str> {
+    match frame {
+        Frame::Local(env) => {
+            let index = env.introduce_variable(name.to_string())?;
+            code.add_local(index, name.to_string());
+            code.write_inst(Bytecode::SetLocal { index: index });
+        }
+        Frame::Global if !global_env.is_topmost() => {
+            let index = global_env.introduce_variable(name.to_string())?;
+            code.add_local(index, name.to_string());
+            code.write_inst(Bytecode::SetLocal { index: index });
+        }
+        Frame::Global => {
+            let name_index = pool.push(Constant::from(String::from(name)));
+            let slot_index = pool.push(Constant::Slot { name: name_index });
+            globals.introduce_variable(slot_index);
+            code.write_inst(Bytecode::SetGlobal { name: name_index });
+        }
+    }
     Ok(())
 }
 
+/**
+ * Compiles the function, methods get their receiver `this` as the first parameter.
+ */
 fn compile_fun_def(
     name: String,
-    parameters: &Vec<Identifier>,
-    body: &Box<AST>,
-    is_method: bool,
+    parameters: &[Identifier],
+    body: &AST,
     pool: &mut ConstantPool,
     globals: &mut Globals,
     global_env: &mut VecEnvironments,
-    generator: &mut RandomNameGenerator,
+    context: Context,
 ) -> Result<ConstantPoolIndex, &'static str> {
     let mut env = VecEnvironments::new();
-    if is_method {
-        env.introduce_variable(String::from("this"))?;
-    }
-
+    let mut code = Code::new();
     for param in parameters.iter() {
-        env.introduce_variable(param.0.clone())?;
+        let index = env.introduce_variable(param.0.clone())?;
+        code.add_local(index, param.0.clone());
     }
 
     let mut frame = Frame::Local(env);
-    let mut code = Code::new();
 
     _compile(
-        &body, pool, &mut code, &mut frame, globals, global_env, generator, false,
+        body, pool, &mut code, &mut frame, globals, global_env, context.with_drop(false),
     )?;
 
     code.write_inst(Bytecode::Return);
+    code.end_locals(|_| true);
 
     let locals = match frame {
         Frame::Local(env) => env.var_cnt,
@@ -209,7 +663,7 @@ fn compile_fun_def(
 
     let func = Constant::Function {
         name: pool.push(Constant::from(name)),
-        parameters: (parameters.len() + is_method as usize).try_into().unwrap(),
+        parameters: parameters.len().try_into().unwrap(),
         locals,
         code,
     };
@@ -219,6 +673,9 @@ fn compile_fun_def(
     Ok(fun_idx)
 }
 
+/**
+ * Compiles the node, recording that the emitted instructions come from its span.
+ */
 fn _compile(
     ast: &AST,
     pool: &mut ConstantPool,
@@ -226,12 +683,57 @@ fn _compile(
     frame: &mut Frame,
     globals: &mut Globals,
     global_env: &mut VecEnvironments,
-    generator: &mut RandomNameGenerator,
-    drop: bool,
+    context: Context,
+) -> Result<(), &'static str> {
+    // Every level of the tree passes through here, generated programs can be deep.
+    ensure_stack(|| {
+        let span = match context.spans.get(ast) {
+            Some(span) => span,
+            None => {
+                return compile_node(
+                    ast, pool, code, frame, globals, global_env, context,
+                )
+            }
+        };
+        let outer = code.lines.last().map(|entry| entry.span);
+        code.add_line(span);
+        compile_node(
+            ast, pool, code, frame, globals, global_env, context,
+        )
+        .inspect_err(|_| context.spans.fail(span))?;
+        // Rest of the instructions belongs to the parent
+        if let Some(outer) = outer {
+            code.add_line(outer);
+        }
+        Ok(())
+    })
+}
+
+fn compile_node(
+    ast: &AST,
+    pool: &mut ConstantPool,
+    code: &mut Code,
+    frame: &mut Frame,
+    globals: &mut Globals,
+    global_env: &mut VecEnvironments,
+    context: Context,
 ) -> Result<(), &'static str> {
+    let (generator, drop) = (context.generator, context.drop);
     match ast {
         AST::Integer(val) => {
+            // Integers that fit into 32 bits keep the original encoding,
+            // so runtimes without 64-bit support can still run the program.
+            let constant = match i32::try_from(*val) {
+                Ok(val) => Constant::from(val),
+                Err(_) => Constant::from(*val),
+            };
             // Add it to constant pool.
+            let index = pool.push(constant);
+            code.write_inst(Bytecode::Literal { index });
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
+        AST::Float(val) => {
             let index = pool.push(Constant::from(*val));
             code.write_inst(Bytecode::Literal { index });
             code.write_inst_if(Bytecode::Drop, drop);
@@ -243,6 +745,12 @@ fn _compile(
             code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
+        AST::String(val) => {
+            let index = pool.push(Constant::from(val.clone()));
+            code.write_inst(Bytecode::Literal { index });
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
         AST::Null => {
             let index = pool.push(Constant::Null);
             code.write_inst(Bytecode::Literal { index });
@@ -251,56 +759,31 @@ fn _compile(
         }
         AST::Variable { name, value } => {
             _compile(
-                value, pool, code, frame, globals, global_env, generator, false,
+                value, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
-            match frame {
-                Frame::Local(env) => {
-                    let index = env.introduce_variable(name.0.clone()).unwrap_or_else(|_| {
-                        panic!(
-                            "Variable '{0}' already exists in global environment",
-                            name.0
-                        )
-                    });
-                    code.write_inst(Bytecode::SetLocal { index: index });
-                }
-                Frame::Global if !global_env.is_topmost() => {
-                    let index = global_env
-                        .introduce_variable(name.0.clone())
-                        .unwrap_or_else(|_| {
-                            panic!(
-                                "Variable '{0}' already exists in global environment",
-                                name.0
-                            )
-                        });
-                    code.write_inst(Bytecode::SetLocal { index: index });
-                }
-                Frame::Global => {
-                    let name_index = pool.push(Constant::from(String::from(name.as_str())));
-                    let slot_index = pool.push(Constant::Slot { name: name_index });
-                    globals.introduce_variable(slot_index);
-                    code.write_inst(Bytecode::SetGlobal { name: name_index });
-                }
-            }
-
+            define_variable(name.as_str(), pool, code, frame, globals, global_env)?;
+            code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
         AST::Array { size, value } => {
             match **value {
-                AST::Integer(_) | AST::Null | AST::AccessField {..} | AST::AccessArray {..} | AST::AccessVariable {..} => {
-                    _compile(size, pool, code, frame, globals, global_env, generator, false)?;
-                    _compile(value, pool, code, frame, globals, global_env, generator, false)?;
+                AST::Integer(_) | AST::Float(_) | AST::String(_) | AST::Null | AST::AccessField {..} | AST::AccessArray {..} | AST::AccessVariable {..} => {
+                    _compile(size, pool, code, frame, globals, global_env, context.with_drop(false))?;
+                    _compile(value, pool, code, frame, globals, global_env, context.with_drop(false))?;
                     code.write_inst(Bytecode::Array);
+                    code.write_inst_if(Bytecode::Drop, drop);
                     Ok(())
                 }
                 _ => {
                     // Create a while loop that iterates over the array and evaluates the value every time
                     // var i = 0;
-                    // var size = 0;
+                    // var size = size;
                     // var array = array(size, null);
                     // while ( i < size ) {
                     //    arr[i] = value;
                     //    i <- i + 1;
                     // }
+                    // The size and the value are compiled in place, not copied into the loop.
 
                     // var i = 0;
                     let iter_var_name = generator.generate("i");
@@ -309,18 +792,16 @@ fn _compile(
                         value: AST::Integer(0).into_boxed(),
                     };
                     _compile(
-                        &iter_var, pool, code, frame, globals, global_env, generator, true,
+                        &iter_var, pool, code, frame, globals, global_env, context.with_drop(true),
                     )?;
 
-                    // var size = 0;
+                    // var size = size;
                     let size_var_name = generator.generate("size");
-                    let size_var = AST::Variable {
-                        name: Identifier(size_var_name.clone()),
-                        value: size.clone(),
-                    };
                     _compile(
-                        &size_var, pool, code, frame, globals, global_env, generator, true,
+                        size, pool, code, frame, globals, global_env, context.with_drop(false),
                     )?;
+                    define_variable(&size_var_name, pool, code, frame, globals, global_env)?;
+                    code.write_inst(Bytecode::Drop);
 
                     // var array = array(size, null)
                     let array_var_name = generator.generate("array");
@@ -336,59 +817,92 @@ fn _compile(
                         .into_boxed(),
                     };
                     _compile(
-                        &array_var, pool, code, frame, globals, global_env, generator, true,
+                        &array_var, pool, code, frame, globals, global_env, context.with_drop(true),
                     )?;
 
+                    // while (i < size)
+                    //  arr[i] = value;
+                    //  i <- i + 1
+                    let access = |name: &String| AST::AccessVariable {
+                        name: Identifier(name.clone()),
+                    };
+                    let layout = LoopLayout::begin(
+                        false,
+                        pool,
+                        code,
+                        current_env(frame, global_env),
+                        generator,
+                    );
+                    // The body is a block, anything the value declares is local to it.
+                    current_env(frame, global_env).enter_scope();
+
                     // arr[i] = value
-                    let assign = AST::AssignArray {
-                        array: AST::AccessVariable {
-                            name: Identifier(array_var_name.clone()),
-                        }
-                        .into_boxed(),
-                        index: AST::AccessVariable {
-                            name: Identifier(iter_var_name.clone()),
-                        }
-                        .into_boxed(),
-                        value: value.clone(),
+                    for variable in [&array_var_name, &iter_var_name] {
+                        let variable = access(variable);
+                        _compile(
+                            &variable,
+                            pool,
+                            code,
+                            frame,
+                            globals,
+                            global_env,
+                            context.with_drop(false),
+                        )?;
                     }
-                    .into_boxed();
+                    _compile(
+                        value, pool, code, frame, globals, global_env, context.with_drop(false),
+                    )?;
+                    let access_idx = pool.push(Constant::from(String::from("set")));
+                    code.write_inst(Bytecode::CallMethod {
+                        name: access_idx,
+                        arguments: 3,
+                    });
+                    code.write_inst(Bytecode::Drop);
 
                     // i <- i + 1
                     let iter_add = AST::CallMethod {
-                        object: AST::AccessVariable {
-                            name: Identifier(iter_var_name.clone()),
-                        }
-                        .into_boxed(),
+                        object: access(&iter_var_name).into_boxed(),
                         name: Identifier("+".to_string()),
                         arguments: vec![AST::Integer(1).into_boxed()],
                     }
                     .into_boxed();
-                    let iter_update = AST::AssignVariable { name: Identifier(iter_var_name.clone()), value: iter_add }.into_boxed();
-
-                    // while (i < size)
-                    //  arr[i] = value;
-                    //  i <- i + 1
-                    let init_loop = AST::Loop {
-                        condition: AST::CallMethod {
-                            object: AST::AccessVariable {
-                                name: Identifier(iter_var_name.clone()),
-                            }
-                            .into_boxed(),
-                            name: Identifier("<".to_string()),
-                            arguments: vec![AST::AccessVariable {
-                                name: Identifier(size_var_name.clone()),
-                            }
-                            .into_boxed()],
-                        }
-                        .into_boxed(),
-                        body: AST::Block(vec![assign, iter_update]).into_boxed(),
-                    }.into_boxed();
-
-                    _compile(&init_loop, pool, code, frame, globals, global_env, generator, true)?;
+                    let iter_update = AST::AssignVariable {
+                        name: Identifier(iter_var_name.clone()),
+                        value: iter_add,
+                    };
+                    _compile(
+                        &iter_update,
+                        pool,
+                        code,
+                        frame,
+                        globals,
+                        global_env,
+                        context.with_drop(true),
+                    )?;
+                    leave_scope(current_env(frame, global_env), code)?;
+                    layout.end_body(code, current_env(frame, global_env));
+
+                    layout.begin_condition(code);
+                    let condition = AST::CallMethod {
+                        object: access(&iter_var_name).into_boxed(),
+                        name: Identifier("<".to_string()),
+                        arguments: vec![access(&size_var_name).into_boxed()],
+                    };
+                    _compile(
+                        &condition,
+                        pool,
+                        code,
+                        frame,
+                        globals,
+                        global_env,
+                        context.with_drop(false),
+                    )?;
+                    layout.end(pool, code);
+                    code.write_inst(Bytecode::Drop);
 
-                    let array_access = AST::AccessVariable { name: Identifier(array_var_name.clone()) };
+                    let array_access = access(&array_var_name);
 
-                    _compile(&array_access, pool, code, frame, globals, global_env, generator, drop)?;
+                    _compile(&array_access, pool, code, frame, globals, global_env, context)?;
 
                     Ok(())
 
@@ -397,11 +911,11 @@ fn _compile(
         }
         AST::Object { extends, members } => {
             _compile(
-                extends, pool, code, frame, globals, global_env, generator, false,
+                extends, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
 
             // Compile the members and save the members as constant pool indexes
-            let indexes: Vec<ConstantPoolIndex> = members
+            let indexes = members
                 .iter()
                 .map(|ast| {
                     // Love me some stars
@@ -412,32 +926,36 @@ fn _compile(
                             body,
                         } => compile_fun_def(
                             name.0.clone(),
-                            parameters,
+                            &[vec![Identifier(String::from("this"))],
+                            parameters.clone()].concat(),
                             body,
-                            true,
                             pool,
                             globals,
                             global_env,
-                            generator,
-                        )
-                        .expect("Compilation of method definition failed"),
+                            context,
+                        ),
                         AST::Variable { name, value } => {
                             _compile(
-                                &value, pool, code, frame, globals, global_env, generator, false,
-                            )
-                            .expect("Compilation failed");
+                                &value,
+                                pool,
+                                code,
+                                frame,
+                                globals,
+                                global_env,
+                                context.with_drop(false),
+                            )?;
                             let str_idx = pool.push(Constant::from(name.0.clone()));
-                            let idx = pool.push(Constant::Slot { name: str_idx });
-                            idx
+                            Ok(pool.push(Constant::Slot { name: str_idx }))
                         }
-                        _ => panic!("Object definition can only have method or variable."),
+                        _ => Err("Object definition can only have method or variable."),
                     }
                 })
-                .collect();
+                .collect::<Result<Vec<ConstantPoolIndex>, _>>()?;
 
             let obj = pool.push(Constant::Object { members: indexes });
             code.write_inst(Bytecode::Object { class: obj });
 
+            code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
         AST::AccessVariable { name } => {
@@ -464,26 +982,28 @@ fn _compile(
                     code.write_inst(Bytecode::GetGlobal { name: idx });
                 }
             };
+            code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
         AST::AccessField { object, field } => {
-            let field_idx = pool
-                .push(Constant::from(field.0.clone()));
+            // Fields are resolved by name at runtime, the object might have
+            // inherited the field, so it's not checked here.
+            let field_idx = pool.push(Constant::from(field.0.clone()));
 
-            // let slot_idx = pool.find(&Constant::Slot { name: field_idx }).expect("Slot doesn't exist");
             _compile(
-                object, pool, code, frame, globals, global_env, generator, drop,
+                object, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
             code.write_inst(Bytecode::GetField { name: field_idx });
+            code.write_inst_if(Bytecode::Drop, drop);
 
             Ok(())
         }
         AST::AccessArray { array, index } => {
             _compile(
-                array, pool, code, frame, globals, global_env, generator, false,
+                array, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
             _compile(
-                index, pool, code, frame, globals, global_env, generator, false,
+                index, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
 
             let access_idx = pool.push(Constant::from(String::from("get")));
@@ -491,11 +1011,12 @@ fn _compile(
                 name: access_idx,
                 arguments: 2,
             });
+            code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
         AST::AssignVariable { name, value } => {
             _compile(
-                value, pool, code, frame, globals, global_env, generator, false,
+                value, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
             match frame {
                 Frame::Local(env) if env.has_variable(&name.0).is_some() => {
@@ -527,17 +1048,17 @@ fn _compile(
             field,
             value,
         } => {
-            let field_idx = pool
-                .find_by_str(&field.0)
-                .expect("Given field does not exist");
-            // let slot_idx = pool.find(&Constant::Slot { name: field_idx }).expect("Slot doesn't exist");
+            // Same as with AccessField, the name is resolved at runtime.
+            let field_idx = pool.push(Constant::from(field.0.clone()));
+
             _compile(
-                object, pool, code, frame, globals, global_env, generator, false,
+                object, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
             _compile(
-                value, pool, code, frame, globals, global_env, generator, false,
+                value, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
             code.write_inst(Bytecode::SetField { name: field_idx });
+            code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
         AST::AssignArray {
@@ -546,13 +1067,13 @@ fn _compile(
             value,
         } => {
             _compile(
-                array, pool, code, frame, globals, global_env, generator, false,
+                array, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
             _compile(
-                index, pool, code, frame, globals, global_env, generator, false,
+                index, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
             _compile(
-                value, pool, code, frame, globals, global_env, generator, false,
+                value, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
 
             let access_idx = pool.push(Constant::from(String::from("set")));
@@ -560,6 +1081,7 @@ fn _compile(
                 name: access_idx,
                 arguments: 3,
             });
+            code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
         AST::Function {
@@ -571,7 +1093,7 @@ fn _compile(
             if matches!(frame, Frame::Local(_)) {
                 return Err("Functions can't be nested");
             }
-            let func = compile_fun_def(name.0.clone(), parameters, body, false, pool, globals, global_env, generator)?;
+            let func = compile_fun_def(name.0.clone(), parameters, body, pool, globals, global_env, context)?;
             globals.introduce_variable(func);
 
             Ok(())
@@ -583,13 +1105,14 @@ fn _compile(
             let fun_idx = pool.push(Constant::from(name.0.clone()));
             for ast in arguments {
                 _compile(
-                    ast, pool, code, frame, globals, global_env, generator, false,
+                    ast, pool, code, frame, globals, global_env, context.with_drop(false),
                 )?;
             }
             code.write_inst(Bytecode::CallFunction {
                 name: fun_idx,
                 arguments: arguments.len().try_into().unwrap(),
             });
+            code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
         AST::CallMethod {
@@ -601,11 +1124,11 @@ fn _compile(
             let method_idx = pool.push(Constant::from(name.0.clone()));
             // Push object first and then the arguments.
             _compile(
-                object, pool, code, frame, globals, global_env, generator, false,
+                object, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
             for ast in arguments {
                 _compile(
-                    ast, pool, code, frame, globals, global_env, generator, false,
+                    ast, pool, code, frame, globals, global_env, context.with_drop(false),
                 )?;
             }
             code.write_inst(Bytecode::CallMethod {
@@ -613,6 +1136,113 @@ fn _compile(
                 arguments: (arguments.len() + 1).try_into().unwrap(),
             });
 
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
+        // Logical operators only evaluate the right side if needed.
+        // And evaluates to false or to the value of the right side.
+        AST::And { left, right } if context.branch_false => {
+            let label_false = pool.push(Constant::from(generator.generate("and_false")));
+            let label_merge = pool.push(Constant::from(generator.generate("and_merge")));
+
+            _compile(
+                left, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            code.write_inst(Bytecode::BranchFalse { label: label_false });
+            _compile(
+                right, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            code.write_inst(Bytecode::Jump { label: label_merge });
+
+            code.write_inst(Bytecode::Label { name: label_false });
+            let index = pool.push(Constant::from(false));
+            code.write_inst(Bytecode::Literal { index });
+
+            code.write_inst(Bytecode::Label { name: label_merge });
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
+        AST::And { left, right } => {
+            let label_right = pool.push(Constant::from(generator.generate("and_right")));
+            let label_merge = pool.push(Constant::from(generator.generate("and_merge")));
+
+            _compile(
+                left, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            code.write_inst(Bytecode::Branch { label: label_right });
+            let index = pool.push(Constant::from(false));
+            code.write_inst(Bytecode::Literal { index });
+            code.write_inst(Bytecode::Jump { label: label_merge });
+
+            code.write_inst(Bytecode::Label { name: label_right });
+            _compile(
+                right, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+
+            code.write_inst(Bytecode::Label { name: label_merge });
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
+        // Or evaluates to true or to the value of the right side.
+        AST::Or { left, right } => {
+            let label_true = pool.push(Constant::from(generator.generate("or_true")));
+            let label_merge = pool.push(Constant::from(generator.generate("or_merge")));
+
+            _compile(
+                left, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            code.write_inst(Bytecode::Branch { label: label_true });
+            _compile(
+                right, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            code.write_inst(Bytecode::Jump { label: label_merge });
+
+            code.write_inst(Bytecode::Label { name: label_true });
+            let index = pool.push(Constant::from(true));
+            code.write_inst(Bytecode::Literal { index });
+
+            code.write_inst(Bytecode::Label { name: label_merge });
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
+        AST::Not { operand } if context.branch_false => {
+            let label_false = pool.push(Constant::from(generator.generate("not_false")));
+            let label_merge = pool.push(Constant::from(generator.generate("not_merge")));
+
+            _compile(
+                operand, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            code.write_inst(Bytecode::BranchFalse { label: label_false });
+            let index = pool.push(Constant::from(false));
+            code.write_inst(Bytecode::Literal { index });
+            code.write_inst(Bytecode::Jump { label: label_merge });
+
+            code.write_inst(Bytecode::Label { name: label_false });
+            let index = pool.push(Constant::from(true));
+            code.write_inst(Bytecode::Literal { index });
+
+            code.write_inst(Bytecode::Label { name: label_merge });
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
+        AST::Not { operand } => {
+            let label_true = pool.push(Constant::from(generator.generate("not_true")));
+            let label_merge = pool.push(Constant::from(generator.generate("not_merge")));
+
+            _compile(
+                operand, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            code.write_inst(Bytecode::Branch { label: label_true });
+            let index = pool.push(Constant::from(true));
+            code.write_inst(Bytecode::Literal { index });
+            code.write_inst(Bytecode::Jump { label: label_merge });
+
+            code.write_inst(Bytecode::Label { name: label_true });
+            let index = pool.push(Constant::from(false));
+            code.write_inst(Bytecode::Literal { index });
+
+            code.write_inst(Bytecode::Label { name: label_merge });
+            code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
         // Here, global statements or functions definitions are
@@ -621,6 +1251,10 @@ fn _compile(
             let mut code_main = Code::new();
 
             for ast in asts.iter() {
+                // Imports are resolved before compilation, modules are linked afterwards.
+                if matches!(**ast, AST::Import { .. }) {
+                    continue;
+                }
                 // We send here code_main even if new function is encountered,
                 // but that function will define it's own code vector anyway.
                 _compile(
@@ -630,11 +1264,12 @@ fn _compile(
                     &mut Frame::Global,
                     globals,
                     global_env,
-                    generator,
-                    true,
+                    context.with_drop(true),
                 )?;
             }
 
+            code_main.end_locals(|_| true);
+
             // println!("{:?}", global_env);
             let func_name = pool.push(Constant::from(String::from("λ:")));
             let fun = Constant::Function {
@@ -658,8 +1293,15 @@ fn _compile(
                 }
             }
 
+            // Empty block evaluates to null
+            if asts.is_empty() && !drop {
+                let index = pool.push(Constant::Null);
+                code.write_inst(Bytecode::Literal { index });
+            }
+
             let mut it = asts.iter().peekable();
-            // Discard all values from stack except the last one
+            // Discard all values from stack except the last one,
+            // that one is dropped only if the value of the block is.
             while let Some(ast) = it.next() {
                 _compile(
                     ast,
@@ -668,42 +1310,247 @@ fn _compile(
                     frame,
                     globals,
                     global_env,
-                    generator,
-                    it.peek().is_some() && !drop,
+                    context.with_drop(it.peek().is_some() || drop),
                 )?;
             }
 
             match frame {
                 Frame::Global => {
-                    global_env.leave_scope()?;
+                    leave_scope(global_env, code)?;
                 }
                 Frame::Local(env) => {
-                    env.leave_scope()?;
+                    leave_scope(env, code)?;
                 }
             }
             Ok(())
         }
         AST::Loop { condition, body } => {
-            let label_begin = pool.push(Constant::from(generator.generate("while_begin")));
-            let label_cond = pool.push(Constant::from(generator.generate("while_cond")));
+            let layout =
+                LoopLayout::begin(false, pool, code, current_env(frame, global_env), generator);
+            _compile(
+                body, pool, code, frame, globals, global_env, context.with_drop(true),
+            )?;
+            layout.end_body(code, current_env(frame, global_env));
+            layout.begin_condition(code);
+            _compile(
+                condition, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            layout.end(pool, code);
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
+        AST::For {
+            variable,
+            from,
+            to,
+            body,
+        } => {
+            // Compiled as
+            // {
+            //   var variable = from;
+            //   var end = to;
+            //   while (variable < end) {
+            //     body;
+            //     variable <- variable + 1;
+            //   }
+            // }
+            // with continue jumping to the increment. The bounds and the body are
+            // compiled in place.
+            current_env(frame, global_env).enter_scope();
+
+            let end_var_name = generator.generate("end");
+            for (name, value) in [(variable.0.as_str(), from), (end_var_name.as_str(), to)] {
+                _compile(
+                    value, pool, code, frame, globals, global_env, context.with_drop(false),
+                )?;
+                define_variable(name, pool, code, frame, globals, global_env)?;
+                code.write_inst(Bytecode::Drop);
+            }
+
+            let layout =
+                LoopLayout::begin(true, pool, code, current_env(frame, global_env), generator);
+            _compile(
+                body, pool, code, frame, globals, global_env, context.with_drop(true),
+            )?;
+            layout.end_body(code, current_env(frame, global_env));
+
+            let step = AST::AssignVariable {
+                name: variable.clone(),
+                value: AST::CallMethod {
+                    object: AST::AccessVariable {
+                        name: variable.clone(),
+                    }
+                    .into_boxed(),
+                    name: Identifier("+".to_string()),
+                    arguments: vec![AST::Integer(1).into_boxed()],
+                }
+                .into_boxed(),
+            };
+            _compile(
+                &step, pool, code, frame, globals, global_env, context.with_drop(true),
+            )?;
+
+            layout.begin_condition(code);
+            let condition = AST::CallMethod {
+                object: AST::AccessVariable {
+                    name: variable.clone(),
+                }
+                .into_boxed(),
+                name: Identifier("<".to_string()),
+                arguments: vec![AST::AccessVariable {
+                    name: Identifier(end_var_name),
+                }
+                .into_boxed()],
+            };
+            _compile(
+                &condition, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            layout.end(pool, code);
 
-            // Since there is no instruction for negation, we need to evaluate the condition at the end
-            // if it's false, we just fall through.
-            code.write_inst(Bytecode::Jump { label: label_cond });
+            leave_scope(current_env(frame, global_env), code)?;
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
+        AST::ForEach {
+            variable,
+            array,
+            body,
+        } => {
+            // Compiled as
+            // {
+            //   var array = array;
+            //   var size = array.length();
+            //   var i = 0;
+            //   var variable = null;
+            //   while (i < size) {
+            //     variable <- array[i];
+            //     body;
+            //     i <- i + 1;
+            //   }
+            // }
+            // with continue jumping to the increment. The array and the body are
+            // compiled in place.
+            current_env(frame, global_env).enter_scope();
+
+            let access = |name: &String| {
+                AST::AccessVariable {
+                    name: Identifier(name.clone()),
+                }
+                .into_boxed()
+            };
+            let array_var_name = generator.generate("array");
+            let size_var_name = generator.generate("size");
+            let iter_var_name = generator.generate("i");
+            _compile(
+                array, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            define_variable(&array_var_name, pool, code, frame, globals, global_env)?;
+            code.write_inst(Bytecode::Drop);
+            let init = [
+                AST::Variable {
+                    name: Identifier(size_var_name.clone()),
+                    value: AST::CallMethod {
+                        object: access(&array_var_name),
+                        name: Identifier("length".to_string()),
+                        arguments: vec![],
+                    }
+                    .into_boxed(),
+                },
+                AST::Variable {
+                    name: Identifier(iter_var_name.clone()),
+                    value: AST::Integer(0).into_boxed(),
+                },
+                AST::Variable {
+                    name: variable.clone(),
+                    value: AST::Null.into_boxed(),
+                },
+            ];
+            for ast in init.iter() {
+                _compile(
+                    ast, pool, code, frame, globals, global_env, context.with_drop(true),
+                )?;
+            }
 
-            // Body
-            code.write_inst(Bytecode::Label { name: label_begin });
+            let layout =
+                LoopLayout::begin(true, pool, code, current_env(frame, global_env), generator);
+            // The body is a block, which starts with the assignment of the element.
+            current_env(frame, global_env).enter_scope();
+            let element = AST::AssignVariable {
+                name: variable.clone(),
+                value: AST::AccessArray {
+                    array: access(&array_var_name),
+                    index: access(&iter_var_name),
+                }
+                .into_boxed(),
+            };
+            for ast in [&element, &**body] {
+                _compile(
+                    ast, pool, code, frame, globals, global_env, context.with_drop(true),
+                )?;
+            }
+            leave_scope(current_env(frame, global_env), code)?;
+            layout.end_body(code, current_env(frame, global_env));
+
+            let step = AST::AssignVariable {
+                name: Identifier(iter_var_name.clone()),
+                value: AST::CallMethod {
+                    object: access(&iter_var_name),
+                    name: Identifier("+".to_string()),
+                    arguments: vec![AST::Integer(1).into_boxed()],
+                }
+                .into_boxed(),
+            };
             _compile(
-                body, pool, code, frame, globals, global_env, generator, drop,
+                &step, pool, code, frame, globals, global_env, context.with_drop(true),
             )?;
 
-            // Condition
-            code.write_inst(Bytecode::Label { name: label_cond });
+            layout.begin_condition(code);
+            let condition = AST::CallMethod {
+                object: access(&iter_var_name),
+                name: Identifier("<".to_string()),
+                arguments: vec![access(&size_var_name)],
+            };
             _compile(
-                condition, pool, code, frame, globals, global_env, generator, false,
+                &condition, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
-            code.write_inst(Bytecode::Branch { label: label_begin });
+            layout.end(pool, code);
 
+            leave_scope(current_env(frame, global_env), code)?;
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
+        AST::Break { value } => {
+            let env = current_env(frame, global_env);
+            let labels = *env.loops.last().ok_or("Break outside of a loop.")?;
+            if labels.finally_depth != env.finally_depth {
+                return Err("Break can't leave a try block with finally.");
+            }
+            // Operands of the enclosing expressions are left behind
+            drop_operands(labels.stack, pool, code);
+            match value {
+                Some(value) => _compile(
+                    value, pool, code, frame, globals, global_env, context.with_drop(false),
+                )?,
+                None => {
+                    let index = pool.push(Constant::Null);
+                    code.write_inst(Bytecode::Literal { index });
+                }
+            }
+            code.write_inst(Bytecode::Jump {
+                label: labels.exit,
+            });
+            Ok(())
+        }
+        AST::Continue => {
+            let env = current_env(frame, global_env);
+            let labels = *env.loops.last().ok_or("Continue outside of a loop.")?;
+            if labels.finally_depth != env.finally_depth {
+                return Err("Continue can't leave a try block with finally.");
+            }
+            drop_operands(labels.stack, pool, code);
+            code.write_inst(Bytecode::Jump {
+                label: labels.next,
+            });
             Ok(())
         }
         AST::Conditional {
@@ -711,46 +1558,49 @@ fn _compile(
             consequent,
             alternative,
         } => {
-            let label_then = pool.push(Constant::from(generator.generate("if_then")));
+            let label_then = (!context.branch_false)
+                .then(|| pool.push(Constant::from(generator.generate("if_then"))));
             let label_else = pool.push(Constant::from(generator.generate("if_else")));
             let label_merge = pool.push(Constant::from(generator.generate("if_merge")));
 
             _compile(
-                condition, pool, code, frame, globals, global_env, generator, false,
+                condition, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
-            code.write_inst(Bytecode::Branch { label: label_then });
-            code.write_inst(Bytecode::Jump { label: label_else });
+            match label_then {
+                Some(label_then) => {
+                    code.write_inst(Bytecode::Branch { label: label_then });
+                    code.write_inst(Bytecode::Jump { label: label_else });
+                    code.write_inst(Bytecode::Label { name: label_then });
+                }
+                None => code.write_inst(Bytecode::BranchFalse { label: label_else }),
+            }
 
             // Then body
-            code.write_inst(Bytecode::Label { name: label_then });
             _compile(
-                consequent, pool, code, frame, globals, global_env, generator, false,
+                consequent, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
             code.write_inst(Bytecode::Jump { label: label_merge });
 
             // Else body
             code.write_inst(Bytecode::Label { name: label_else });
             _compile(
-                alternative,
-                pool,
-                code,
-                frame,
-                globals,
-                global_env,
-                generator,
-                false,
+                alternative, pool, code, frame, globals, global_env, context.with_drop(false),
             )?;
 
             // Merge label
             code.write_inst(Bytecode::Label { name: label_merge });
+            code.write_inst_if(Bytecode::Drop, drop);
 
             Ok(())
         }
         AST::Print { format, arguments } => {
+            if count_placeholders(format)? != arguments.len() {
+                return Err("Number of print arguments doesn't match the format.");
+            }
             let string = pool.push(Constant::from(format.clone()));
             for ast in arguments.iter() {
                 _compile(
-                    ast, pool, code, frame, globals, global_env, generator, false,
+                    ast, pool, code, frame, globals, global_env, context.with_drop(false),
                 )?;
             }
             let print = Bytecode::Print {
@@ -761,6 +1611,137 @@ fn _compile(
             code.write_inst_if(Bytecode::Drop, drop);
             Ok(())
         }
+        AST::Import { .. } => Err("Imports are allowed only at the top level."),
+        AST::Throw { value } => {
+            _compile(
+                value, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            code.write_inst(Bytecode::Throw);
+            Ok(())
+        }
+        AST::Try {
+            body,
+            catch_var,
+            handler,
+            finally,
+        } => {
+            // Compiled as
+            //   try_start:
+            //     body
+            //   try_end:
+            //     finally
+            //     jump try_merge
+            //   try_handler:
+            //     catch_var <- exception
+            //   catch_start:
+            //     handler
+            //   catch_end:
+            //     finally
+            //     jump try_merge
+            //   catch_rethrow:
+            //     tmp <- exception
+            //     finally
+            //     throw tmp
+            //   try_merge:
+            // where the rethrow part is present only if there is a finally block.
+            let label_start = pool.push(Constant::from(generator.generate("try_start")));
+            let label_end = pool.push(Constant::from(generator.generate("try_end")));
+            let label_handler = pool.push(Constant::from(generator.generate("try_handler")));
+            let label_merge = pool.push(Constant::from(generator.generate("try_merge")));
+            let stack = code.stack_depth(pool);
+
+            current_env(frame, global_env).enter_scope();
+            let has_finally = finally.is_some() as usize;
+
+            code.write_inst(Bytecode::Label { name: label_start });
+            current_env(frame, global_env).finally_depth += has_finally;
+            _compile(
+                body, pool, code, frame, globals, global_env, context.with_drop(false),
+            )?;
+            current_env(frame, global_env).finally_depth -= has_finally;
+            code.write_inst(Bytecode::Label { name: label_end });
+            code.add_handler(ExceptionHandler {
+                start: label_start,
+                end: label_end,
+                handler: label_handler,
+                stack,
+            });
+            if let Some(finally) = finally {
+                _compile(
+                    finally, pool, code, frame, globals, global_env, context.with_drop(true),
+                )?;
+            }
+            code.write_inst(Bytecode::Jump { label: label_merge });
+
+            // Handler
+            code.write_inst(Bytecode::Label {
+                name: label_handler,
+            });
+            let catch_index = current_env(frame, global_env)
+                .introduce_variable(catch_var.0.clone())?;
+            code.add_local(catch_index, catch_var.0.clone());
+            code.write_inst(Bytecode::SetLocal { index: catch_index });
+            code.write_inst(Bytecode::Drop);
+
+            match finally {
+                None => {
+                    _compile(
+                        handler, pool, code, frame, globals, global_env, context.with_drop(false),
+                    )?;
+                }
+                Some(finally) => {
+                    let label_catch_start =
+                        pool.push(Constant::from(generator.generate("catch_start")));
+                    let label_catch_end =
+                        pool.push(Constant::from(generator.generate("catch_end")));
+                    let label_rethrow =
+                        pool.push(Constant::from(generator.generate("catch_rethrow")));
+
+                    code.write_inst(Bytecode::Label {
+                        name: label_catch_start,
+                    });
+                    current_env(frame, global_env).finally_depth += 1;
+                    _compile(
+                        handler, pool, code, frame, globals, global_env, context.with_drop(false),
+                    )?;
+                    current_env(frame, global_env).finally_depth -= 1;
+                    code.write_inst(Bytecode::Label {
+                        name: label_catch_end,
+                    });
+                    code.add_handler(ExceptionHandler {
+                        start: label_catch_start,
+                        end: label_catch_end,
+                        handler: label_rethrow,
+                        stack,
+                    });
+                    _compile(
+                        finally, pool, code, frame, globals, global_env, context.with_drop(true),
+                    )?;
+                    code.write_inst(Bytecode::Jump { label: label_merge });
+
+                    // Exception thrown from the handler, run finally and throw it again
+                    code.write_inst(Bytecode::Label {
+                        name: label_rethrow,
+                    });
+                    let tmp_name = generator.generate("exception");
+                    let tmp_index =
+                        current_env(frame, global_env).introduce_variable(tmp_name.clone())?;
+                    code.add_local(tmp_index, tmp_name);
+                    code.write_inst(Bytecode::SetLocal { index: tmp_index });
+                    code.write_inst(Bytecode::Drop);
+                    _compile(
+                        finally, pool, code, frame, globals, global_env, context.with_drop(true),
+                    )?;
+                    code.write_inst(Bytecode::GetLocal { index: tmp_index });
+                    code.write_inst(Bytecode::Throw);
+                }
+            }
+
+            code.write_inst(Bytecode::Label { name: label_merge });
+            leave_scope(current_env(frame, global_env), code)?;
+            code.write_inst_if(Bytecode::Drop, drop);
+            Ok(())
+        }
     }
 }
 
@@ -768,6 +1749,475 @@ fn _compile(
 mod tests {
     use super::*;
 
+    fn compile_top(ast: &AST) -> Result<ConstantPool, &'static str> {
+        let mut pool = ConstantPool::new();
+        _compile(
+            ast,
+            &mut pool,
+            &mut Code::new(),
+            &mut Frame::Global,
+            &mut Globals::new(),
+            &mut VecEnvironments::new(),
+            Context {
+                spans: &Spans::default(),
+                generator: &RandomNameGenerator::new(),
+                branch_false: false,
+                drop: true,
+            },
+        )?;
+        Ok(pool)
+    }
+
+    fn main_code(pool: &ConstantPool) -> &Code {
+        match pool.get(pool.len() - 1) {
+            Constant::Function { code, .. } => code,
+            _ => panic!("Main function is not last."),
+        }
+    }
+
+    #[test]
+    fn string_test() {
+        let ast = AST::Top(vec![
+            AST::String(String::from("a")).into_boxed(),
+            AST::String(String::from("b")).into_boxed(),
+            AST::String(String::from("a")).into_boxed(),
+        ]);
+        let pool = compile_top(&ast).unwrap();
+        let a = pool.find(&Constant::from(String::from("a"))).unwrap();
+        let b = pool.find(&Constant::from(String::from("b"))).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(
+            main_code(&pool).insert_point,
+            vec![
+                Bytecode::Literal { index: a },
+                Bytecode::Drop,
+                Bytecode::Literal { index: b },
+                Bytecode::Drop,
+                Bytecode::Literal { index: a },
+                Bytecode::Drop,
+            ]
+        );
+    }
+
+    #[test]
+    fn logical_test() {
+        let ast = AST::Top(vec![
+            AST::And {
+                left: AST::Boolean(true).into_boxed(),
+                right: AST::Integer(1).into_boxed(),
+            }
+            .into_boxed(),
+            AST::Or {
+                left: AST::Boolean(false).into_boxed(),
+                right: AST::Integer(2).into_boxed(),
+            }
+            .into_boxed(),
+            AST::Not {
+                operand: AST::Boolean(true).into_boxed(),
+            }
+            .into_boxed(),
+        ]);
+        // The standard instructions branch over a jump instead.
+        let standard = compile_top(&ast).unwrap();
+        assert!(!main_code(&standard)
+            .insert_point
+            .iter()
+            .any(|inst| matches!(inst, Bytecode::BranchFalse { .. })));
+
+        let options = CompilerOptions {
+            branch_false: true,
+            ..CompilerOptions::default()
+        };
+        let pool = compile(&ast, &options).unwrap().pool;
+        let find = |constant: Constant| pool.find(&constant).unwrap();
+        let label = |name: &str| find(Constant::from(String::from(name)));
+        let (t, f) = (find(Constant::from(true)), find(Constant::from(false)));
+        assert_eq!(
+            main_code(&pool).insert_point,
+            vec![
+                // true && 1
+                Bytecode::Literal { index: t },
+                Bytecode::BranchFalse {
+                    label: label("and_false_0")
+                },
+                Bytecode::Literal {
+                    index: find(Constant::Integer(1))
+                },
+                Bytecode::Jump {
+                    label: label("and_merge_1")
+                },
+                Bytecode::Label {
+                    name: label("and_false_0")
+                },
+                Bytecode::Literal { index: f },
+                Bytecode::Label {
+                    name: label("and_merge_1")
+                },
+                Bytecode::Drop,
+                // false || 2
+                Bytecode::Literal { index: f },
+                Bytecode::Branch {
+                    label: label("or_true_2")
+                },
+                Bytecode::Literal {
+                    index: find(Constant::Integer(2))
+                },
+                Bytecode::Jump {
+                    label: label("or_merge_3")
+                },
+                Bytecode::Label {
+                    name: label("or_true_2")
+                },
+                Bytecode::Literal { index: t },
+                Bytecode::Label {
+                    name: label("or_merge_3")
+                },
+                Bytecode::Drop,
+                // !true
+                Bytecode::Literal { index: t },
+                Bytecode::BranchFalse {
+                    label: label("not_false_4")
+                },
+                Bytecode::Literal { index: f },
+                Bytecode::Jump {
+                    label: label("not_merge_5")
+                },
+                Bytecode::Label {
+                    name: label("not_false_4")
+                },
+                Bytecode::Literal { index: t },
+                Bytecode::Label {
+                    name: label("not_merge_5")
+                },
+                Bytecode::Drop,
+            ]
+        );
+    }
+
+    #[test]
+    fn long_test() {
+        let ast = AST::Top(vec![
+            AST::Integer(i32::MAX as i64).into_boxed(),
+            AST::Integer(i32::MAX as i64 + 1).into_boxed(),
+        ]);
+        let pool = compile_top(&ast).unwrap();
+        let int = pool.find(&Constant::Integer(i32::MAX)).unwrap();
+        let long = pool.find(&Constant::Long(i32::MAX as i64 + 1)).unwrap();
+        assert_eq!(
+            main_code(&pool).insert_point,
+            vec![
+                Bytecode::Literal { index: int },
+                Bytecode::Drop,
+                Bytecode::Literal { index: long },
+                Bytecode::Drop,
+            ]
+        );
+
+        let mut bytes = Vec::new();
+        pool.get(long).serializable_byte(&mut bytes).unwrap();
+        assert_eq!(bytes[0], 0x08);
+        assert_eq!(bytes[1..], (i32::MAX as i64 + 1).to_le_bytes());
+    }
+
+    #[test]
+    fn break_outside_loop_test() {
+        let loop_body = AST::Block(vec![AST::Break { value: None }.into_boxed()]);
+        let ast = AST::Top(vec![AST::For {
+            variable: Identifier(String::from("i")),
+            from: AST::Integer(0).into_boxed(),
+            to: AST::Integer(10).into_boxed(),
+            body: loop_body.clone().into_boxed(),
+        }
+        .into_boxed()]);
+        assert!(compile_top(&ast).is_ok());
+
+        let ast = AST::Top(vec![loop_body.into_boxed()]);
+        assert_eq!(compile_top(&ast).unwrap_err(), "Break outside of a loop.");
+    }
+
+    #[test]
+    fn break_operands_test() {
+        let ast: AST = serde_json::from_str(
+            r#"{"Top": [
+                {"Function": {"name": "f", "parameters": ["a", "b"], "body": "Null"}},
+                {"Print": {"format": "~ ~\n", "arguments": [{"Integer": 7}, {"Loop": {
+                    "condition": {"Boolean": true},
+                    "body": {"CallFunction": {"name": "f", "arguments": [
+                        {"Integer": 1}, {"Break": {"value": {"Integer": 2}}}]}}}}]}},
+                {"Print": {"format": "~ ~\n", "arguments": [{"Integer": 7}, {"For": {
+                    "variable": "i", "from": {"Integer": 0}, "to": {"Integer": 3},
+                    "body": {"CallFunction": {"name": "f", "arguments": [
+                        {"AccessVariable": {"name": "i"}}, "Continue"]}}}}]}}
+            ]}"#,
+        )
+        .unwrap();
+        let pool = compile_top(&ast).unwrap();
+        let one = pool.find(&Constant::Integer(1)).unwrap();
+        let two = pool.find(&Constant::Integer(2)).unwrap();
+        // f(1, break 2)
+        assert!(main_code(&pool).insert_point.windows(4).any(|insts| matches!(
+            insts,
+            [Bytecode::Literal { index: a }, Bytecode::Drop, Bytecode::Literal { index: b }, Bytecode::Jump { .. }]
+                if *a == one && *b == two
+        )));
+
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+        let mut output = Vec::new();
+        let mut interpreter = crate::interpreter::Interpreter::new(&mut output);
+        interpreter.load_globals(&program.pool, &program.globals.globals);
+        interpreter.run(&program.pool, program.entry).unwrap();
+        drop(interpreter);
+        assert_eq!(String::from_utf8(output).unwrap(), "7 2\n7 null\n");
+    }
+
+    #[test]
+    fn loop_spans_test() {
+        let text = r#"{"Top": [
+            {"For": {"variable": "i", "from": {"Integer": 0}, "to": {"Integer": 2},
+                "body": {"ForEach": {"variable": "x", "array": {"Array": {
+                    "size": {"Integer": 1}, "value": {"Integer": 0}}},
+                    "body": {"Print": {"format": "~\n", "arguments": [{"AccessVariable": {"name": "x"}}]}}}}}}
+        ]}"#;
+        let ast: AST = serde_json::from_str(text).unwrap();
+        let spans = Spans::new(text, &ast);
+        let print = match &ast {
+            AST::Top(items) => match &*items[0] {
+                AST::For { body, .. } => match &**body {
+                    AST::ForEach { body, .. } => body,
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        let span = spans.get(print).unwrap();
+        let options = CompilerOptions {
+            debug: Some(spans),
+            ..CompilerOptions::default()
+        };
+        let program = compile(&ast, &options).unwrap();
+        let code = match program.pool.get(program.entry) {
+            Constant::Function { code, .. } => code,
+            _ => unreachable!(),
+        };
+        assert!(code.lines.iter().any(|entry| entry.span == span));
+    }
+
+    #[test]
+    fn try_test() {
+        // f(1, try { throw 2 } catch (e) { e } finally { 3 })
+        let ast = AST::Top(vec![AST::CallFunction {
+            name: Identifier(String::from("f")),
+            arguments: vec![
+                AST::Integer(1).into_boxed(),
+                AST::Try {
+                    body: AST::Throw {
+                        value: AST::Integer(2).into_boxed(),
+                    }
+                    .into_boxed(),
+                    catch_var: Identifier(String::from("e")),
+                    handler: AST::AccessVariable {
+                        name: Identifier(String::from("e")),
+                    }
+                    .into_boxed(),
+                    finally: Some(AST::Integer(3).into_boxed()),
+                }
+                .into_boxed(),
+            ],
+        }
+        .into_boxed()]);
+
+        let pool = compile_top(&ast).unwrap();
+        let handlers = match pool.get(pool.len() - 1) {
+            Constant::Function { code, .. } => code.handlers.clone(),
+            _ => panic!("Main function is not last."),
+        };
+        // One for the body, one for the handler, both expect
+        // the first argument on the stack.
+        assert_eq!(handlers.len(), 2);
+        assert!(handlers.iter().all(|handler| handler.stack == 1));
+    }
+
+    #[test]
+    fn print_format_test() {
+        assert_eq!(count_placeholders("~ + ~ = ~\\n"), Ok(3));
+        assert_eq!(count_placeholders("\\~\\t\\\\\\\""), Ok(0));
+        assert!(count_placeholders("\\a").is_err());
+        assert!(count_placeholders("~\\").is_err());
+
+        let print = AST::Print {
+            format: String::from("~ ~"),
+            arguments: vec![AST::Null.into_boxed()],
+        };
+        assert!(compile_top(&AST::Top(vec![print.into_boxed()])).is_err());
+    }
+
+    #[test]
+    fn undeclared_fields_test() {
+        let field = |object: &str, field: &str| {
+            AST::AccessField {
+                object: AST::AccessVariable {
+                    name: Identifier(String::from(object)),
+                }
+                .into_boxed(),
+                field: Identifier(String::from(field)),
+            }
+            .into_boxed()
+        };
+        let ast = AST::Top(vec![
+            AST::Variable {
+                name: Identifier(String::from("obj")),
+                value: AST::Object {
+                    extends: AST::Null.into_boxed(),
+                    members: vec![AST::Variable {
+                        name: Identifier(String::from("x")),
+                        value: AST::Integer(1).into_boxed(),
+                    }
+                    .into_boxed()],
+                }
+                .into_boxed(),
+            }
+            .into_boxed(),
+            field("obj", "x"),
+            field("obj", "y"),
+            AST::AssignField {
+                object: field("obj", "y"),
+                field: Identifier(String::from("z")),
+                value: AST::Null.into_boxed(),
+            }
+            .into_boxed(),
+        ]);
+
+        assert_eq!(undeclared_fields(&ast), vec!["y", "z"]);
+    }
+
+    #[test]
+    fn streaming_test() {
+        let text = r#"{"Top": [
+            {"Variable": {"name": "x", "value": {"Integer": 1}}},
+            {"Function": {"name": "f", "parameters": ["n"], "body": {"Array": {
+                "size": {"AccessVariable": {"name": "n"}},
+                "value": {"AccessArray": {"array": {"Array": {"size": {"Integer": 1},
+                    "value": {"AccessVariable": {"name": "x"}}}}, "index": {"Integer": 0}}}}}}},
+            {"Print": {"format": "~\\n", "arguments": [
+                {"CallFunction": {"name": "f", "arguments": [{"Integer": 3}]}}]}}
+        ]}"#;
+        let options = CompilerOptions::default();
+        let mut expected = Vec::new();
+        compile(&AST::from_json(text).unwrap(), &options)
+            .unwrap()
+            .serializable_byte(&mut expected)
+            .unwrap();
+
+        let mut compiler = StreamingCompiler::new(&options);
+        AST::for_each_item(text.as_bytes(), |item| {
+            compiler.compile_item(item).map_err(String::from)
+        })
+        .unwrap();
+        let mut streamed = Vec::new();
+        compiler.finish().serializable_byte(&mut streamed).unwrap();
+        assert_eq!(streamed, expected);
+
+        let text = r#"{"Top": [{"Import": {"path": "module.json"}}]}"#;
+        let mut compiler = StreamingCompiler::new(&options);
+        let result = AST::for_each_item(text.as_bytes(), |item| {
+            compiler.compile_item(item).map_err(String::from)
+        });
+        assert!(result.is_err());
+        assert!(AST::for_each_item(r#"{"Block": []}"#.as_bytes(), |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn deep_nesting_test() {
+        let depth = 100_000;
+        let text = nested_additions(depth);
+        let ast = AST::from_json(&text).unwrap();
+        let options = CompilerOptions {
+            branch_false: false,
+            operators: false,
+            superinstructions: false,
+            debug: Some(Spans::new(&text, &ast)),
+        };
+        let program = compile(&ast, &options).unwrap();
+        assert!(undeclared_fields(&ast).is_empty());
+
+        let mut output = Vec::new();
+        let mut interpreter = crate::interpreter::Interpreter::new(&mut output);
+        interpreter.load_globals(&program.pool, &program.globals.globals);
+        interpreter.run(&program.pool, program.entry).unwrap();
+        drop(interpreter);
+        assert_eq!(String::from_utf8(output).unwrap(), (depth + 1).to_string());
+    }
+
+    #[test]
+    fn deep_decompile_test() {
+        let depth = 100_000;
+        let ast = AST::from_json(&nested_additions(depth)).unwrap();
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+
+        let decompiled = crate::decompiler::decompile(&program).unwrap();
+        assert!(crate::printer::to_source(&decompiled).starts_with("print(\"~\", 1 + (1 + ("));
+        let recompiled = AST::from_json(&decompiled.to_json().unwrap()).unwrap();
+        let program = compile(&recompiled, &CompilerOptions::default()).unwrap();
+
+        let mut output = Vec::new();
+        let mut interpreter = crate::interpreter::Interpreter::new(&mut output);
+        interpreter.load_globals(&program.pool, &program.globals.globals);
+        interpreter.run(&program.pool, program.entry).unwrap();
+        drop(interpreter);
+        assert_eq!(String::from_utf8(output).unwrap(), (depth + 1).to_string());
+    }
+
+    /**
+     * JSON of `print("~", 1 + (1 + (1 + ... + 1)))` with the given number of additions.
+     */
+    fn nested_additions(depth: usize) -> String {
+        let mut text = String::from(r#"{"Top": [{"Print": {"format": "~", "arguments": ["#);
+        for _ in 0..depth {
+            text.push_str(
+                r#"{"CallMethod": {"object": {"Integer": 1}, "name": "+", "arguments": ["#,
+            );
+        }
+        text.push_str(r#"{"Integer": 1}"#);
+        text.push_str(&"]}}".repeat(depth));
+        text.push_str("]}}]}");
+        text
+    }
+
+    /**
+     * Compilation of a 100k-node program, then the constant lookups it makes timed
+     * against searching the pool linearly. Run with `cargo test --release -- --ignored`.
+     */
+    #[test]
+    #[ignore]
+    fn large_program_bench() {
+        let statements = (0..50_000)
+            .map(|i| {
+                AST::Print {
+                    format: String::from("~\\n"),
+                    arguments: vec![AST::Integer(i % 30_000).into_boxed()],
+                }
+                .into_boxed()
+            })
+            .collect();
+        let ast = AST::Top(statements);
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+        assert_eq!(program.pool.len(), 30_003);
+
+        let constants: Vec<Constant> = program.pool.iter().step_by(10).cloned().collect();
+        let start = std::time::Instant::now();
+        for constant in constants.iter() {
+            assert!(program.pool.find(constant).is_some());
+        }
+        let indexed = start.elapsed();
+        let start = std::time::Instant::now();
+        for constant in constants.iter() {
+            assert!(program.pool.iter().any(|other| other == constant));
+        }
+        let linear = start.elapsed();
+        assert!(indexed * 10 < linear);
+    }
+
     #[test]
     fn env_test() {
         let mut env = VecEnvironments::new();
@@ -1,5 +1,7 @@
-use crate::bytecode::Code;
-use crate::serializer::Serializable;
+use crate::bytecode::{Bytecode, Code, ExceptionHandler, ResolvedHandler};
+use crate::serializer::*;
+use std::collections::HashMap;
+use std::io::Read;
 
 pub type ConstantPoolIndex = u16;
 
@@ -10,6 +12,8 @@ fn from_usize(i: usize) -> ConstantPoolIndex {
 #[derive(PartialEq, Clone, Debug)]
 pub enum Constant {
     Integer(i32),
+    Long(i64),
+    Float(f64),
     Boolean(bool),
     Null,
     String(String),
@@ -33,6 +37,18 @@ impl From<i32> for Constant {
     }
 }
 
+impl From<i64> for Constant {
+    fn from(num: i64) -> Self {
+        Constant::Long(num)
+    }
+}
+
+impl From<f64> for Constant {
+    fn from(num: f64) -> Self {
+        Constant::Float(num)
+    }
+}
+
 impl From<bool> for Constant {
     fn from(b: bool) -> Self {
         Constant::Boolean(b)
@@ -49,24 +65,32 @@ impl Serializable for Constant {
     fn serializable_byte<W: std::io::Write>(&self, output: &mut W) -> std::io::Result<()> {
         match self {
             Constant::Integer(val) => {
-                output.write(&[0x00 as u8])?;
-                output.write(&(val.to_le_bytes()))?;
+                output.write_all(&[0x00])?;
+                output.write_all(&(val.to_le_bytes()))?;
+            }
+            Constant::Long(val) => {
+                output.write_all(&[0x08])?;
+                output.write_all(&(val.to_le_bytes()))?;
+            }
+            Constant::Float(val) => {
+                output.write_all(&[0x07])?;
+                output.write_all(&(val.to_le_bytes()))?;
             }
             Constant::Boolean(val) => {
-                output.write(&[0x06 as u8])?;
-                output.write(&((*val as u8).to_le_bytes()))?;
+                output.write_all(&[0x06])?;
+                output.write_all(&((*val as u8).to_le_bytes()))?;
             }
             Constant::Null => {
-                output.write(&[0x01 as u8])?;
+                output.write_all(&[0x01])?;
             }
             Constant::String(str) => {
-                output.write(&[0x02 as u8])?;
-                output.write(&(str.len() as u32).to_le_bytes())?;
-                output.write(str.as_bytes())?;
+                output.write_all(&[0x02])?;
+                output.write_all(&(str.len() as u32).to_le_bytes())?;
+                output.write_all(str.as_bytes())?;
             }
             Constant::Slot { name } => {
-                output.write(&0x04u8.to_le_bytes())?;
-                output.write(&name.to_le_bytes())?;
+                output.write_all(&0x04u8.to_le_bytes())?;
+                output.write_all(&name.to_le_bytes())?;
             }
             Constant::Function {
                 name,
@@ -74,20 +98,39 @@ impl Serializable for Constant {
                 locals,
                 code,
             } => {
-                output.write(&[0x03 as u8])?;
-                output.write(&name.to_le_bytes())?;
-                output.write(&parameters.to_le_bytes())?;
-                output.write(&locals.to_le_bytes())?;
-                output.write(&code.len().to_le_bytes())?;
+                // Functions with exception handlers have their own tag,
+                // so the others stay readable by the standard runtimes.
+                if !code.resolved_handlers.is_empty() {
+                    output.write_all(&[0x0A])?;
+                } else if code.handlers.is_empty() {
+                    output.write_all(&[0x03])?;
+                } else {
+                    output.write_all(&[0x09])?;
+                }
+                output.write_all(&name.to_le_bytes())?;
+                output.write_all(&parameters.to_le_bytes())?;
+                output.write_all(&locals.to_le_bytes())?;
+                output.write_all(&code.len().to_le_bytes())?;
                 for bytecode in code.insert_point.iter() {
                     bytecode.serializable_byte(output)?;
                 }
+                if !code.resolved_handlers.is_empty() {
+                    output.write_all(&(code.resolved_handlers.len() as u16).to_le_bytes())?;
+                    for handler in code.resolved_handlers.iter() {
+                        handler.serializable_byte(output)?;
+                    }
+                } else if !code.handlers.is_empty() {
+                    output.write_all(&(code.handlers.len() as u16).to_le_bytes())?;
+                    for handler in code.handlers.iter() {
+                        handler.serializable_byte(output)?;
+                    }
+                }
             }
             Constant::Object { members } => {
-                output.write(&0x05u8.to_le_bytes())?;
-                output.write(&(members.len() as u16).to_le_bytes())?;
+                output.write_all(&0x05u8.to_le_bytes())?;
+                output.write_all(&(members.len() as u16).to_le_bytes())?;
                 for member in members.iter() {
-                    output.write(&member.to_le_bytes())?;
+                    output.write_all(&member.to_le_bytes())?;
                 }
             }
         }
@@ -96,12 +139,127 @@ impl Serializable for Constant {
     }
 }
 
+impl Deserializable for Constant {
+    fn deserialize<R: Read>(input: &mut R) -> std::io::Result<Self> {
+        let constant = match read_u8(input)? {
+            0x00 => Constant::Integer(read_i32(input)?),
+            0x01 => Constant::Null,
+            0x02 => {
+                let len = read_u32(input)?;
+                let mut bytes = vec![0; len as usize];
+                input.read_exact(&mut bytes)?;
+                let str = String::from_utf8(bytes)
+                    .map_err(|_| invalid_data("String constant is not valid UTF-8."))?;
+                Constant::String(str)
+            }
+            tag @ (0x03 | 0x09 | 0x0A) => {
+                let name = read_u16(input)?;
+                let parameters = read_u8(input)?;
+                let locals = read_u16(input)?;
+                let mut code = Code::new();
+                for _ in 0..read_u32(input)? {
+                    code.write_inst(Bytecode::deserialize(input)?);
+                }
+                if tag == 0x09 {
+                    for _ in 0..read_u16(input)? {
+                        code.add_handler(ExceptionHandler::deserialize(input)?);
+                    }
+                }
+                if tag == 0x0A {
+                    for _ in 0..read_u16(input)? {
+                        code.resolved_handlers
+                            .push(ResolvedHandler::deserialize(input)?);
+                    }
+                }
+                Constant::Function {
+                    name,
+                    parameters,
+                    locals,
+                    code,
+                }
+            }
+            0x04 => Constant::Slot {
+                name: read_u16(input)?,
+            },
+            0x05 => {
+                let mut members = Vec::new();
+                for _ in 0..read_u16(input)? {
+                    members.push(read_u16(input)?);
+                }
+                Constant::Object { members }
+            }
+            0x06 => Constant::Boolean(read_u8(input)? != 0),
+            0x07 => Constant::Float(read_f64(input)?),
+            0x08 => Constant::Long(read_i64(input)?),
+            _ => return Err(invalid_data("Unknown constant tag.")),
+        };
+        Ok(constant)
+    }
+}
+
+/**
+ * Value of a constant the pool looks it up by. Floats are compared bitwise,
+ * NaN wouldn't be equal to itself and -0.0 would be merged with 0.0.
+ */
+#[derive(PartialEq, Eq, Hash, Debug)]
+enum Key {
+    Integer(i32),
+    Long(i64),
+    Float(u64),
+    Boolean(bool),
+    Null,
+    Slot(ConstantPoolIndex),
+    Object(Vec<ConstantPoolIndex>),
+}
+
+impl Key {
+    /**
+     * Functions have no key, comparing their whole code would be too slow
+     * and two functions are rarely the same anyway, so they are never shared.
+     * Strings have no key either, they are kept apart to be found by `&str`.
+     */
+    fn of(constant: &Constant) -> Option<Key> {
+        let key = match constant {
+            Constant::Integer(val) => Key::Integer(*val),
+            Constant::Long(val) => Key::Long(*val),
+            Constant::Float(val) => Key::Float(val.to_bits()),
+            Constant::Boolean(val) => Key::Boolean(*val),
+            Constant::Null => Key::Null,
+            Constant::String(_) => return None,
+            Constant::Slot { name } => Key::Slot(*name),
+            Constant::Function { .. } => return None,
+            Constant::Object { members } => Key::Object(members.clone()),
+        };
+        Some(key)
+    }
+}
+
+/**
+ * Whether the pool would find the constants as the same one.
+ */
+fn same_value(a: &Constant, b: &Constant) -> bool {
+    match (a, b) {
+        (Constant::String(a), Constant::String(b)) => a == b,
+        _ => Key::of(a).is_some() && Key::of(a) == Key::of(b),
+    }
+}
+
 #[derive(Debug)]
-pub struct ConstantPool(Vec<Constant>);
+pub struct ConstantPool {
+    constants: Vec<Constant>,
+    // First index of every constant with a key.
+    indices: HashMap<Key, ConstantPoolIndex>,
+    // First index of every string, looked up without copying the name.
+    strings: HashMap<String, ConstantPoolIndex>,
+}
 
 impl ConstantPool {
     pub fn new() -> Self {
-        ConstantPool(Vec::new())
+        ConstantPool {
+            constants: Vec::new(),
+            indices: HashMap::new(),
+            strings: HashMap::new(),
+        }
     }
 
     /**
@@ -111,43 +269,210 @@ impl ConstantPool {
     pub fn push(&mut self, constant: Constant) -> ConstantPoolIndex {
         match self.find(&constant) {
             Some(idx) => idx,
-            None => {
-                self.0.push(constant);
-                (self.0.len() - 1).try_into().unwrap()
+            None => self.add(constant),
+        }
+    }
+
+    /**
+     * Appends the constant, even if it's already in the pool.
+     */
+    pub fn add(&mut self, constant: Constant) -> ConstantPoolIndex {
+        let index = from_usize(self.constants.len());
+        if self.find(&constant).is_none() {
+            self.set_first(&constant, Some(index));
+        }
+        self.constants.push(constant);
+        index
+    }
+
+    /**
+     * Overwrites the constant at the index, keeping the lookup consistent.
+     */
+    pub fn replace(&mut self, index: ConstantPoolIndex, constant: Constant) {
+        let position = index as usize;
+        let old = std::mem::replace(&mut self.constants[position], Constant::Null);
+        if self.find(&old) == Some(index) {
+            // Any other occurrence of the old value comes only later.
+            let next = self.constants[position + 1..]
+                .iter()
+                .position(|other| same_value(other, &old));
+            self.set_first(&old, next.map(|next| from_usize(position + 1 + next)));
+        }
+        if self.find(&constant).is_none_or(|first| index < first) {
+            self.set_first(&constant, Some(index));
+        }
+        self.constants[position] = constant;
+    }
+
+    /**
+     * Points the lookup of the constant's value at the index, or forgets it.
+     */
+    fn set_first(&mut self, constant: &Constant, index: Option<ConstantPoolIndex>) {
+        match (constant, index) {
+            (Constant::String(str), Some(index)) => {
+                self.strings.insert(str.clone(), index);
+            }
+            (Constant::String(str), None) => {
+                self.strings.remove(str);
+            }
+            (_, Some(index)) => {
+                if let Some(key) = Key::of(constant) {
+                    self.indices.insert(key, index);
+                }
+            }
+            (_, None) => {
+                if let Some(key) = Key::of(constant) {
+                    self.indices.remove(&key);
+                }
             }
         }
     }
 
+    pub fn get(&self, index: ConstantPoolIndex) -> &Constant {
+        &self.constants[index as usize]
+    }
+
+    /**
+     * Code of the function at the index. Only functions can be changed in place,
+     * as they aren't looked up by value.
+     */
+    pub fn code_mut(&mut self, index: ConstantPoolIndex) -> Option<&mut Code> {
+        match self.constants.get_mut(index as usize) {
+            Some(Constant::Function { code, .. }) => Some(code),
+            _ => None,
+        }
+    }
+
+    /**
+     * Finds the first occurrence of the constant. Functions are never found.
+     */
     pub fn find(&self, constant: &Constant) -> Option<ConstantPoolIndex> {
-        self.0
-            .iter()
-            .position(|x| constant == x)
-            .map(|x| from_usize(x))
+        match constant {
+            Constant::String(str) => self.find_by_str(str),
+            _ => Key::of(constant).and_then(|key| self.indices.get(&key).copied()),
+        }
     }
 
-    pub fn find_by_str(&self, str: &String) -> Option<ConstantPoolIndex> {
-        self.0
-            .iter()
-            .position(|x| match x {
-                Constant::String(val) => val == str,
-                _ => false,
+    pub fn find_by_str(&self, str: &str) -> Option<ConstantPoolIndex> {
+        self.strings.get(str).copied()
+    }
+
+    /**
+     * Iterates over code of all functions and methods in the pool.
+     */
+    pub fn codes_mut(&mut self) -> impl Iterator<Item = &mut Code> {
+        self.constants
+            .iter_mut()
+            .filter_map(|constant| match constant {
+                Constant::Function { code, .. } => Some(code),
+                _ => None,
             })
-            .map(|x| from_usize(x))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Constant> {
+        self.constants.iter()
     }
 
     pub fn len(&self) -> u16 {
-        self.0.len().try_into().unwrap()
+        self.constants.len().try_into().unwrap()
     }
 }
 
 impl Serializable for ConstantPool {
     fn serializable_byte<W: std::io::Write>(&self, output: &mut W) -> std::io::Result<()> {
-        output.write(&self.len().to_le_bytes())?;
+        output.write_all(&self.len().to_le_bytes())?;
 
-        for constant in self.0.iter() {
+        for constant in self.constants.iter() {
             constant.serializable_byte(output)?;
         }
 
         Ok(())
     }
 }
+
+impl Deserializable for ConstantPool {
+    fn deserialize<R: Read>(input: &mut R) -> std::io::Result<Self> {
+        let mut pool = ConstantPool::new();
+        for _ in 0..read_u16(input)? {
+            // Constants are taken as they are, even the duplicate ones.
+            pool.add(Constant::deserialize(input)?);
+        }
+        Ok(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn float_dedup_test() {
+        let mut pool = ConstantPool::new();
+        let nan = pool.push(Constant::from(f64::NAN));
+        let zero = pool.push(Constant::from(0.0));
+        let neg_zero = pool.push(Constant::from(-0.0));
+
+        assert_eq!(pool.push(Constant::from(f64::NAN)), nan);
+        assert_eq!(pool.push(Constant::from(0.0)), zero);
+        assert_eq!(pool.push(Constant::from(-0.0)), neg_zero);
+        assert_ne!(zero, neg_zero);
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn find_test() {
+        let mut pool = ConstantPool::new();
+        let name = pool.push(Constant::from(String::from("x")));
+        let constants = [
+            Constant::from(1),
+            Constant::from(1_i64 << 40),
+            Constant::from(1.5),
+            Constant::from(true),
+            Constant::Null,
+            Constant::from(String::from("y")),
+            Constant::Slot { name },
+            Constant::Object {
+                members: vec![name],
+            },
+        ];
+        let indices: Vec<ConstantPoolIndex> = constants
+            .iter()
+            .map(|constant| pool.add(constant.clone()))
+            .collect();
+        // Repeated values are found at their first occurrence.
+        for (constant, index) in constants.iter().zip(indices.iter()) {
+            pool.add(constant.clone());
+            assert_eq!(pool.find(constant), Some(*index));
+        }
+        assert_eq!(pool.find_by_str("x"), Some(name));
+        assert_eq!(pool.find_by_str("y"), Some(indices[5]));
+
+        assert_eq!(pool.find(&Constant::from(1_i64)), None);
+        assert_eq!(pool.find(&Constant::from(1.0)), None);
+        assert_eq!(pool.find(&Constant::from(false)), None);
+        assert_eq!(pool.find_by_str("z"), None);
+        assert_eq!(pool.find(&Constant::Object { members: vec![] }), None);
+    }
+
+    #[test]
+    fn lookup_test() {
+        let mut pool = ConstantPool::new();
+        let function = || Constant::Function {
+            name: 0,
+            parameters: 0,
+            locals: 0,
+            code: Code::new(),
+        };
+        let first = pool.push(function());
+        assert_ne!(pool.push(function()), first);
+        assert_eq!(pool.find(&function()), None);
+
+        let null = pool.add(Constant::Null);
+        let other = pool.add(Constant::Null);
+        pool.replace(null, Constant::from(String::from("x")));
+        assert_eq!(pool.push(Constant::Null), other);
+        assert_eq!(pool.find_by_str("x"), Some(null));
+        pool.replace(other, Constant::Boolean(true));
+        assert_eq!(pool.find(&Constant::Null), None);
+    }
+}
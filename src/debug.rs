@@ -0,0 +1,539 @@
+use crate::ast::{ensure_stack, AST};
+use crate::bytecode::{Bytecode, LocalFrameIndex};
+use crate::constants::{Constant, ConstantPool, ConstantPoolIndex};
+use crate::json::{Json, JsonParser, Spanned};
+use crate::program::Program;
+use crate::serializer::*;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+
+/**
+ * Part of the source, lines and columns are counted from one.
+ */
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub struct Span {
+    pub line: u32,
+    pub column: u32,
+    pub end_line: u32,
+    pub end_column: u32,
+}
+
+/**
+ * Instructions from the given one up to the next entry were compiled from
+ * the span.
+ */
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct LineEntry {
+    pub instruction: u32,
+    pub span: Span,
+}
+
+/**
+ * Name of the local slot, while the instructions from start (inclusive)
+ * to end (exclusive) are executed.
+ */
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct LocalName {
+    pub index: LocalFrameIndex,
+    pub name: String,
+    pub start: u32,
+    pub end: u32,
+}
+
+/**
+ * Name of the label which stood in front of the instruction before the labels
+ * were resolved to instruction offsets.
+ */
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct LabelName {
+    pub instruction: u32,
+    pub name: String,
+}
+
+/**
+ * Spans of the AST nodes in the JSON source they were read from. Nodes are
+ * identified by their address, so the tree must not move after this is built.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct Spans {
+    spans: HashMap<*const AST, Span>,
+    // Innermost node whose compilation failed
+    failed: Cell<Option<Span>>,
+}
+
+/**
+ * Fields of the AST variants which hold subtrees, in the order of `AST::children`.
+ */
+fn child_fields(variant: &str) -> &'static [&'static str] {
+    match variant {
+        "Variable" | "AssignVariable" | "Throw" | "Break" => &["value"],
+        "Array" => &["size", "value"],
+        "Object" => &["extends", "members"],
+        "AccessField" => &["object"],
+        "AccessArray" => &["array", "index"],
+        "AssignField" => &["object", "value"],
+        "AssignArray" => &["array", "index", "value"],
+        "Function" => &["body"],
+        "CallFunction" | "Print" => &["arguments"],
+        "CallMethod" => &["object", "arguments"],
+        "And" | "Or" => &["left", "right"],
+        "Not" => &["operand"],
+        "Loop" => &["condition", "body"],
+        "For" => &["from", "to", "body"],
+        "ForEach" => &["array", "body"],
+        "Conditional" => &["condition", "consequent", "alternative"],
+        "Try" => &["body", "handler", "finally"],
+        _ => &[],
+    }
+}
+
+impl Spans {
+    /**
+     * Matches the tree with the JSON text it was deserialized from.
+     */
+    pub fn new(text: &str, ast: &AST) -> Self {
+        let mut spans = Spans::default();
+        if let Ok(tree) = JsonParser::parse(text) {
+            let line_starts: Vec<usize> = std::iter::once(0)
+                .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+                .collect();
+            spans.collect(ast, &tree, &line_starts);
+        }
+        spans
+    }
+
+    fn collect(&mut self, ast: &AST, tree: &Spanned, line_starts: &[usize]) {
+        let position = |offset: usize| {
+            let line = line_starts.partition_point(|start| *start <= offset);
+            (line as u32, (offset - line_starts[line - 1] + 1) as u32)
+        };
+        let (line, column) = position(tree.start);
+        let (end_line, end_column) = position(tree.end);
+        self.spans.insert(
+            ast,
+            Span {
+                line,
+                column,
+                end_line,
+                end_column,
+            },
+        );
+
+        let (variant, content) = match tree.variant() {
+            Some(variant) => variant,
+            None => return,
+        };
+        let subtrees: Vec<&Spanned> = match variant {
+            "Top" | "Block" => content.items().iter().collect(),
+            _ => child_fields(variant)
+                .iter()
+                .filter_map(|field| content.get(field))
+                .flat_map(|value| match &value.value {
+                    Json::Array(items) => items.iter().collect(),
+                    Json::Null => vec![],
+                    _ => vec![value],
+                })
+                .collect(),
+        };
+        let children = ast.children();
+        if children.len() == subtrees.len() {
+            for (child, subtree) in children.into_iter().zip(subtrees) {
+                ensure_stack(|| self.collect(child, subtree, line_starts));
+            }
+        }
+    }
+
+    pub fn get(&self, ast: &AST) -> Option<Span> {
+        self.spans.get(&(ast as *const AST)).copied()
+    }
+
+    /**
+     * Remembers where the compilation failed, unless a node inside the span
+     * already did.
+     */
+    pub fn fail(&self, span: Span) {
+        if self.failed.get().is_none() {
+            self.failed.set(Some(span));
+        }
+    }
+
+    pub fn failed(&self) -> Option<Span> {
+        self.failed.get()
+    }
+}
+
+fn write_string<W: Write>(output: &mut W, str: &str) -> std::io::Result<()> {
+    output.write_all(&(str.len() as u32).to_le_bytes())?;
+    output.write_all(str.as_bytes())?;
+    Ok(())
+}
+
+fn read_string<R: Read>(input: &mut R) -> std::io::Result<String> {
+    let mut bytes = vec![0; read_u32(input)? as usize];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("Local name is not valid UTF-8."))
+}
+
+/**
+ * Writes the line tables, local names and names of resolved labels of the
+ * functions which have them.
+ */
+pub fn write_section<W: Write>(pool: &ConstantPool, output: &mut W) -> std::io::Result<()> {
+    let functions: Vec<(usize, &crate::bytecode::Code)> = pool
+        .iter()
+        .enumerate()
+        .filter_map(|(i, constant)| match constant {
+            Constant::Function { code, .. }
+                if !code.lines.is_empty() || !code.locals.is_empty() || !code.labels.is_empty() =>
+            {
+                Some((i, code))
+            }
+            _ => None,
+        })
+        .collect();
+
+    output.write_all(&(functions.len() as u16).to_le_bytes())?;
+    for (index, code) in functions {
+        output.write_all(&(index as u16).to_le_bytes())?;
+        output.write_all(&(code.lines.len() as u32).to_le_bytes())?;
+        for entry in code.lines.iter() {
+            output.write_all(&entry.instruction.to_le_bytes())?;
+            output.write_all(&entry.span.line.to_le_bytes())?;
+            output.write_all(&entry.span.column.to_le_bytes())?;
+            output.write_all(&entry.span.end_line.to_le_bytes())?;
+            output.write_all(&entry.span.end_column.to_le_bytes())?;
+        }
+        output.write_all(&(code.locals.len() as u16).to_le_bytes())?;
+        for local in code.locals.iter() {
+            output.write_all(&local.index.to_le_bytes())?;
+            output.write_all(&local.start.to_le_bytes())?;
+            output.write_all(&local.end.to_le_bytes())?;
+            write_string(output, &local.name)?;
+        }
+        output.write_all(&(code.labels.len() as u32).to_le_bytes())?;
+        for label in code.labels.iter() {
+            output.write_all(&label.instruction.to_le_bytes())?;
+            write_string(output, &label.name)?;
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Reads back what `write_section` wrote, attaching it to the functions.
+ */
+pub fn read_section<R: Read>(pool: &mut ConstantPool, input: &mut R) -> std::io::Result<()> {
+    for _ in 0..read_u16(input)? {
+        let index = read_u16(input)?;
+        let mut lines = Vec::new();
+        for _ in 0..read_u32(input)? {
+            lines.push(LineEntry {
+                instruction: read_u32(input)?,
+                span: Span {
+                    line: read_u32(input)?,
+                    column: read_u32(input)?,
+                    end_line: read_u32(input)?,
+                    end_column: read_u32(input)?,
+                },
+            });
+        }
+        let mut locals = Vec::new();
+        for _ in 0..read_u16(input)? {
+            locals.push(LocalName {
+                index: read_u16(input)?,
+                start: read_u32(input)?,
+                end: read_u32(input)?,
+                name: read_string(input)?,
+            });
+        }
+        let mut labels = Vec::new();
+        for _ in 0..read_u32(input)? {
+            labels.push(LabelName {
+                instruction: read_u32(input)?,
+                name: read_string(input)?,
+            });
+        }
+        match pool.code_mut(index) {
+            Some(code) => {
+                code.lines = lines;
+                code.locals = locals;
+                code.labels = labels;
+            }
+            None => return Err(invalid_data("Debug information refers to a non-function.")),
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Describes the constant for comments in the listing.
+ */
+pub(crate) fn describe(pool: &ConstantPool, index: ConstantPoolIndex) -> String {
+    match pool.get(index) {
+        Constant::Integer(val) => val.to_string(),
+        Constant::Long(val) => val.to_string(),
+        Constant::Float(val) => format!("{:?}", val),
+        Constant::Boolean(val) => val.to_string(),
+        Constant::Null => String::from("null"),
+        Constant::String(str) => format!("{:?}", str),
+        Constant::Slot { name } | Constant::Function { name, .. } => describe(pool, *name),
+        Constant::Object { .. } => String::from("object"),
+    }
+}
+
+pub(crate) fn instruction(inst: &Bytecode) -> String {
+    match inst {
+        Bytecode::Literal { index } => format!("literal #{}", index),
+        Bytecode::GetLocal { index } => format!("get_local {}", index),
+        Bytecode::SetLocal { index } => format!("set_local {}", index),
+        Bytecode::GetGlobal { name } => format!("get_global #{}", name),
+        Bytecode::SetGlobal { name } => format!("set_global #{}", name),
+        Bytecode::Object { class } => format!("object #{}", class),
+        Bytecode::Array => String::from("array"),
+        Bytecode::GetField { name } => format!("get_field #{}", name),
+        Bytecode::SetField { name } => format!("set_field #{}", name),
+        Bytecode::CallMethod { name, arguments } => {
+            format!("call_method #{} {}", name, arguments)
+        }
+        Bytecode::CallFunction { name, arguments } => {
+            format!("call_function #{} {}", name, arguments)
+        }
+        Bytecode::Label { name } => format!("label #{}", name),
+        Bytecode::Print { format, arguments } => format!("print #{} {}", format, arguments),
+        Bytecode::Jump { label } => format!("jump #{}", label),
+        Bytecode::Branch { label } => format!("branch #{}", label),
+        Bytecode::BranchFalse { label } => format!("branch_false #{}", label),
+        Bytecode::Return => String::from("return"),
+        Bytecode::Drop => String::from("drop"),
+        Bytecode::Throw => String::from("throw"),
+        Bytecode::JumpTo { target } => format!("jump_to {}", target),
+        Bytecode::BranchTo { target } => format!("branch_to {}", target),
+        Bytecode::BranchFalseTo { target } => format!("branch_false_to {}", target),
+        Bytecode::Add => String::from("add"),
+        Bytecode::Sub => String::from("sub"),
+        Bytecode::Mul => String::from("mul"),
+        Bytecode::Div => String::from("div"),
+        Bytecode::Mod => String::from("mod"),
+        Bytecode::Lt => String::from("lt"),
+        Bytecode::Le => String::from("le"),
+        Bytecode::Gt => String::from("gt"),
+        Bytecode::Ge => String::from("ge"),
+        Bytecode::Eq => String::from("eq"),
+        Bytecode::Neq => String::from("neq"),
+        Bytecode::IncLocal { index, amount } => format!("inc_local {} #{}", index, amount),
+        Bytecode::CompareLocalsBranch { left, right } => {
+            format!("compare_locals_branch {} {}", left, right)
+        }
+    }
+}
+
+/**
+ * Renders the program as a listing, one constant per line, with instructions
+ * of functions below them. Comments show the referred constants, and names
+ * and source lines from the debug information.
+ */
+pub fn disassemble(program: &Program) -> String {
+    let pool = &program.pool;
+    let mut output = String::new();
+
+    for (i, constant) in pool.iter().enumerate() {
+        let _ = match constant {
+            Constant::Integer(val) => writeln!(output, "constant {} = integer {}", i, val),
+            Constant::Long(val) => writeln!(output, "constant {} = long {}", i, val),
+            Constant::Float(val) => writeln!(output, "constant {} = float {:?}", i, val),
+            Constant::Boolean(val) => writeln!(output, "constant {} = boolean {}", i, val),
+            Constant::Null => writeln!(output, "constant {} = null", i),
+            Constant::String(str) => writeln!(output, "constant {} = string {:?}", i, str),
+            Constant::Slot { name } => writeln!(
+                output,
+                "constant {} = slot #{} ; {}",
+                i,
+                name,
+                describe(pool, *name)
+            ),
+            Constant::Object { members } => {
+                let members: Vec<String> = members.iter().map(|m| format!("#{}", m)).collect();
+                writeln!(output, "constant {} = object {}", i, members.join(" "))
+            }
+            Constant::Function {
+                name,
+                parameters,
+                locals,
+                code,
+            } => {
+                let _ = writeln!(
+                    output,
+                    "constant {} = function #{} params {} locals {} ; {}",
+                    i,
+                    name,
+                    parameters,
+                    locals,
+                    describe(pool, *name)
+                );
+                let mut line = None;
+                for (pc, inst) in code.insert_point.iter().enumerate() {
+                    let mut comments = Vec::new();
+                    match inst {
+                        Bytecode::GetLocal { index } | Bytecode::SetLocal { index } => {
+                            if let Some(name) = code.local_name(*index, pc as u32) {
+                                comments.push(String::from(name));
+                            }
+                        }
+                        Bytecode::JumpTo { target }
+                        | Bytecode::BranchTo { target }
+                        | Bytecode::BranchFalseTo { target } => {
+                            let labels = code
+                                .labels
+                                .iter()
+                                .filter(|label| label.instruction == *target);
+                            comments.extend(labels.map(|label| label.name.clone()));
+                        }
+                        Bytecode::Array | Bytecode::Return | Bytecode::Drop | Bytecode::Throw => (),
+                        _ => {
+                            inst.map_indices(|index, _| {
+                                comments.push(describe(pool, index));
+                                index
+                            });
+                        }
+                    }
+                    // Source position is shown when it changes
+                    let span = code.span_at(pc as u32);
+                    if span != line {
+                        if let Some(span) = span {
+                            comments.push(format!("line {}:{}", span.line, span.column));
+                        }
+                        line = span;
+                    }
+                    let indent = match inst {
+                        Bytecode::Label { .. } => "  ",
+                        _ => "    ",
+                    };
+                    let _ = match comments.is_empty() {
+                        true => writeln!(output, "{}{}", indent, instruction(inst)),
+                        false => writeln!(
+                            output,
+                            "{}{} ; {}",
+                            indent,
+                            instruction(inst),
+                            comments.join(", ")
+                        ),
+                    };
+                }
+                for handler in code.handlers.iter() {
+                    let _ = writeln!(
+                        output,
+                        "    handler #{} #{} #{} stack {}",
+                        handler.start, handler.end, handler.handler, handler.stack
+                    );
+                }
+                for handler in code.resolved_handlers.iter() {
+                    let _ = writeln!(
+                        output,
+                        "    handler_at {} {} {} stack {}",
+                        handler.start, handler.end, handler.handler, handler.stack
+                    );
+                }
+                writeln!(output, "end")
+            }
+        };
+    }
+
+    let globals: Vec<String> = program
+        .globals
+        .globals
+        .iter()
+        .map(|g| format!("#{}", g))
+        .collect();
+    let _ = writeln!(output, "globals {}", globals.join(" "));
+    let _ = writeln!(output, "entry #{}", program.entry);
+    output
+}
+
+/**
+ * Counts the sequences of instructions of the given length in all functions, as
+ * candidates for superinstructions. Instructions are told apart only by their
+ * mnemonics, and method calls also by the name and number of arguments. Sequences
+ * with labels are left out, those can't be fused. The most frequent come first.
+ */
+pub fn ngrams(program: &Program, length: usize) -> Vec<(String, usize)> {
+    let pool = &program.pool;
+    let shape = |inst: &Bytecode| match inst {
+        Bytecode::CallMethod { name, arguments } => {
+            format!("call_method {} {}", describe(pool, *name), arguments)
+        }
+        _ => instruction(inst)
+            .split(' ')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    };
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for code in pool.iter().filter_map(|constant| match constant {
+        Constant::Function { code, .. } => Some(code),
+        _ => None,
+    }) {
+        for window in code.insert_point.windows(length.max(1)) {
+            if window
+                .iter()
+                .any(|inst| matches!(inst, Bytecode::Label { .. }))
+            {
+                continue;
+            }
+            let sequence: Vec<String> = window.iter().map(shape).collect();
+            *counts.entry(sequence.join("; ")).or_default() += 1;
+        }
+    }
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile, CompilerOptions};
+
+    #[test]
+    fn debug_info_test() {
+        let text = r#"{"Top": [
+    {"Function": {"name": "f", "parameters": ["x"], "body":
+        {"Block": [
+            {"Variable": {"name": "y", "value": {"Integer": 1}}},
+            {"CallMethod": {"object": {"AccessVariable": {"name": "x"}}, "name": "/",
+                "arguments": [{"AccessVariable": {"name": "y"}}]}}
+        ]}}}
+]}"#;
+        let ast: AST = serde_json::from_str(text).unwrap();
+        let options = CompilerOptions {
+            debug: Some(Spans::new(text, &ast)),
+            ..CompilerOptions::default()
+        };
+        let program = compile(&ast, &options).unwrap();
+
+        let mut bytes = Vec::new();
+        program.serializable_byte(&mut bytes).unwrap();
+        let loaded = Program::deserialize(&mut bytes.as_slice()).unwrap();
+
+        let code = loaded
+            .pool
+            .iter()
+            .find_map(|constant| match constant {
+                Constant::Function { code, .. } if code.locals.len() == 2 => Some(code),
+                _ => None,
+            })
+            .unwrap();
+        let division = code
+            .insert_point
+            .iter()
+            .position(|inst| matches!(inst, Bytecode::CallMethod { .. }))
+            .unwrap() as u32;
+        assert_eq!(code.span_at(division).map(|span| span.line), Some(5));
+        assert_eq!(code.local_name(0, 0), Some("x"));
+        assert_eq!(code.local_name(1, division), Some("y"));
+
+        assert!(disassemble(&loaded).contains("get_local 1 ; y, line 6:31"));
+    }
+}
@@ -0,0 +1,1106 @@
+use crate::ast::{ensure_stack, Identifier, IntoBoxed, AST};
+use crate::bytecode::{Bytecode, Code, ExceptionHandler, LocalFrameIndex};
+use crate::constants::{Constant, ConstantPool, ConstantPoolIndex};
+use crate::program::Program;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+/**
+ * Labels of the loop which is decompiled, `break` jumps to the exit and
+ * `continue` to one of the next labels.
+ */
+struct Loop {
+    exit: Option<ConstantPoolIndex>,
+    next: Vec<ConstantPoolIndex>,
+}
+
+/**
+ * Statements and the symbolic stack of a straight part of the code.
+ */
+#[derive(Default)]
+struct Sequence {
+    statements: Vec<AST>,
+    stack: Vec<AST>,
+    // Statements dropped while values of the enclosing expression were on the stack,
+    // they are evaluated just before the next value.
+    pending: Vec<AST>,
+}
+
+impl Sequence {
+    fn push(&mut self, value: AST) {
+        if self.pending.is_empty() {
+            self.stack.push(value);
+            return;
+        }
+        let mut block: Vec<Box<AST>> = self.pending.drain(..).map(Box::new).collect();
+        block.push(value.into_boxed());
+        self.stack.push(AST::Block(block));
+    }
+
+    fn pop(&mut self) -> Result<AST, String> {
+        self.stack
+            .pop()
+            .ok_or_else(|| String::from("Instruction pops from an empty stack."))
+    }
+
+    fn pop_many(&mut self, count: usize) -> Result<Vec<AST>, String> {
+        if count > self.stack.len() {
+            return Err(String::from("Instruction pops from an empty stack."));
+        }
+        Ok(self.stack.split_off(self.stack.len() - count))
+    }
+
+    fn drop(&mut self) -> Result<(), String> {
+        let statement = self.pop()?;
+        match self.stack.is_empty() {
+            true => self.statements.push(statement),
+            false => self.pending.push(statement),
+        }
+        Ok(())
+    }
+
+    /**
+     * Adds `throw`, `break` or `continue`, which leave no value on the stack.
+     */
+    fn terminate(&mut self, ast: AST) {
+        match self.stack.is_empty() && self.pending.is_empty() {
+            true => self.statements.push(ast),
+            false => self.push(ast),
+        }
+    }
+
+    fn into_statements(mut self) -> Vec<AST> {
+        self.statements.append(&mut self.stack);
+        self.statements.append(&mut self.pending);
+        self.statements
+    }
+
+    /**
+     * Splits the sequence into statements and the value it leaves on the stack.
+     * The sequence which ends with a jump away has the jump as its value.
+     */
+    fn into_value(mut self) -> (Vec<AST>, AST) {
+        let value = self.stack.pop();
+        let mut statements = self.into_statements();
+        let value = match value {
+            Some(value) => value,
+            None => match statements.last() {
+                Some(AST::Throw { .. } | AST::Break { .. } | AST::Continue) => {
+                    statements.pop().unwrap()
+                }
+                _ => AST::Null,
+            },
+        };
+        (statements, value)
+    }
+}
+
+struct Decompiler<'a> {
+    pool: &'a ConstantPool,
+    code: &'a Code,
+    labels: HashMap<ConstantPoolIndex, usize>,
+    names: Vec<String>,
+    declared: HashSet<LocalFrameIndex>,
+    // Global variables which are not yet declared, used only in the main function.
+    globals: HashSet<String>,
+    loops: Vec<Loop>,
+    depth: usize,
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(
+        pool: &'a ConstantPool,
+        code: &'a Code,
+        parameters: u8,
+        locals: u16,
+        is_method: bool,
+        globals: HashSet<String>,
+    ) -> Self {
+        let labels = code
+            .insert_point
+            .iter()
+            .enumerate()
+            .filter_map(|(position, inst)| match inst {
+                Bytecode::Label { name } => Some((*name, position)),
+                _ => None,
+            })
+            .collect();
+
+        // Names from the debug information can repeat in different scopes,
+        // but the decompiled scopes don't have to match the original ones.
+        let mut names: Vec<String> = Vec::new();
+        for index in 0..locals.max(parameters.into()) {
+            let name = match code.locals.iter().find(|local| local.index == index) {
+                _ if is_method && index == 0 => String::from("this"),
+                Some(local) => local.name.clone(),
+                None => format!("local_{}", index),
+            };
+            match names.contains(&name) {
+                true => names.push(format!("{}_{}", name, index)),
+                false => names.push(name),
+            }
+        }
+
+        Decompiler {
+            pool,
+            code,
+            labels,
+            names,
+            declared: (0..parameters.into()).collect(),
+            globals,
+            loops: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    fn string(&self, index: ConstantPoolIndex) -> Result<String, String> {
+        match self.pool.get(index) {
+            Constant::String(string) => Ok(string.clone()),
+            _ => Err(format!("Constant #{} is not a string.", index)),
+        }
+    }
+
+    fn local(&self, index: LocalFrameIndex) -> Result<Identifier, String> {
+        match self.names.get(usize::from(index)) {
+            Some(name) => Ok(Identifier(name.clone())),
+            None => Err(format!("Local #{} is out of the frame.", index)),
+        }
+    }
+
+    fn literal(&self, index: ConstantPoolIndex) -> Result<AST, String> {
+        Ok(match self.pool.get(index) {
+            Constant::Integer(value) => AST::Integer((*value).into()),
+            Constant::Long(value) => AST::Integer(*value),
+            Constant::Float(value) => AST::Float(*value),
+            Constant::Boolean(value) => AST::Boolean(*value),
+            Constant::Null => AST::Null,
+            Constant::String(value) => AST::String(value.clone()),
+            _ => return Err(format!("Constant #{} is not a literal.", index)),
+        })
+    }
+
+    fn position(&self, label: ConstantPoolIndex) -> Result<usize, String> {
+        self.labels
+            .get(&label)
+            .copied()
+            .ok_or_else(|| format!("Label #{} is not defined.", label))
+    }
+
+    /**
+     * Returns the position of the label which the jump in front of the given
+     * position goes to, the end of a conditional or a try block.
+     */
+    fn merge(&self, position: usize) -> Result<usize, String> {
+        match self.code.insert_point.get(position.wrapping_sub(1)) {
+            Some(Bytecode::Jump { label }) if self.position(*label)? > position => {
+                self.position(*label)
+            }
+            _ => Err(format!(
+                "Unstructured control flow at instruction {}.",
+                position
+            )),
+        }
+    }
+
+    fn sequence(&mut self, start: usize, end: usize) -> Result<Sequence, String> {
+        self.depth += 1;
+        let mut sequence = Sequence::default();
+        let mut position = start;
+        while position < end {
+            position = self.instruction(&mut sequence, position)?;
+        }
+        self.depth -= 1;
+        match position {
+            position if position > end => {
+                Err(format!("Unstructured control flow at instruction {}.", end))
+            }
+            _ => Ok(sequence),
+        }
+    }
+
+    /**
+     * Decompiles the code which leaves a value on the stack.
+     */
+    fn expression(&mut self, start: usize, end: usize) -> Result<AST, String> {
+        let (mut statements, value) = self.sequence(start, end)?.into_value();
+        if statements.is_empty() && !matches!(value, AST::Variable { .. }) {
+            return Ok(value);
+        }
+        statements.push(value);
+        Ok(AST::Block(statements.into_iter().map(Box::new).collect()))
+    }
+
+    /**
+     * Decompiles the code which leaves the stack as it was.
+     */
+    fn statements(&mut self, start: usize, end: usize) -> Result<AST, String> {
+        let mut statements = self.sequence(start, end)?.into_statements();
+        if statements.len() == 1 && !matches!(statements[0], AST::Variable { .. }) {
+            return Ok(statements.pop().unwrap());
+        }
+        Ok(AST::Block(statements.into_iter().map(Box::new).collect()))
+    }
+
+    /**
+     * Decompiles the instruction, or the whole construct it starts, and returns
+     * the position of the following instruction. Constructs return the position
+     * of their end label, which may be shared with an enclosing construct.
+     */
+    fn instruction(&mut self, sequence: &mut Sequence, position: usize) -> Result<usize, String> {
+        match self.code.insert_point[position] {
+            Bytecode::Literal { index } => sequence.push(self.literal(index)?),
+            // The rest of the sequences fused into superinstructions is left behind them.
+            Bytecode::GetLocal { index }
+            | Bytecode::IncLocal { index, .. }
+            | Bytecode::CompareLocalsBranch { left: index, .. } => {
+                sequence.push(AST::AccessVariable {
+                    name: self.local(index)?,
+                })
+            }
+            Bytecode::SetLocal { index } => {
+                let name = self.local(index)?;
+                let value = sequence.pop()?.into_boxed();
+                sequence.push(match self.declared.insert(index) {
+                    true => AST::Variable { name, value },
+                    false => AST::AssignVariable { name, value },
+                });
+            }
+            Bytecode::GetGlobal { name } => sequence.push(AST::AccessVariable {
+                name: Identifier(self.string(name)?),
+            }),
+            Bytecode::SetGlobal { name } => {
+                let name = self.string(name)?;
+                let value = sequence.pop()?.into_boxed();
+                // Globals are declared only by the top level statements of the main function.
+                let declares = self.depth == 1 && self.globals.remove(&name);
+                let name = Identifier(name);
+                sequence.push(match declares {
+                    true => AST::Variable { name, value },
+                    false => AST::AssignVariable { name, value },
+                });
+            }
+            Bytecode::Object { class } => {
+                let object = self.object(sequence, class)?;
+                sequence.push(object);
+            }
+            Bytecode::Array => {
+                let value = sequence.pop()?.into_boxed();
+                let size = sequence.pop()?.into_boxed();
+                sequence.push(AST::Array { size, value });
+            }
+            Bytecode::GetField { name } => {
+                let object = sequence.pop()?.into_boxed();
+                sequence.push(AST::AccessField {
+                    object,
+                    field: Identifier(self.string(name)?),
+                });
+            }
+            Bytecode::SetField { name } => {
+                let value = sequence.pop()?.into_boxed();
+                let object = sequence.pop()?.into_boxed();
+                sequence.push(AST::AssignField {
+                    object,
+                    field: Identifier(self.string(name)?),
+                    value,
+                });
+            }
+            Bytecode::CallMethod { name, arguments } => {
+                let name = self.string(name)?;
+                let mut arguments = sequence.pop_many(arguments.into())?;
+                if arguments.is_empty() {
+                    return Err(format!(
+                        "Method call without receiver at instruction {}.",
+                        position
+                    ));
+                }
+                let object = arguments.remove(0).into_boxed();
+                // Array accesses are compiled as calls of 'get' and 'set'.
+                sequence.push(match (name.as_str(), arguments.len()) {
+                    ("get", 1) => AST::AccessArray {
+                        array: object,
+                        index: arguments.pop().unwrap().into_boxed(),
+                    },
+                    ("set", 2) => {
+                        let value = arguments.pop().unwrap().into_boxed();
+                        let index = arguments.pop().unwrap().into_boxed();
+                        AST::AssignArray {
+                            array: object,
+                            index,
+                            value,
+                        }
+                    }
+                    _ => AST::CallMethod {
+                        object,
+                        name: Identifier(name),
+                        arguments: arguments.into_iter().map(Box::new).collect(),
+                    },
+                });
+            }
+            Bytecode::CallFunction { name, arguments } => {
+                let arguments = sequence.pop_many(arguments.into())?;
+                sequence.push(AST::CallFunction {
+                    name: Identifier(self.string(name)?),
+                    arguments: arguments.into_iter().map(Box::new).collect(),
+                });
+            }
+            Bytecode::Print { format, arguments } => {
+                let arguments = sequence.pop_many(arguments.into())?;
+                sequence.push(AST::Print {
+                    format: self.string(format)?,
+                    arguments: arguments.into_iter().map(Box::new).collect(),
+                });
+            }
+            Bytecode::Label { name } => {
+                let handler = self
+                    .code
+                    .handlers
+                    .iter()
+                    .find(|handler| handler.start == name);
+                if let Some(handler) = handler.copied() {
+                    return self.try_block(sequence, position, handler);
+                }
+            }
+            Bytecode::Jump { label } => return self.jump(sequence, position, label),
+            Bytecode::Branch { label } => return self.branch(sequence, position, label),
+            Bytecode::BranchFalse { label } => {
+                let condition = sequence.pop()?.into_boxed();
+                let else_start = self.position(label)?;
+                let merge = self.merge(else_start)?;
+                let consequent = self.expression(position + 1, else_start - 1)?;
+                let alternative = self.expression(else_start + 1, merge)?;
+                sequence.push(match (consequent, alternative) {
+                    (AST::Boolean(false), AST::Boolean(true)) => AST::Not { operand: condition },
+                    (right, AST::Boolean(false)) => AST::And {
+                        left: condition,
+                        right: right.into_boxed(),
+                    },
+                    (consequent, alternative) => AST::Conditional {
+                        condition,
+                        consequent: consequent.into_boxed(),
+                        alternative: alternative.into_boxed(),
+                    },
+                });
+                return Ok(merge);
+            }
+            Bytecode::Return => {
+                return Err(format!("Unexpected return at instruction {}.", position))
+            }
+            Bytecode::JumpTo { .. }
+            | Bytecode::BranchTo { .. }
+            | Bytecode::BranchFalseTo { .. } => {
+                unreachable!("Labels are restored before decompilation.")
+            }
+            Bytecode::Add
+            | Bytecode::Sub
+            | Bytecode::Mul
+            | Bytecode::Div
+            | Bytecode::Mod
+            | Bytecode::Lt
+            | Bytecode::Le
+            | Bytecode::Gt
+            | Bytecode::Ge
+            | Bytecode::Eq
+            | Bytecode::Neq => {
+                let argument = sequence.pop()?.into_boxed();
+                let object = sequence.pop()?.into_boxed();
+                let name = self.code.insert_point[position].operator_name().unwrap();
+                sequence.push(AST::CallMethod {
+                    object,
+                    name: Identifier(String::from(name)),
+                    arguments: vec![argument],
+                });
+            }
+            Bytecode::Drop => sequence.drop()?,
+            Bytecode::Throw => {
+                let value = sequence.pop()?.into_boxed();
+                sequence.terminate(AST::Throw { value });
+            }
+        }
+        Ok(position + 1)
+    }
+
+    fn object(&mut self, sequence: &mut Sequence, class: ConstantPoolIndex) -> Result<AST, String> {
+        let members = match self.pool.get(class) {
+            Constant::Object { members } => members,
+            _ => return Err(format!("Constant #{} is not a class.", class)),
+        };
+        let slots = members
+            .iter()
+            .filter(|member| matches!(self.pool.get(**member), Constant::Slot { .. }))
+            .count();
+        let mut values = sequence.pop_many(slots)?.into_iter();
+        let extends = sequence.pop()?.into_boxed();
+
+        let mut asts = Vec::new();
+        for member in members.iter() {
+            asts.push(
+                match self.pool.get(*member) {
+                    Constant::Slot { name } => AST::Variable {
+                        name: Identifier(self.string(*name)?),
+                        value: values.next().unwrap().into_boxed(),
+                    },
+                    Constant::Function { .. } => function(self.pool, *member, true)?,
+                    _ => return Err(format!("Constant #{} is not a member.", member)),
+                }
+                .into_boxed(),
+            );
+        }
+        Ok(AST::Object {
+            extends,
+            members: asts,
+        })
+    }
+
+    /**
+     * Decompiles the conditional, `&&`, `||` or `!` which starts with the branch.
+     */
+    fn branch(
+        &mut self,
+        sequence: &mut Sequence,
+        position: usize,
+        label: ConstantPoolIndex,
+    ) -> Result<usize, String> {
+        let condition = sequence.pop()?.into_boxed();
+        let target = self.position(label)?;
+        if target <= position {
+            return Err(format!(
+                "Unstructured control flow at instruction {}.",
+                position
+            ));
+        }
+
+        // Conditionals jump over the 'then' part to the 'else' part.
+        if let (Some(Bytecode::Jump { label }), true) = (
+            self.code.insert_point.get(position + 1),
+            target == position + 2,
+        ) {
+            let else_start = self.position(*label)?;
+            let merge = self.merge(else_start)?;
+            let consequent = self.expression(target + 1, else_start - 1)?.into_boxed();
+            let alternative = self.expression(else_start + 1, merge)?.into_boxed();
+            sequence.push(AST::Conditional {
+                condition,
+                consequent,
+                alternative,
+            });
+            return Ok(merge);
+        }
+
+        // Logical operators have the false case first.
+        let merge = self.merge(target)?;
+        let alternative = self.expression(position + 1, target - 1)?;
+        let consequent = self.expression(target + 1, merge)?;
+        sequence.push(match (consequent, alternative) {
+            (AST::Boolean(false), AST::Boolean(true)) => AST::Not { operand: condition },
+            (right, AST::Boolean(false)) => AST::And {
+                left: condition,
+                right: right.into_boxed(),
+            },
+            (AST::Boolean(true), right) => AST::Or {
+                left: condition,
+                right: right.into_boxed(),
+            },
+            (consequent, alternative) => AST::Conditional {
+                condition,
+                consequent: consequent.into_boxed(),
+                alternative: alternative.into_boxed(),
+            },
+        });
+        Ok(merge)
+    }
+
+    /**
+     * Decompiles a loop which starts with the jump, `break` or `continue`.
+     */
+    fn jump(
+        &mut self,
+        sequence: &mut Sequence,
+        position: usize,
+        label: ConstantPoolIndex,
+    ) -> Result<usize, String> {
+        if let Some(Bytecode::Label { name }) = self.code.insert_point.get(position + 1) {
+            if let Some(next) = self.loop_block(sequence, position, label, *name)? {
+                return Ok(next);
+            }
+        }
+        let innermost = self
+            .loops
+            .last()
+            .ok_or_else(|| format!("Unstructured jump at instruction {}.", position))?;
+        if Some(label) == innermost.exit {
+            let value = match sequence.pop()? {
+                AST::Null => None,
+                value => Some(value.into_boxed()),
+            };
+            sequence.terminate(AST::Break { value });
+        } else if innermost.next.contains(&label) {
+            sequence.terminate(AST::Continue);
+        } else {
+            return Err(format!("Unstructured jump at instruction {}.", position));
+        }
+        Ok(position + 1)
+    }
+
+    /**
+     * Decompiles the loop laid out as
+     *   jump cond; begin: body; [step: step;] cond: condition; branch begin; null; end:
+     * and returns the position of its end label, or None if the code is not a loop.
+     */
+    fn loop_block(
+        &mut self,
+        sequence: &mut Sequence,
+        position: usize,
+        cond_label: ConstantPoolIndex,
+        begin_label: ConstantPoolIndex,
+    ) -> Result<Option<usize>, String> {
+        let code = &self.code.insert_point;
+        let cond = self.position(cond_label)?;
+        if cond <= position {
+            return Ok(None);
+        }
+        let branch =
+            (cond + 1..code.len()).find(|p| code[*p] == Bytecode::Branch { label: begin_label });
+        let branch = match branch {
+            Some(branch) => branch,
+            None => return Ok(None),
+        };
+        // Code with resolved labels keeps the end label only if 'break' jumps to it.
+        let exit = match (code.get(branch + 1), code.get(branch + 2)) {
+            (Some(Bytecode::Literal { index }), label)
+                if matches!(self.pool.get(*index), Constant::Null) =>
+            {
+                match label {
+                    Some(Bytecode::Label { name }) => Some(*name),
+                    _ => None,
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        // The step of 'for' loops is recognized only if 'continue' jumps to it,
+        // otherwise it's left as the end of the body.
+        let saved = (self.declared.clone(), self.globals.clone());
+        self.loops.push(Loop {
+            exit,
+            next: vec![cond_label],
+        });
+        let body = self.statements(position + 2, cond);
+        self.loops.pop();
+        let ast = match body {
+            Ok(body) => AST::Loop {
+                condition: self.expression(cond + 1, branch)?.into_boxed(),
+                body: body.into_boxed(),
+            },
+            Err(error) => {
+                (self.declared, self.globals) = saved;
+                self.step_loop(sequence, position + 1, cond, branch, exit)?
+                    .ok_or(error)?
+            }
+        };
+        sequence.push(ast);
+        Ok(Some(branch + 2))
+    }
+
+    /**
+     * Decompiles the loop with a step as `for` or `for ... in`, removing
+     * the variables it introduces from the sequence.
+     */
+    fn step_loop(
+        &mut self,
+        sequence: &mut Sequence,
+        begin: usize,
+        cond: usize,
+        branch: usize,
+        exit: Option<ConstantPoolIndex>,
+    ) -> Result<Option<AST>, String> {
+        let code = &self.code.insert_point;
+        let step = (begin + 1..cond)
+            .rev()
+            .find(|p| matches!(code[*p], Bytecode::Label { .. }));
+        let (step, step_label) = match step.map(|step| (step, code[step])) {
+            Some((step, Bytecode::Label { name })) => (step, name),
+            _ => return Ok(None),
+        };
+
+        self.loops.push(Loop {
+            exit,
+            next: vec![step_label],
+        });
+        let body = self.sequence(begin + 1, step);
+        self.loops.pop();
+        let mut body = body?.into_statements();
+        let step = self.statements(step + 1, cond)?;
+        let condition = self.expression(cond + 1, branch)?;
+
+        let (counter, limit) = match &condition {
+            AST::CallMethod {
+                object,
+                name,
+                arguments,
+            } if name.as_str() == "<" && arguments.len() == 1 => {
+                match (&**object, &*arguments[0]) {
+                    (
+                        AST::AccessVariable { name: counter },
+                        AST::AccessVariable { name: limit },
+                    ) => (counter.as_str(), limit.as_str()),
+                    _ => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        };
+        if !is_increment(&step, counter) {
+            return Ok(None);
+        }
+
+        let statements = &sequence.statements;
+        let init = |offset: usize| match statements.len().checked_sub(offset) {
+            Some(index) => match &statements[index] {
+                AST::Variable { name, value } => Some((name.as_str(), &**value)),
+                _ => None,
+            },
+            None => None,
+        };
+
+        // for variable in from .. to
+        if let (Some((variable, from)), Some((end, to))) = (init(2), init(1)) {
+            if variable == counter && end == limit {
+                let ast = AST::For {
+                    variable: Identifier(String::from(variable)),
+                    from: from.clone().into_boxed(),
+                    to: to.clone().into_boxed(),
+                    body: block(body).into_boxed(),
+                };
+                sequence.statements.truncate(statements.len() - 2);
+                return Ok(Some(ast));
+            }
+        }
+
+        // for variable in array, with the element assigned at the start of the body
+        if let (Some((array, value)), Some((size, length)), Some((i, zero)), Some((variable, _))) =
+            (init(4), init(3), init(2), init(1))
+        {
+            let element = match body.first() {
+                Some(AST::AssignVariable { name, value }) if name.as_str() == variable => {
+                    match &**value {
+                        AST::AccessArray {
+                            array: element_array,
+                            index,
+                        } => is_variable(element_array, array) && is_variable(index, i),
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+            let length = match length {
+                AST::CallMethod {
+                    object,
+                    name,
+                    arguments,
+                } => {
+                    name.as_str() == "length" && arguments.is_empty() && is_variable(object, array)
+                }
+                _ => false,
+            };
+            if element && length && i == counter && size == limit && matches!(zero, AST::Integer(0))
+            {
+                let ast = AST::ForEach {
+                    variable: Identifier(String::from(variable)),
+                    array: value.clone().into_boxed(),
+                    body: block(body.split_off(1)).into_boxed(),
+                };
+                sequence.statements.truncate(statements.len() - 4);
+                return Ok(Some(ast));
+            }
+        }
+        Ok(None)
+    }
+
+    /**
+     * Decompiles the try block which starts with the label, using its entries
+     * in the handler table.
+     */
+    fn try_block(
+        &mut self,
+        sequence: &mut Sequence,
+        position: usize,
+        handler: ExceptionHandler,
+    ) -> Result<usize, String> {
+        let code = &self.code.insert_point;
+        let end = self.position(handler.end)?;
+        let handler_start = self.position(handler.handler)?;
+        let merge = self.merge(handler_start)?;
+        let catch_index = match (code.get(handler_start + 1), code.get(handler_start + 2)) {
+            (Some(Bytecode::SetLocal { index }), Some(Bytecode::Drop)) => *index,
+            _ => {
+                return Err(format!(
+                    "Unrecognized exception handler at instruction {}.",
+                    handler_start
+                ))
+            }
+        };
+
+        // With finally, exceptions from the handler are caught to run it again.
+        let rethrow = match code.get(handler_start + 3) {
+            Some(Bytecode::Label { name }) => self
+                .code
+                .handlers
+                .iter()
+                .find(|handler| handler.start == *name)
+                .copied(),
+            _ => None,
+        };
+        let rethrow = match rethrow {
+            Some(rethrow) if self.merge(self.position(rethrow.handler)?)? == merge => Some(rethrow),
+            _ => None,
+        };
+
+        let body = self.expression(position + 1, end)?.into_boxed();
+        let catch_var = self.local(catch_index)?;
+        self.declared.insert(catch_index);
+        let (handler, finally) = match rethrow {
+            Some(rethrow) => {
+                let finally = self.statements(end + 1, handler_start - 1)?;
+                let catch_end = self.position(rethrow.end)?;
+                let handler = self.expression(handler_start + 4, catch_end)?;
+                (handler, Some(finally.into_boxed()))
+            }
+            None if end + 2 == handler_start => (self.expression(handler_start + 3, merge)?, None),
+            None => {
+                return Err(format!(
+                    "Unrecognized try block at instruction {}.",
+                    position
+                ))
+            }
+        };
+        sequence.push(AST::Try {
+            body,
+            catch_var,
+            handler: handler.into_boxed(),
+            finally,
+        });
+        Ok(merge)
+    }
+}
+
+fn is_variable(ast: &AST, variable: &str) -> bool {
+    matches!(ast, AST::AccessVariable { name } if name.as_str() == variable)
+}
+
+/**
+ * Checks that the statement is `variable <- variable + 1`.
+ */
+fn is_increment(ast: &AST, variable: &str) -> bool {
+    let value = match ast {
+        AST::AssignVariable { name, value } if name.as_str() == variable => value,
+        _ => return false,
+    };
+    match &**value {
+        AST::CallMethod {
+            object,
+            name,
+            arguments,
+        } => {
+            name.as_str() == "+"
+                && is_variable(object, variable)
+                && matches!(arguments.as_slice(), [one] if matches!(**one, AST::Integer(1)))
+        }
+        _ => false,
+    }
+}
+
+fn block(statements: Vec<AST>) -> AST {
+    AST::Block(statements.into_iter().map(Box::new).collect())
+}
+
+/**
+ * Checks whether the tree refers to any of the variables.
+ */
+fn mentions(ast: &AST, variables: &HashSet<&str>) -> bool {
+    let name = match ast {
+        AST::Variable { name, .. }
+        | AST::AccessVariable { name }
+        | AST::AssignVariable { name, .. }
+        | AST::Try {
+            catch_var: name, ..
+        }
+        | AST::For { variable: name, .. }
+        | AST::ForEach { variable: name, .. } => Some(name.as_str()),
+        _ => None,
+    };
+    name.is_some_and(|name| variables.contains(name))
+        || ast
+            .children()
+            .into_iter()
+            .any(|child| ensure_stack(|| mentions(child, variables)))
+}
+
+/**
+ * Wraps the top level statements which use local variables of the main function
+ * in a block, since they would be globals otherwise. Globals introduced among
+ * them are declared in front of the block.
+ */
+fn scope_locals(mut statements: Vec<AST>, locals: &HashSet<&str>) -> Vec<AST> {
+    let first = statements.iter().position(|ast| mentions(ast, locals));
+    let last = statements.iter().rposition(|ast| mentions(ast, locals));
+    let (first, last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return statements,
+    };
+
+    let rest = statements.split_off(last + 1);
+    let scoped = statements.split_off(first);
+    let mut asts = Vec::new();
+    for mut ast in scoped {
+        if let AST::Variable { name, value } = &mut ast {
+            if !locals.contains(name.as_str()) {
+                statements.push(AST::Variable {
+                    name: name.clone(),
+                    value: AST::Null.into_boxed(),
+                });
+                ast = AST::AssignVariable {
+                    name: name.clone(),
+                    value: std::mem::replace(value, AST::Null.into_boxed()),
+                };
+            }
+        }
+        asts.push(ast);
+    }
+    statements.push(block(asts));
+    statements.extend(rest);
+    statements
+}
+
+/**
+ * Puts labels back into the code whose labels were resolved to instruction
+ * offsets, so that the patterns of the compiler can be recognized. The labels
+ * get indexes behind the constant pool, as their names are never looked up.
+ * Try blocks get start labels of their own, since they can start together.
+ */
+fn restore_labels(code: &Code, pool: &ConstantPool) -> Result<Code, String> {
+    let mut next = usize::from(pool.len());
+    let mut fresh = || {
+        next += 1;
+        ConstantPoolIndex::try_from(next - 1)
+            .map_err(|_| String::from("Too many labels to restore."))
+    };
+
+    let mut shared: HashMap<u32, ConstantPoolIndex> = HashMap::new();
+    let mut targets: Vec<u32> = code
+        .insert_point
+        .iter()
+        .filter_map(|inst| match inst {
+            Bytecode::JumpTo { target }
+            | Bytecode::BranchTo { target }
+            | Bytecode::BranchFalseTo { target } => Some(*target),
+            _ => None,
+        })
+        .collect();
+    for handler in code.resolved_handlers.iter() {
+        targets.extend([handler.end, handler.handler]);
+    }
+    for target in targets {
+        if let Entry::Vacant(entry) = shared.entry(target) {
+            entry.insert(fresh()?);
+        }
+    }
+    // Outer handlers come last in the table, but their blocks start first.
+    let mut starts = Vec::new();
+    for handler in code.resolved_handlers.iter().rev() {
+        starts.push((handler.start, fresh()?));
+    }
+
+    let mut restored = Code::new();
+    for position in 0..=code.len() {
+        if let Some(name) = shared.get(&position) {
+            restored.write_inst(Bytecode::Label { name: *name });
+        }
+        for (_, name) in starts.iter().filter(|(start, _)| *start == position) {
+            restored.write_inst(Bytecode::Label { name: *name });
+        }
+        let label = |target| shared[&target];
+        restored.write_inst(match code.insert_point.get(position as usize) {
+            Some(Bytecode::JumpTo { target }) => Bytecode::Jump {
+                label: label(*target),
+            },
+            Some(Bytecode::BranchTo { target }) => Bytecode::Branch {
+                label: label(*target),
+            },
+            Some(Bytecode::BranchFalseTo { target }) => Bytecode::BranchFalse {
+                label: label(*target),
+            },
+            Some(inst) => *inst,
+            None => break,
+        });
+    }
+    for (handler, (_, start)) in code.resolved_handlers.iter().zip(starts.iter().rev()) {
+        restored.add_handler(ExceptionHandler {
+            start: *start,
+            end: shared[&handler.end],
+            handler: shared[&handler.handler],
+            stack: handler.stack,
+        });
+    }
+    restored.handlers.extend(code.handlers.iter().copied());
+    restored.locals = code.locals.clone();
+    Ok(restored)
+}
+
+fn function(pool: &ConstantPool, index: ConstantPoolIndex, is_method: bool) -> Result<AST, String> {
+    let (name, parameters, locals, code) = match pool.get(index) {
+        Constant::Function {
+            name,
+            parameters,
+            locals,
+            code,
+        } => (*name, *parameters, *locals, code),
+        _ => return Err(format!("Constant #{} is not a function.", index)),
+    };
+    let code = &restore_labels(code, pool)?;
+    let mut decompiler = Decompiler::new(pool, code, parameters, locals, is_method, HashSet::new());
+    let end = match code.insert_point.last() {
+        Some(Bytecode::Return) => code.insert_point.len() - 1,
+        _ => code.insert_point.len(),
+    };
+    let body = decompiler.expression(0, end)?;
+    Ok(AST::Function {
+        name: Identifier(decompiler.string(name)?),
+        parameters: decompiler.names[is_method as usize..parameters.into()]
+            .iter()
+            .map(|name| Identifier(name.clone()))
+            .collect(),
+        body: body.into_boxed(),
+    })
+}
+
+/**
+ * Decompiles the program back to a tree, recovering the conditionals and loops
+ * from the jumps laid out by the compiler. Local variables are named after
+ * the debug information if the program has it, `local_N` otherwise.
+ * Global functions come first, followed by the statements of the main function.
+ */
+pub fn decompile(program: &Program) -> Result<AST, String> {
+    let pool = &program.pool;
+    let mut top = Vec::new();
+    let mut globals = HashSet::new();
+    for index in program.globals.globals.iter() {
+        match pool.get(*index) {
+            Constant::Function { .. } => top.push(function(pool, *index, false)?.into_boxed()),
+            Constant::Slot { name } => match pool.get(*name) {
+                Constant::String(name) => {
+                    globals.insert(name.clone());
+                }
+                _ => return Err(format!("Constant #{} is not a string.", name)),
+            },
+            _ => {
+                return Err(format!(
+                    "Global #{} is neither a slot nor a function.",
+                    index
+                ))
+            }
+        }
+    }
+
+    let (locals, code) = match pool.get(program.entry) {
+        Constant::Function { locals, code, .. } => (*locals, code),
+        _ => return Err(String::from("Entry of the program is not a function.")),
+    };
+    let code = &restore_labels(code, pool)?;
+    let mut decompiler = Decompiler::new(pool, code, 0, locals, false, globals);
+    let end = match code.insert_point.last() {
+        Some(Bytecode::Return) => code.insert_point.len() - 1,
+        _ => code.insert_point.len(),
+    };
+    let statements = decompiler.sequence(0, end)?.into_statements();
+    let locals = decompiler.names.iter().map(String::as_str).collect();
+    top.extend(scope_locals(statements, &locals).into_iter().map(Box::new));
+    Ok(AST::Top(top))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile, CompilerOptions};
+    use crate::interpreter::Interpreter;
+
+    fn run(program: &Program) -> String {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(&mut output);
+        interpreter.load_globals(&program.pool, &program.globals.globals);
+        interpreter.run(&program.pool, program.entry).unwrap();
+        drop(interpreter);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let ast: AST = serde_json::from_str(
+            r#"{"Top": [
+                {"Function": {"name": "f", "parameters": ["n"], "body": {"Conditional": {
+                    "condition": {"And": {
+                        "left": {"CallMethod": {"object": {"AccessVariable": {"name": "n"}},
+                            "name": ">", "arguments": [{"Integer": 0}]}},
+                        "right": {"Not": {"operand": {"Boolean": false}}}}},
+                    "consequent": {"Try": {
+                        "body": {"Throw": {"value": {"AccessVariable": {"name": "n"}}}},
+                        "catch_var": "e",
+                        "handler": {"CallMethod": {"object": {"AccessVariable": {"name": "e"}},
+                            "name": "*", "arguments": [{"Integer": 2}]}},
+                        "finally": {"Print": {"format": "finally\\n", "arguments": []}}}},
+                    "alternative": {"Or": {"left": {"Boolean": false}, "right": {"Integer": 7}}}}}}},
+                {"Variable": {"name": "o", "value": {"Object": {"extends": "Null", "members": [
+                    {"Variable": {"name": "x", "value": {"Integer": 3}}},
+                    {"Function": {"name": "get", "parameters": [], "body": {"AccessField": {
+                        "object": {"AccessVariable": {"name": "this"}}, "field": "x"}}}}
+                ]}}}},
+                {"Block": [
+                    {"Variable": {"name": "a", "value": {"Array": {"size": {"Integer": 3},
+                        "value": {"Integer": 1}}}}},
+                    {"AssignArray": {"array": {"AccessVariable": {"name": "a"}},
+                        "index": {"Integer": 1}, "value": {"CallFunction": {"name": "f",
+                        "arguments": [{"Integer": 5}]}}}},
+                    {"ForEach": {"variable": "v", "array": {"AccessVariable": {"name": "a"}},
+                        "body": {"Block": [
+                            {"Conditional": {"condition": {"CallMethod": {
+                                "object": {"AccessVariable": {"name": "v"}}, "name": "==",
+                                "arguments": [{"Integer": 1}]}},
+                                "consequent": "Continue", "alternative": "Null"}},
+                            {"Print": {"format": "~\\n", "arguments": [{"AccessVariable": {"name": "v"}}]}}
+                        ]}}}
+                ]},
+                {"For": {"variable": "i", "from": {"Integer": 0}, "to": {"Integer": 5},
+                    "body": {"Conditional": {"condition": {"CallMethod": {
+                        "object": {"AccessVariable": {"name": "i"}}, "name": "==",
+                        "arguments": [{"Integer": 3}]}},
+                        "consequent": {"Break": {"value": null}},
+                        "alternative": {"Print": {"format": "~ ~\\n", "arguments": [
+                            {"AccessVariable": {"name": "i"}},
+                            {"CallFunction": {"name": "f", "arguments": [{"Integer": 0}]}}]}}}}}},
+                {"Print": {"format": "~\\n", "arguments": [{"CallMethod": {
+                    "object": {"AccessVariable": {"name": "o"}}, "name": "get", "arguments": []}}]}}
+            ]}"#,
+        )
+        .unwrap();
+
+        for (branch_false, operators) in [(false, false), (true, false), (true, true)] {
+            let options = CompilerOptions {
+                branch_false,
+                operators,
+                superinstructions: operators,
+                debug: None,
+            };
+            let program = compile(&ast, &options).unwrap();
+            let decompiled = decompile(&program).unwrap();
+            let recompiled = compile(&decompiled, &options).unwrap();
+            assert_eq!(run(&recompiled), run(&program));
+
+            let resolved = crate::optimizer::resolve_labels(&program).unwrap();
+            let decompiled = decompile(&resolved).unwrap();
+            let recompiled = compile(&decompiled, &options).unwrap();
+            assert_eq!(run(&recompiled), run(&program));
+        }
+
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+        let source = crate::printer::to_source(&decompile(&program).unwrap());
+        assert!(source.contains("for local_4 in local_0 do"));
+        assert!(source.contains("function f(local_0) ->"));
+    }
+}
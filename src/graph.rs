@@ -0,0 +1,341 @@
+use crate::bytecode::{Bytecode, Code};
+use crate::constants::{Constant, ConstantPool, ConstantPoolIndex};
+use crate::debug::{describe, instruction};
+use crate::program::Program;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/**
+ * Escapes the text for a quoted DOT string.
+ */
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn function_name(pool: &ConstantPool, index: ConstantPoolIndex) -> String {
+    match pool.get(index) {
+        Constant::Function { name, .. } => match pool.get(*name) {
+            Constant::String(name) => name.clone(),
+            _ => describe(pool, *name),
+        },
+        _ => describe(pool, index),
+    }
+}
+
+/**
+ * Straight part of the code, entered only at its first instruction.
+ */
+struct Block {
+    start: usize,
+    end: usize,
+    successors: Vec<usize>,
+}
+
+/**
+ * Splits the code into basic blocks. A block starts at a label or a resolved
+ * jump target, and ends behind a jump, branch, return or throw.
+ */
+fn basic_blocks(code: &Code) -> Vec<Block> {
+    let insts = &code.insert_point;
+    let labels: HashMap<ConstantPoolIndex, usize> = insts
+        .iter()
+        .enumerate()
+        .filter_map(|(pc, inst)| match inst {
+            Bytecode::Label { name } => Some((*name, pc)),
+            _ => None,
+        })
+        .collect();
+    let target = |inst: &Bytecode| match inst {
+        Bytecode::Jump { label } | Bytecode::Branch { label } | Bytecode::BranchFalse { label } => {
+            labels.get(label).copied()
+        }
+        Bytecode::JumpTo { target }
+        | Bytecode::BranchTo { target }
+        | Bytecode::BranchFalseTo { target } => Some(*target as usize),
+        _ => None,
+    };
+
+    let mut starts = vec![0];
+    starts.extend(labels.values());
+    starts.extend(insts.iter().filter_map(target));
+    for handler in code.resolved_handlers.iter() {
+        starts.extend([handler.start, handler.end, handler.handler].map(|pc| pc as usize));
+    }
+    for (pc, inst) in insts.iter().enumerate() {
+        match inst {
+            Bytecode::Jump { .. }
+            | Bytecode::Branch { .. }
+            | Bytecode::BranchFalse { .. }
+            | Bytecode::JumpTo { .. }
+            | Bytecode::BranchTo { .. }
+            | Bytecode::BranchFalseTo { .. }
+            | Bytecode::Return
+            | Bytecode::Throw => starts.push(pc + 1),
+            _ => (),
+        }
+    }
+    starts.retain(|start| *start < insts.len());
+    starts.sort_unstable();
+    starts.dedup();
+    let block_of: HashMap<usize, usize> = starts
+        .iter()
+        .enumerate()
+        .map(|(block, start)| (*start, block))
+        .collect();
+
+    let mut blocks = Vec::new();
+    for (block, start) in starts.iter().enumerate() {
+        let end = starts.get(block + 1).copied().unwrap_or(insts.len());
+        let mut successors = Vec::new();
+        let last = &insts[end - 1];
+        successors.extend(target(last).and_then(|target| block_of.get(&target)));
+        let falls_through = !matches!(
+            last,
+            Bytecode::Jump { .. } | Bytecode::JumpTo { .. } | Bytecode::Return | Bytecode::Throw
+        );
+        if falls_through && end < insts.len() {
+            successors.push(block + 1);
+        }
+        blocks.push(Block {
+            start: *start,
+            end,
+            successors,
+        });
+    }
+    blocks
+}
+
+/**
+ * Renders control-flow graphs of all functions in the DOT format, one cluster
+ * per function. Dashed edges lead from blocks of try blocks to their handlers.
+ */
+pub fn cfg(program: &Program) -> String {
+    let pool = &program.pool;
+    let mut output = String::new();
+    let _ = writeln!(output, "digraph cfg {{");
+    let _ = writeln!(output, "    node [shape=box, fontname=monospace];");
+
+    for (index, constant) in pool.iter().enumerate() {
+        let code = match constant {
+            Constant::Function { code, .. } => code,
+            _ => continue,
+        };
+        let blocks = basic_blocks(code);
+        let node = |block: usize| format!("f{}_{}", index, block);
+        let _ = writeln!(output, "    subgraph cluster_{} {{", index);
+        let _ = writeln!(
+            output,
+            "        label = \"{}\";",
+            escape(&function_name(pool, index as ConstantPoolIndex))
+        );
+
+        for (i, block) in blocks.iter().enumerate() {
+            let mut label = String::new();
+            for pc in block.start..block.end {
+                let inst = &code.insert_point[pc];
+                let mut line = format!("{}: {}", pc, instruction(inst));
+                if !matches!(inst, Bytecode::GetLocal { .. } | Bytecode::SetLocal { .. }) {
+                    let mut comments = Vec::new();
+                    inst.map_indices(|index, _| {
+                        comments.push(describe(pool, index));
+                        index
+                    });
+                    if !comments.is_empty() {
+                        line = format!("{} ; {}", line, comments.join(", "));
+                    }
+                }
+                label.push_str(&escape(&line));
+                label.push_str("\\l");
+            }
+            let _ = writeln!(output, "        {} [label=\"{}\"];", node(i), label);
+            for successor in block.successors.iter() {
+                let _ = writeln!(output, "        {} -> {};", node(i), node(*successor));
+            }
+        }
+
+        let position = |label: ConstantPoolIndex| {
+            code.insert_point
+                .iter()
+                .position(|inst| *inst == Bytecode::Label { name: label })
+        };
+        let handlers = code
+            .handlers
+            .iter()
+            .map(|handler| [handler.start, handler.end, handler.handler].map(position))
+            .chain(code.resolved_handlers.iter().map(|handler| {
+                [handler.start, handler.end, handler.handler].map(|pc| Some(pc as usize))
+            }));
+        for handler in handlers {
+            let (start, end, target) = match handler {
+                [Some(start), Some(end), Some(target)] => (start, end, target),
+                _ => continue,
+            };
+            let target = blocks.iter().position(|block| block.start == target);
+            for (i, block) in blocks.iter().enumerate() {
+                if start <= block.start && block.start < end {
+                    if let Some(target) = target {
+                        let _ = writeln!(
+                            output,
+                            "        {} -> {} [style=dashed];",
+                            node(i),
+                            node(target)
+                        );
+                    }
+                }
+            }
+        }
+        let _ = writeln!(output, "    }}");
+    }
+    let _ = writeln!(output, "}}");
+    output
+}
+
+/**
+ * Renders the call graph in the DOT format. Edges lead from functions to the global
+ * functions they call by name, and from objects to their methods, which are called
+ * dynamically. Global functions not reachable from the entry are drawn in gray.
+ */
+pub fn callgraph(program: &Program) -> String {
+    let pool = &program.pool;
+    let functions: HashMap<String, ConstantPoolIndex> = program
+        .globals
+        .globals
+        .iter()
+        .filter(|index| matches!(pool.get(**index), Constant::Function { .. }))
+        .map(|index| (function_name(pool, *index), *index))
+        .collect();
+
+    let mut calls: HashMap<ConstantPoolIndex, Vec<ConstantPoolIndex>> = HashMap::new();
+    for (index, constant) in pool.iter().enumerate() {
+        let code = match constant {
+            Constant::Function { code, .. } => code,
+            _ => continue,
+        };
+        let callees = calls.entry(index as ConstantPoolIndex).or_default();
+        for inst in code.insert_point.iter() {
+            if let Bytecode::CallFunction { name, .. } = inst {
+                let callee = match pool.get(*name) {
+                    Constant::String(name) => functions.get(name),
+                    _ => None,
+                };
+                if let Some(callee) = callee {
+                    if !callees.contains(callee) {
+                        callees.push(*callee);
+                    }
+                }
+            }
+        }
+    }
+
+    // Methods are considered reachable, as they are called dynamically.
+    let mut reachable = HashSet::new();
+    let mut worklist: Vec<ConstantPoolIndex> = pool
+        .iter()
+        .filter_map(|constant| match constant {
+            Constant::Object { members } => Some(members.clone()),
+            _ => None,
+        })
+        .flatten()
+        .chain(std::iter::once(program.entry))
+        .collect();
+    while let Some(function) = worklist.pop() {
+        if reachable.insert(function) {
+            worklist.extend(calls.get(&function).into_iter().flatten());
+        }
+    }
+
+    let mut output = String::new();
+    let _ = writeln!(output, "digraph callgraph {{");
+    for (index, constant) in pool.iter().enumerate() {
+        let index = index as ConstantPoolIndex;
+        let attributes = match constant {
+            Constant::Function { .. } if index == program.entry => "shape=doublecircle",
+            Constant::Function { .. } if !reachable.contains(&index) => {
+                "color=gray, fontcolor=gray"
+            }
+            Constant::Function { .. } => "shape=ellipse",
+            Constant::Object { .. } => "shape=box",
+            _ => continue,
+        };
+        let label = match constant {
+            Constant::Object { .. } => format!("object #{}", index),
+            _ => function_name(pool, index),
+        };
+        let _ = writeln!(
+            output,
+            "    c{} [label=\"{}\", {}];",
+            index,
+            escape(&label),
+            attributes
+        );
+        if let Constant::Object { members } = constant {
+            for member in members.iter() {
+                if let Constant::Function { .. } = pool.get(*member) {
+                    let _ = writeln!(output, "    c{} -> c{} [style=dashed];", index, member);
+                }
+            }
+        }
+        for callee in calls.get(&index).into_iter().flatten() {
+            let _ = writeln!(output, "    c{} -> c{};", index, callee);
+        }
+    }
+    let _ = writeln!(output, "}}");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::AST;
+    use crate::compiler::{compile, CompilerOptions};
+
+    #[test]
+    fn graph_test() {
+        let ast: AST = serde_json::from_str(
+            r#"{"Top": [
+                {"Function": {"name": "f", "parameters": ["x"], "body": {"Conditional": {
+                    "condition": {"AccessVariable": {"name": "x"}},
+                    "consequent": {"CallFunction": {"name": "g", "arguments": []}},
+                    "alternative": {"Integer": 1}}}}},
+                {"Function": {"name": "g", "parameters": [], "body": {"Integer": 2}}},
+                {"Function": {"name": "dead", "parameters": [], "body": "Null"}},
+                {"CallFunction": {"name": "f", "arguments": [{"Boolean": true}]}}
+            ]}"#,
+        )
+        .unwrap();
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+
+        // Condition, then, else and merge blocks of 'f'
+        let f = program
+            .globals
+            .globals
+            .iter()
+            .find(|index| function_name(&program.pool, **index) == "f")
+            .unwrap();
+        let code = match program.pool.get(*f) {
+            Constant::Function { code, .. } => code,
+            _ => unreachable!(),
+        };
+        let blocks = basic_blocks(code);
+        assert_eq!(blocks.len(), 5);
+        assert_eq!(blocks[0].successors, vec![2, 1]);
+        assert_eq!(blocks[1].successors, vec![3]);
+        assert_eq!(blocks[2].successors, vec![4]);
+        assert_eq!(blocks[3].successors, vec![4]);
+        assert!(cfg(&program).contains("label = \"f\";"));
+
+        let graph = callgraph(&program);
+        let node = |name: &str| {
+            let index = program
+                .globals
+                .globals
+                .iter()
+                .copied()
+                .find(|index| function_name(&program.pool, *index) == name);
+            format!("c{}", index.unwrap())
+        };
+        assert!(graph.contains(&format!("c{} -> {};", program.entry, node("f"))));
+        assert!(graph.contains(&format!("{} -> {};", node("f"), node("g"))));
+        assert!(graph.contains(&format!("{} [label=\"dead\", color=gray", node("dead"))));
+    }
+}
@@ -0,0 +1,929 @@
+use crate::bytecode::*;
+use crate::constants::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(Rc<str>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    Object(Rc<RefCell<Object>>),
+}
+
+#[derive(Debug)]
+pub struct Object {
+    parent: Value,
+    // Class of the object, methods are looked up in it.
+    class: ConstantPoolIndex,
+    fields: Vec<(Rc<str>, Value)>,
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Null | Value::Boolean(false))
+    }
+
+    fn error(message: String) -> Value {
+        Value::String(Rc::from(message))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Integer(val) => write!(f, "{}", val),
+            Value::Float(val) => write!(f, "{:?}", val),
+            Value::Boolean(val) => write!(f, "{}", val),
+            Value::String(val) => write!(f, "{}", val),
+            Value::Array(array) => {
+                write!(f, "[")?;
+                for (i, value) in array.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(object) => {
+                let object = object.borrow();
+                write!(f, "object(")?;
+                let mut first = true;
+                if !matches!(object.parent, Value::Null) {
+                    write!(f, "..={}", object.parent)?;
+                    first = false;
+                }
+                for (name, value) in object.fields.iter() {
+                    if !first {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}={}", name, value)?;
+                    first = false;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null) => true,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Integer(a), Value::Float(b)) | (Value::Float(b), Value::Integer(a)) => {
+                *a as f64 == *b
+            }
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => Rc::ptr_eq(a, b),
+            (Value::Object(a), Value::Object(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/**
+ * Replaces escape sequences and placeholders of the print format.
+ */
+fn format(format: &str, arguments: &[Value]) -> Result<String, Value> {
+    let mut result = String::new();
+    let mut arguments = arguments.iter();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '~' => match arguments.next() {
+                Some(value) => result.push_str(&value.to_string()),
+                None => return Err(Value::error(String::from("Not enough print arguments."))),
+            },
+            '\\' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(c @ ('~' | '\\' | '"')) => result.push(c),
+                _ => return Err(Value::error(String::from("Invalid escape sequence."))),
+            },
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+fn int_op(name: &str, a: i64, b: i64) -> Option<Result<Value, Value>> {
+    let overflow = || Value::error(format!("Integer overflow in {} {} {}.", a, name, b));
+    let result = match name {
+        "+" => a.checked_add(b).map(Value::Integer).ok_or_else(overflow),
+        "-" => a.checked_sub(b).map(Value::Integer).ok_or_else(overflow),
+        "*" => a.checked_mul(b).map(Value::Integer).ok_or_else(overflow),
+        "/" if b == 0 => Err(Value::error(String::from("Division by zero."))),
+        "/" => a.checked_div(b).map(Value::Integer).ok_or_else(overflow),
+        "%" if b == 0 => Err(Value::error(String::from("Division by zero."))),
+        "%" => a.checked_rem(b).map(Value::Integer).ok_or_else(overflow),
+        "<" => Ok(Value::Boolean(a < b)),
+        "<=" => Ok(Value::Boolean(a <= b)),
+        ">" => Ok(Value::Boolean(a > b)),
+        ">=" => Ok(Value::Boolean(a >= b)),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn float_op(name: &str, a: f64, b: f64) -> Option<Value> {
+    let result = match name {
+        "+" => Value::Float(a + b),
+        "-" => Value::Float(a - b),
+        "*" => Value::Float(a * b),
+        "/" => Value::Float(a / b),
+        "%" => Value::Float(a % b),
+        "<" => Value::Boolean(a < b),
+        "<=" => Value::Boolean(a <= b),
+        ">" => Value::Boolean(a > b),
+        ">=" => Value::Boolean(a >= b),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn index(array_len: usize, index: &Value) -> Result<usize, Value> {
+    match index {
+        Value::Integer(i) if *i >= 0 && (*i as usize) < array_len => Ok(*i as usize),
+        _ => Err(Value::error(format!("Index {} out of bounds.", index))),
+    }
+}
+
+/**
+ * Methods of the values which are not objects.
+ */
+fn builtin_method(receiver: &Value, name: &str, arguments: &[Value]) -> Result<Value, Value> {
+    let result = match (receiver, name, arguments) {
+        (_, "==", [other]) => Some(Value::Boolean(receiver == other)),
+        (_, "!=", [other]) => Some(Value::Boolean(receiver != other)),
+        (Value::Boolean(a), "&", [other]) => Some(Value::Boolean(*a && other.is_truthy())),
+        (Value::Boolean(a), "|", [other]) => Some(Value::Boolean(*a || other.is_truthy())),
+        // Integers are promoted to floats if the other operand is a float.
+        (Value::Integer(a), _, [Value::Integer(b)]) => int_op(name, *a, *b).transpose()?,
+        (Value::Integer(a), _, [Value::Float(b)]) => float_op(name, *a as f64, *b),
+        (Value::Float(a), _, [Value::Integer(b)]) => float_op(name, *a, *b as f64),
+        (Value::Float(a), _, [Value::Float(b)]) => float_op(name, *a, *b),
+        (Value::String(a), "+", [other]) => {
+            Some(Value::String(Rc::from(format!("{}{}", a, other))))
+        }
+        (Value::String(a), "length", []) => Some(Value::Integer(a.chars().count() as i64)),
+        (Value::String(a), "get", [i]) => {
+            let i = index(a.chars().count(), i)?;
+            a.chars()
+                .nth(i)
+                .map(|c| Value::String(Rc::from(c.to_string())))
+        }
+        (Value::String(a), "<", [Value::String(b)]) => Some(Value::Boolean(a < b)),
+        (Value::String(a), "<=", [Value::String(b)]) => Some(Value::Boolean(a <= b)),
+        (Value::String(a), ">", [Value::String(b)]) => Some(Value::Boolean(a > b)),
+        (Value::String(a), ">=", [Value::String(b)]) => Some(Value::Boolean(a >= b)),
+        (Value::Array(array), "length", []) => Some(Value::Integer(array.borrow().len() as i64)),
+        (Value::Array(array), "get", [i]) => {
+            let array = array.borrow();
+            Some(array[index(array.len(), i)?].clone())
+        }
+        (Value::Array(array), "set", [i, value]) => {
+            let mut array = array.borrow_mut();
+            let i = index(array.len(), i)?;
+            array[i] = value.clone();
+            Some(value.clone())
+        }
+        _ => None,
+    };
+    result.ok_or_else(|| {
+        Value::error(format!(
+            "Value {} has no method '{}' with {} arguments.",
+            receiver,
+            name,
+            arguments.len()
+        ))
+    })
+}
+
+struct Frame {
+    function: ConstantPoolIndex,
+    pc: usize,
+    locals: Vec<Value>,
+    // Height of the operand stack when the function was called.
+    stack_base: usize,
+}
+
+/**
+ * Interpreter of the bytecode. Globals are kept between runs, so the program
+ * can be executed in parts.
+ */
+pub struct Interpreter<W: Write> {
+    variables: HashMap<Rc<str>, Value>,
+    functions: HashMap<Rc<str>, ConstantPoolIndex>,
+    // Positions of the labels, for each function
+    labels: HashMap<ConstantPoolIndex, Rc<HashMap<ConstantPoolIndex, usize>>>,
+    pub output: W,
+}
+
+impl<W: Write> Interpreter<W> {
+    pub fn new(output: W) -> Self {
+        Interpreter {
+            variables: HashMap::new(),
+            functions: HashMap::new(),
+            labels: HashMap::new(),
+            output,
+        }
+    }
+
+    /**
+     * Registers the global variables and functions. Already defined
+     * variables keep their value.
+     */
+    pub fn load_globals(&mut self, pool: &ConstantPool, globals: &[ConstantPoolIndex]) {
+        for global in globals.iter() {
+            match pool.get(*global) {
+                Constant::Slot { name } => {
+                    let name = string(pool, *name);
+                    self.variables.entry(name).or_insert(Value::Null);
+                }
+                Constant::Function { name, .. } => {
+                    self.functions.insert(string(pool, *name), *global);
+                }
+                _ => panic!("Global is neither a slot nor a function."),
+            }
+        }
+    }
+
+    fn labels(
+        &mut self,
+        code: &Code,
+        function: ConstantPoolIndex,
+    ) -> Rc<HashMap<ConstantPoolIndex, usize>> {
+        self.labels
+            .entry(function)
+            .or_insert_with(|| {
+                Rc::new(
+                    code.insert_point
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(pc, inst)| match inst {
+                            Bytecode::Label { name } => Some((*name, pc)),
+                            _ => None,
+                        })
+                        .collect(),
+                )
+            })
+            .clone()
+    }
+
+    fn frame(
+        &self,
+        pool: &ConstantPool,
+        function: ConstantPoolIndex,
+        arguments: Vec<Value>,
+        stack_base: usize,
+    ) -> Result<Frame, Value> {
+        match pool.get(function) {
+            Constant::Function {
+                name,
+                parameters,
+                locals,
+                ..
+            } => {
+                if *parameters as usize != arguments.len() {
+                    return Err(Value::error(format!(
+                        "Function '{}' expects {} arguments, got {}.",
+                        string(pool, *name),
+                        parameters,
+                        arguments.len()
+                    )));
+                }
+                let mut frame_locals = arguments;
+                frame_locals.resize(*parameters as usize + *locals as usize, Value::Null);
+                Ok(Frame {
+                    function,
+                    pc: 0,
+                    locals: frame_locals,
+                    stack_base,
+                })
+            }
+            _ => panic!("Called constant is not a function."),
+        }
+    }
+
+    /**
+     * Calls the method, looking it up in the parents if the object doesn't
+     * define it. Returns either the value of builtin method, or frame of the
+     * called method.
+     */
+    fn dispatch(
+        &self,
+        pool: &ConstantPool,
+        receiver: Value,
+        name: &str,
+        arguments: Vec<Value>,
+        stack_base: usize,
+    ) -> Result<Result<Value, Frame>, Value> {
+        let object = match &receiver {
+            Value::Object(object) => object.clone(),
+            _ => return builtin_method(&receiver, name, &arguments).map(Ok),
+        };
+        let class = object.borrow().class;
+        let method = match pool.get(class) {
+            Constant::Object { members } => members.iter().find(|member| {
+                matches!(pool.get(**member), Constant::Function { name: method, .. } if &*string(pool, *method) == name)
+            }),
+            _ => panic!("Class is not an object."),
+        };
+        match method {
+            Some(method) => {
+                let mut method_arguments = vec![receiver];
+                method_arguments.extend(arguments);
+                Ok(Err(self.frame(
+                    pool,
+                    *method,
+                    method_arguments,
+                    stack_base,
+                )?))
+            }
+            None => {
+                let parent = object.borrow().parent.clone();
+                match parent {
+                    Value::Null => builtin_method(&receiver, name, &arguments).map(Ok),
+                    parent => self.dispatch(pool, parent, name, arguments, stack_base),
+                }
+            }
+        }
+    }
+
+    /**
+     * Runs the function without parameters and returns its value,
+     * or the uncaught exception.
+     */
+    pub fn run(
+        &mut self,
+        pool: &ConstantPool,
+        function: ConstantPoolIndex,
+    ) -> Result<Value, Value> {
+        let mut stack: Vec<Value> = Vec::new();
+        let mut frames = vec![self.frame(pool, function, vec![], 0)?];
+
+        loop {
+            let result = self.step(pool, &mut stack, &mut frames);
+            match result {
+                Ok(Some(value)) => return Ok(value),
+                Ok(None) => (),
+                Err(exception) => {
+                    // Instruction which failed, known if there is debug information.
+                    let span = frames.last().and_then(|frame| {
+                        function_code(pool, frame.function).span_at(frame.pc as u32 - 1)
+                    });
+                    if let Err(exception) = self.unwind(pool, exception, &mut stack, &mut frames) {
+                        return Err(match span {
+                            Some(span) => Value::error(format!(
+                                "{} (line {}, column {})",
+                                exception, span.line, span.column
+                            )),
+                            None => exception,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /**
+     * Finds the handler of the exception, removing the frames which don't
+     * handle it. Returns the exception if nothing handles it.
+     */
+    fn unwind(
+        &mut self,
+        pool: &ConstantPool,
+        exception: Value,
+        stack: &mut Vec<Value>,
+        frames: &mut Vec<Frame>,
+    ) -> Result<(), Value> {
+        while let Some(frame) = frames.last_mut() {
+            let code = function_code(pool, frame.function);
+            let labels = self.labels(code, frame.function);
+            // Instruction which threw, or the call which is being unwound.
+            let pc = frame.pc - 1;
+            let handler = code
+                .handlers
+                .iter()
+                .map(|handler| {
+                    let start = labels[&handler.start];
+                    (start, labels[&handler.end], labels[&handler.handler], handler.stack)
+                })
+                .chain(code.resolved_handlers.iter().map(|handler| {
+                    let start = handler.start as usize;
+                    (start, handler.end as usize, handler.handler as usize, handler.stack)
+                }))
+                .find(|(start, end, _, _)| *start <= pc && pc < *end);
+            match handler {
+                Some((_, _, handler, height)) => {
+                    stack.truncate(frame.stack_base + height as usize);
+                    stack.push(exception);
+                    frame.pc = handler;
+                    return Ok(());
+                }
+                None => {
+                    stack.truncate(frame.stack_base);
+                    frames.pop();
+                }
+            }
+        }
+        Err(exception)
+    }
+
+    /**
+     * Executes one instruction. Returns value of the program if it ended.
+     */
+    fn step(
+        &mut self,
+        pool: &ConstantPool,
+        stack: &mut Vec<Value>,
+        frames: &mut Vec<Frame>,
+    ) -> Result<Option<Value>, Value> {
+        let frame = frames.last_mut().unwrap();
+        let code = function_code(pool, frame.function);
+        // Operands below belong to the callers.
+        let base = frame.stack_base;
+
+        // Main function doesn't have to end with return
+        if frame.pc >= code.insert_point.len() {
+            let value = match stack.len() > frame.stack_base {
+                true => stack.pop().unwrap(),
+                false => Value::Null,
+            };
+            return self.return_value(value, stack, frames);
+        }
+
+        let inst = code.insert_point[frame.pc];
+        frame.pc += 1;
+
+        match inst {
+            Bytecode::Literal { index } => stack.push(literal(pool, index)?),
+            Bytecode::GetLocal { index } => stack.push(frame.locals[index as usize].clone()),
+            Bytecode::SetLocal { index } => {
+                frame.locals[index as usize] = peek(stack, base)?.clone();
+            }
+            Bytecode::GetGlobal { name } => {
+                let name = string(pool, name);
+                match self.variables.get(&name) {
+                    Some(value) => stack.push(value.clone()),
+                    None => return Err(Value::error(format!("Unknown global '{}'.", name))),
+                }
+            }
+            Bytecode::SetGlobal { name } => {
+                let value = peek(stack, base)?.clone();
+                self.variables.insert(string(pool, name), value);
+            }
+            Bytecode::Object { class } => {
+                let members = match pool.get(class) {
+                    Constant::Object { members } => members,
+                    _ => panic!("Object instruction doesn't refer to an object."),
+                };
+                let names: Vec<Rc<str>> = members
+                    .iter()
+                    .filter_map(|member| match pool.get(*member) {
+                        Constant::Slot { name } => Some(string(pool, *name)),
+                        _ => None,
+                    })
+                    .collect();
+                let values = pop_many(stack, base, names.len())?;
+                let parent = pop(stack, base)?;
+                stack.push(Value::Object(Rc::new(RefCell::new(Object {
+                    parent,
+                    class,
+                    fields: names.into_iter().zip(values).collect(),
+                }))));
+            }
+            Bytecode::Array => {
+                let value = pop(stack, base)?;
+                let size = match pop(stack, base)? {
+                    Value::Integer(size) if size >= 0 => size as usize,
+                    size => return Err(Value::error(format!("Invalid array size {}.", size))),
+                };
+                stack.push(Value::Array(Rc::new(RefCell::new(vec![value; size]))));
+            }
+            Bytecode::GetField { name } => {
+                let object = pop(stack, base)?;
+                stack.push(field(&object, &string(pool, name), None)?);
+            }
+            Bytecode::SetField { name } => {
+                let value = pop(stack, base)?;
+                let object = pop(stack, base)?;
+                field(&object, &string(pool, name), Some(value.clone()))?;
+                stack.push(value);
+            }
+            Bytecode::CallMethod { name, arguments } => {
+                // The receiver is counted among the arguments.
+                let arguments = pop_many(stack, base, (arguments as usize).saturating_sub(1))?;
+                let receiver = pop(stack, base)?;
+                let stack_base = stack.len();
+                match self.dispatch(pool, receiver, &string(pool, name), arguments, stack_base)? {
+                    Ok(value) => stack.push(value),
+                    Err(frame) => frames.push(frame),
+                }
+            }
+            Bytecode::Add
+            | Bytecode::Sub
+            | Bytecode::Mul
+            | Bytecode::Div
+            | Bytecode::Mod
+            | Bytecode::Lt
+            | Bytecode::Le
+            | Bytecode::Gt
+            | Bytecode::Ge
+            | Bytecode::Eq
+            | Bytecode::Neq => {
+                let name = inst.operator_name().unwrap();
+                let argument = pop(stack, base)?;
+                let receiver = pop(stack, base)?;
+                // Objects may define the operator, primitives skip the method lookup.
+                match (&receiver, &argument) {
+                    (Value::Object(_), _) => {
+                        let stack_base = stack.len();
+                        match self.dispatch(pool, receiver, name, vec![argument], stack_base)? {
+                            Ok(value) => stack.push(value),
+                            Err(frame) => frames.push(frame),
+                        }
+                    }
+                    (Value::Integer(a), Value::Integer(b)) => match int_op(name, *a, *b) {
+                        Some(result) => stack.push(result?),
+                        None => stack.push(builtin_method(&receiver, name, &[argument])?),
+                    },
+                    _ => stack.push(builtin_method(&receiver, name, &[argument])?),
+                }
+            }
+            Bytecode::CallFunction { name, arguments } => {
+                let name = string(pool, name);
+                let function = match self.functions.get(&name) {
+                    Some(function) => *function,
+                    None => return Err(Value::error(format!("Unknown function '{}'.", name))),
+                };
+                let arguments = pop_many(stack, base, arguments as usize)?;
+                let frame = self.frame(pool, function, arguments, stack.len())?;
+                frames.push(frame);
+            }
+            Bytecode::Print {
+                format: index,
+                arguments,
+            } => {
+                let arguments = pop_many(stack, base, arguments as usize)?;
+                let output = format(&string(pool, index), &arguments)?;
+                self.output
+                    .write_all(output.as_bytes())
+                    .map_err(|err| Value::error(err.to_string()))?;
+                stack.push(Value::Null);
+            }
+            Bytecode::Label { .. } => (),
+            Bytecode::Jump { label } => {
+                let function = frame.function;
+                frame.pc = self.labels(code, function)[&label];
+            }
+            Bytecode::Branch { label } | Bytecode::BranchFalse { label } => {
+                let condition = pop(stack, base)?.is_truthy();
+                if condition == matches!(inst, Bytecode::Branch { .. }) {
+                    let function = frame.function;
+                    frame.pc = self.labels(code, function)[&label];
+                }
+            }
+            Bytecode::JumpTo { target } => frame.pc = target as usize,
+            // Without integer operands, the superinstructions act as `GetLocal`
+            // and the rest of the sequence runs one by one.
+            Bytecode::IncLocal { index, amount } => {
+                let amount = match pool.get(amount) {
+                    Constant::Integer(val) => Some(*val as i64),
+                    Constant::Long(val) => Some(*val),
+                    _ => None,
+                };
+                let sum = match (&frame.locals[index as usize], amount) {
+                    (Value::Integer(val), Some(amount)) => val.checked_add(amount),
+                    _ => None,
+                };
+                match sum {
+                    Some(sum) => {
+                        frame.locals[index as usize] = Value::Integer(sum);
+                        frame.pc += inst.fused_length() - 1;
+                    }
+                    None => stack.push(frame.locals[index as usize].clone()),
+                }
+            }
+            Bytecode::CompareLocalsBranch { left, right } => {
+                let locals = &frame.locals;
+                let condition = match (&locals[left as usize], &locals[right as usize]) {
+                    (Value::Integer(a), Value::Integer(b)) => Some(a < b),
+                    _ => None,
+                };
+                match condition {
+                    Some(condition) => {
+                        let branch = code.insert_point[frame.pc + 2];
+                        frame.pc += inst.fused_length() - 1;
+                        if condition {
+                            frame.pc = match branch {
+                                Bytecode::BranchTo { target } => target as usize,
+                                Bytecode::Branch { label } => {
+                                    let function = frame.function;
+                                    self.labels(code, function)[&label]
+                                }
+                                _ => panic!("Fused comparison is not followed by a branch."),
+                            };
+                        }
+                    }
+                    None => stack.push(frame.locals[left as usize].clone()),
+                }
+            }
+            Bytecode::BranchTo { target } | Bytecode::BranchFalseTo { target } => {
+                let condition = pop(stack, base)?.is_truthy();
+                if condition == matches!(inst, Bytecode::BranchTo { .. }) {
+                    frame.pc = target as usize;
+                }
+            }
+            Bytecode::Return => {
+                let value = pop(stack, base)?;
+                return self.return_value(value, stack, frames);
+            }
+            Bytecode::Drop => {
+                pop(stack, base)?;
+            }
+            Bytecode::Throw => return Err(pop(stack, base)?),
+        }
+        Ok(None)
+    }
+
+    fn return_value(
+        &mut self,
+        value: Value,
+        stack: &mut Vec<Value>,
+        frames: &mut Vec<Frame>,
+    ) -> Result<Option<Value>, Value> {
+        let frame = frames.pop().unwrap();
+        stack.truncate(frame.stack_base);
+        if frames.is_empty() {
+            return Ok(Some(value));
+        }
+        stack.push(value);
+        Ok(None)
+    }
+}
+
+fn function_code(pool: &ConstantPool, function: ConstantPoolIndex) -> &Code {
+    match pool.get(function) {
+        Constant::Function { code, .. } => code,
+        _ => panic!("Constant is not a function."),
+    }
+}
+
+fn string(pool: &ConstantPool, index: ConstantPoolIndex) -> Rc<str> {
+    match pool.get(index) {
+        Constant::String(str) => Rc::from(str.as_str()),
+        _ => panic!("Constant is not a string."),
+    }
+}
+
+fn literal(pool: &ConstantPool, index: ConstantPoolIndex) -> Result<Value, Value> {
+    let value = match pool.get(index) {
+        Constant::Integer(val) => Value::Integer(*val as i64),
+        Constant::Long(val) => Value::Integer(*val),
+        Constant::Float(val) => Value::Float(*val),
+        Constant::Boolean(val) => Value::Boolean(*val),
+        Constant::Null => Value::Null,
+        Constant::String(val) => Value::String(Rc::from(val.as_str())),
+        _ => {
+            return Err(Value::error(String::from(
+                "Constant can't be used as a literal.",
+            )))
+        }
+    };
+    Ok(value)
+}
+
+/**
+ * Pops the operand of the frame whose operands start at the base. Loaded
+ * bytecode isn't checked for its stack depth, so it might pop too much.
+ */
+fn pop(stack: &mut Vec<Value>, base: usize) -> Result<Value, Value> {
+    match stack.len() > base {
+        true => Ok(stack.pop().unwrap()),
+        false => Err(empty_stack()),
+    }
+}
+
+/**
+ * Pops the operands, returning them in the order they were pushed.
+ */
+fn pop_many(stack: &mut Vec<Value>, base: usize, count: usize) -> Result<Vec<Value>, Value> {
+    match stack.len().checked_sub(count) {
+        Some(start) if start >= base => Ok(stack.split_off(start)),
+        _ => Err(empty_stack()),
+    }
+}
+
+fn peek(stack: &[Value], base: usize) -> Result<&Value, Value> {
+    match stack.len() > base {
+        true => Ok(stack.last().unwrap()),
+        false => Err(empty_stack()),
+    }
+}
+
+fn empty_stack() -> Value {
+    Value::error(String::from("Instruction pops from an empty stack."))
+}
+
+/**
+ * Reads the field of the object or its parents, or sets it if a value is given.
+ */
+fn field(object: &Value, name: &str, value: Option<Value>) -> Result<Value, Value> {
+    let object = match object {
+        Value::Object(object) => object,
+        _ => {
+            return Err(Value::error(format!(
+                "Value {} has no field '{}'.",
+                object, name
+            )))
+        }
+    };
+    let mut object = object.borrow_mut();
+    match object.fields.iter_mut().find(|(field, _)| &**field == name) {
+        Some((_, field)) => match value {
+            Some(value) => {
+                *field = value.clone();
+                Ok(value)
+            }
+            None => Ok(field.clone()),
+        },
+        None => field(&object.parent, name, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Identifier, IntoBoxed, AST};
+    use crate::compiler::{compile, CompilerOptions};
+
+    fn run(ast: &AST) -> (Result<Value, Value>, String) {
+        let program = compile(ast, &CompilerOptions::default()).unwrap();
+        let mut interpreter = Interpreter::new(Vec::new());
+        interpreter.load_globals(&program.pool, &program.globals.globals);
+        let result = interpreter.run(&program.pool, program.entry);
+        (result, String::from_utf8(interpreter.output).unwrap())
+    }
+
+    fn var(name: &str) -> Box<AST> {
+        AST::AccessVariable {
+            name: Identifier(String::from(name)),
+        }
+        .into_boxed()
+    }
+
+    #[test]
+    fn run_test() {
+        // function f(n) { try { if (n > 2) throw "big" else n * 1.5 } catch (e) { e } }
+        // for i in 1..4 print("~ ", f(i))
+        let ast = AST::Top(vec![
+            AST::Function {
+                name: Identifier(String::from("f")),
+                parameters: vec![Identifier(String::from("n"))],
+                body: AST::Try {
+                    body: AST::Conditional {
+                        condition: AST::CallMethod {
+                            object: var("n"),
+                            name: Identifier(String::from(">")),
+                            arguments: vec![AST::Integer(2).into_boxed()],
+                        }
+                        .into_boxed(),
+                        consequent: AST::Throw {
+                            value: AST::String(String::from("big")).into_boxed(),
+                        }
+                        .into_boxed(),
+                        alternative: AST::CallMethod {
+                            object: var("n"),
+                            name: Identifier(String::from("*")),
+                            arguments: vec![AST::Float(1.5).into_boxed()],
+                        }
+                        .into_boxed(),
+                    }
+                    .into_boxed(),
+                    catch_var: Identifier(String::from("e")),
+                    handler: var("e"),
+                    finally: None,
+                }
+                .into_boxed(),
+            }
+            .into_boxed(),
+            AST::For {
+                variable: Identifier(String::from("i")),
+                from: AST::Integer(1).into_boxed(),
+                to: AST::Integer(4).into_boxed(),
+                body: AST::Print {
+                    format: String::from("~ "),
+                    arguments: vec![AST::CallFunction {
+                        name: Identifier(String::from("f")),
+                        arguments: vec![var("i")],
+                    }
+                    .into_boxed()],
+                }
+                .into_boxed(),
+            }
+            .into_boxed(),
+        ]);
+
+        let (result, output) = run(&ast);
+        assert!(result.is_ok());
+        assert_eq!(output, "1.5 3.0 big ");
+
+        let (result, _) = run(&AST::Top(vec![AST::CallMethod {
+            object: AST::Integer(i64::MAX).into_boxed(),
+            name: Identifier(String::from("+")),
+            arguments: vec![AST::Integer(1).into_boxed()],
+        }
+        .into_boxed()]));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn empty_stack_test() {
+        // f drops the value main pushed before the call.
+        let program = crate::assembler::assemble(
+            "constant f = function \"f\" params 0 locals 0
+                drop
+                return
+            end
+            constant main = function \"main\" params 0 locals 0
+                literal one
+                call_function \"f\" 0
+            end
+            constant one = integer 1
+            globals f
+            entry main",
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::new(Vec::new());
+        interpreter.load_globals(&program.pool, &program.globals.globals);
+        let result = interpreter.run(&program.pool, program.entry);
+        assert!(matches!(
+            result,
+            Err(Value::String(message)) if &*message == "Instruction pops from an empty stack."
+        ));
+    }
+
+    #[test]
+    fn inc_local_fallback_test() {
+        // function f(x) { x <- x + 2; print("~", x) }  f(1)
+        let ast = AST::Top(vec![
+            AST::Function {
+                name: Identifier(String::from("f")),
+                parameters: vec![Identifier(String::from("x"))],
+                body: AST::Block(vec![
+                    AST::AssignVariable {
+                        name: Identifier(String::from("x")),
+                        value: AST::CallMethod {
+                            object: var("x"),
+                            name: Identifier(String::from("+")),
+                            arguments: vec![AST::Integer(2).into_boxed()],
+                        }
+                        .into_boxed(),
+                    }
+                    .into_boxed(),
+                    AST::Print {
+                        format: String::from("~"),
+                        arguments: vec![var("x")],
+                    }
+                    .into_boxed(),
+                ])
+                .into_boxed(),
+            }
+            .into_boxed(),
+            AST::CallFunction {
+                name: Identifier(String::from("f")),
+                arguments: vec![AST::Integer(1).into_boxed()],
+            }
+            .into_boxed(),
+        ]);
+        let options = CompilerOptions {
+            superinstructions: true,
+            ..CompilerOptions::default()
+        };
+        let mut program = compile(&ast, &options).unwrap();
+        let amount = program.pool.find(&Constant::Integer(2)).unwrap();
+        assert!(program.pool.iter().any(|constant| matches!(
+            constant,
+            Constant::Function { code, .. } if code.insert_point.contains(&Bytecode::IncLocal { index: 0, amount })
+        )));
+
+        // Without an integer amount, the fused sequence runs unfused.
+        program.pool.replace(amount, Constant::from(0.5));
+        let mut interpreter = Interpreter::new(Vec::new());
+        interpreter.load_globals(&program.pool, &program.globals.globals);
+        assert!(interpreter.run(&program.pool, program.entry).is_ok());
+        assert_eq!(String::from_utf8(interpreter.output).unwrap(), "1.5");
+    }
+}
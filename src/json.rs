@@ -0,0 +1,242 @@
+use crate::ast::ensure_stack;
+
+/**
+ * JSON value with the byte offsets of its source. The AST itself doesn't
+ * remember where it came from, so the server works with these.
+ */
+#[derive(Debug)]
+pub(crate) struct Spanned {
+    pub start: usize,
+    pub end: usize,
+    pub value: Json,
+}
+
+#[derive(Debug)]
+pub(crate) enum Json {
+    Null,
+    Bool,
+    Number,
+    String(String),
+    Array(Vec<Spanned>),
+    Object(Vec<(String, Spanned)>),
+}
+
+pub(crate) struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+pub(crate) type ParseError = (usize, &'static str);
+
+impl<'a> JsonParser<'a> {
+    pub fn parse(text: &'a str) -> Result<Spanned, ParseError> {
+        let mut parser = JsonParser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.pos != parser.text.len() {
+            return Err((parser.pos, "Trailing characters after the program."));
+        }
+        Ok(value)
+    }
+
+    fn whitespace(&mut self) {
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), ParseError> {
+        self.whitespace();
+        if self.text.get(self.pos) != Some(&c) {
+            return Err((self.pos, "Unexpected character."));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.text.get(self.pos) {
+                None => return Err((self.pos, "Unterminated string.")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let escape = self.text.get(self.pos + 1).copied();
+                    self.pos += 2;
+                    match escape {
+                        Some(b'n') => bytes.push(b'\n'),
+                        Some(b't') => bytes.push(b'\t'),
+                        Some(b'r') => bytes.push(b'\r'),
+                        Some(b'b') => bytes.push(0x08),
+                        Some(b'f') => bytes.push(0x0C),
+                        Some(b'u') => {
+                            let hex = self
+                                .text
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .ok_or((self.pos, "Invalid unicode escape."))?;
+                            let c = char::from_u32(hex).unwrap_or(char::REPLACEMENT_CHARACTER);
+                            bytes.extend(c.to_string().as_bytes());
+                            self.pos += 4;
+                        }
+                        Some(c @ (b'"' | b'\\' | b'/')) => bytes.push(c),
+                        _ => return Err((self.pos - 1, "Invalid escape sequence.")),
+                    }
+                    continue;
+                }
+                Some(c) => bytes.push(*c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        String::from_utf8(bytes).map_err(|_| (self.pos, "Invalid UTF-8."))
+    }
+
+    fn value(&mut self) -> Result<Spanned, ParseError> {
+        self.whitespace();
+        let start = self.pos;
+        let value = match self.text.get(self.pos) {
+            None => return Err((self.pos, "Unexpected end of file.")),
+            Some(b'"') => Json::String(self.string()?),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.whitespace();
+                if self.text.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        items.push(ensure_stack(|| self.value())?);
+                        self.whitespace();
+                        match self.text.get(self.pos) {
+                            Some(b',') => self.pos += 1,
+                            Some(b']') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            _ => return Err((self.pos, "Expected ',' or ']'.")),
+                        }
+                    }
+                }
+                Json::Array(items)
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.whitespace();
+                if self.text.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        members.push((key, ensure_stack(|| self.value())?));
+                        self.whitespace();
+                        match self.text.get(self.pos) {
+                            Some(b',') => self.pos += 1,
+                            Some(b'}') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            _ => return Err((self.pos, "Expected ',' or '}'.")),
+                        }
+                    }
+                }
+                Json::Object(members)
+            }
+            Some(_) => {
+                let word_end = self.text[self.pos..]
+                    .iter()
+                    .position(|c| !(c.is_ascii_alphanumeric() || b"+-.".contains(c)))
+                    .map_or(self.text.len(), |len| self.pos + len);
+                let word = &self.text[self.pos..word_end];
+                let value = match word {
+                    b"null" => Json::Null,
+                    b"true" | b"false" => Json::Bool,
+                    _ if std::str::from_utf8(word)
+                        .ok()
+                        .and_then(|word| word.parse::<f64>().ok())
+                        .is_some() =>
+                    {
+                        Json::Number
+                    }
+                    _ => return Err((self.pos, "Unexpected token.")),
+                };
+                self.pos = word_end;
+                value
+            }
+        };
+        Ok(Spanned {
+            start,
+            end: self.pos,
+            value,
+        })
+    }
+}
+
+/**
+ * Nested values are moved out to a list before they are dropped, like the AST.
+ */
+impl Drop for Json {
+    fn drop(&mut self) {
+        let mut values = self.take_values();
+        while let Some(mut value) = values.pop() {
+            values.extend(value.value.take_values());
+        }
+    }
+}
+
+impl Json {
+    fn take_values(&mut self) -> Vec<Spanned> {
+        match self {
+            Json::Array(items) => std::mem::take(items),
+            Json::Object(members) => std::mem::take(members)
+                .into_iter()
+                .map(|(_, value)| value)
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+impl Spanned {
+    /**
+     * Returns the name of the AST variant and its content.
+     */
+    pub fn variant(&self) -> Option<(&str, &Spanned)> {
+        match &self.value {
+            Json::String(name) => Some((name, self)),
+            Json::Object(members) if members.len() == 1 => Some((&members[0].0, &members[0].1)),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Spanned> {
+        match &self.value {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn items(&self) -> &[Spanned] {
+        match &self.value {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            Json::String(str) => Some(str),
+            _ => None,
+        }
+    }
+}
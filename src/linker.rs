@@ -0,0 +1,291 @@
+use crate::ast::AST;
+use crate::bytecode::*;
+use crate::compiler::Globals;
+use crate::constants::*;
+use crate::program::Program;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/**
+ * Returns paths of the modules imported by the program.
+ */
+pub fn imports(ast: &AST) -> Vec<&str> {
+    match ast {
+        AST::Top(asts) => asts
+            .iter()
+            .filter_map(|ast| match &**ast {
+                AST::Import { path } => Some(path.as_str()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/**
+ * Loads the module and all modules it imports, transitively. Every module
+ * comes after the modules it imports, so their globals are initialized first.
+ */
+pub fn load_modules(path: &Path) -> Result<Vec<(PathBuf, AST)>, String> {
+    fn load(
+        path: &Path,
+        loading: &mut Vec<PathBuf>,
+        modules: &mut Vec<(PathBuf, AST)>,
+    ) -> Result<(), String> {
+        let path = fs::canonicalize(path)
+            .map_err(|err| format!("Can't open module '{}': {}", path.display(), err))?;
+        if modules.iter().any(|(loaded, _)| *loaded == path) {
+            return Ok(());
+        }
+        if loading.contains(&path) {
+            return Err(format!("Import cycle through '{}'.", path.display()));
+        }
+
+        let source = fs::read_to_string(&path)
+            .map_err(|err| format!("Can't read module '{}': {}", path.display(), err))?;
+        let tree = AST::from_json(&source)
+            .map_err(|err| format!("Can't parse module '{}': {}", path.display(), err))?;
+
+        loading.push(path.clone());
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for import in imports(&tree) {
+            load(&dir.join(import), loading, modules)?;
+        }
+        loading.pop();
+
+        modules.push((path, tree));
+        Ok(())
+    }
+
+    let mut modules = Vec::new();
+    load(path, &mut Vec::new(), &mut modules)?;
+    Ok(modules)
+}
+
+/**
+ * Indexes of one module's constants in the linked pool.
+ */
+#[derive(Default)]
+struct ModuleMap {
+    constants: HashMap<ConstantPoolIndex, ConstantPoolIndex>,
+    labels: HashMap<ConstantPoolIndex, ConstantPoolIndex>,
+}
+
+struct Linker {
+    pool: ConstantPool,
+    // Main functions of the modules end up in one function, so the labels
+    // have to be unique in the whole program.
+    labels: HashSet<String>,
+}
+
+impl Linker {
+    fn constant(
+        &mut self,
+        module: &Program,
+        map: &mut ModuleMap,
+        index: ConstantPoolIndex,
+    ) -> ConstantPoolIndex {
+        if let Some(new) = map.constants.get(&index) {
+            return *new;
+        }
+
+        let constant = match module.pool.get(index) {
+            Constant::Slot { name } => Constant::Slot {
+                name: self.constant(module, map, *name),
+            },
+            Constant::Object { members } => Constant::Object {
+                members: members
+                    .iter()
+                    .map(|member| self.constant(module, map, *member))
+                    .collect(),
+            },
+            Constant::Function {
+                name,
+                parameters,
+                locals,
+                code,
+            } => Constant::Function {
+                name: self.constant(module, map, *name),
+                parameters: *parameters,
+                locals: *locals,
+                code: self.code(module, map, code),
+            },
+            constant => constant.clone(),
+        };
+
+        let new = self.pool.push(constant);
+        map.constants.insert(index, new);
+        new
+    }
+
+    fn label(
+        &mut self,
+        module: &Program,
+        map: &mut ModuleMap,
+        index: ConstantPoolIndex,
+    ) -> ConstantPoolIndex {
+        if let Some(new) = map.labels.get(&index) {
+            return *new;
+        }
+
+        let name = match module.pool.get(index) {
+            Constant::String(name) => name,
+            _ => panic!("Label name is not a string."),
+        };
+        let mut new_name = name.clone();
+        let mut cnt = 0;
+        while self.labels.contains(&new_name) {
+            new_name = format!("{}_{}", name, cnt);
+            cnt += 1;
+        }
+        self.labels.insert(new_name.clone());
+
+        let new = self.pool.push(Constant::from(new_name));
+        map.labels.insert(index, new);
+        new
+    }
+
+    fn code(&mut self, module: &Program, map: &mut ModuleMap, code: &Code) -> Code {
+        let mut new = Code::new();
+        for inst in code.insert_point.iter() {
+            new.write_inst(inst.map_indices(|index, is_label| {
+                if is_label {
+                    self.label(module, map, index)
+                } else {
+                    self.constant(module, map, index)
+                }
+            }));
+        }
+        for handler in code.handlers.iter() {
+            new.add_handler(ExceptionHandler {
+                start: self.label(module, map, handler.start),
+                end: self.label(module, map, handler.end),
+                handler: self.label(module, map, handler.handler),
+                stack: handler.stack,
+            });
+        }
+        new.lines = code.lines.clone();
+        new.locals = code.locals.clone();
+        new.labels = code.labels.clone();
+        new
+    }
+}
+
+fn global_name(pool: &ConstantPool, global: ConstantPoolIndex) -> Result<&str, String> {
+    let name = match pool.get(global) {
+        Constant::Slot { name } | Constant::Function { name, .. } => *name,
+        _ => return Err(String::from("Global is neither a slot nor a function.")),
+    };
+    match pool.get(name) {
+        Constant::String(name) => Ok(name),
+        _ => Err(String::from("Name of a global is not a string.")),
+    }
+}
+
+/**
+ * Merges the modules into one program. Main functions of the modules are
+ * concatenated, in the given order, into the main function of the program.
+ */
+pub fn link(modules: &[Program]) -> Result<Program, String> {
+    let mut linker = Linker {
+        pool: ConstantPool::new(),
+        labels: HashSet::new(),
+    };
+    let mut globals = Globals::new();
+    // Module which defines the global
+    let mut defined: HashMap<&str, usize> = HashMap::new();
+    let mut main = Code::new();
+    let mut locals = 0;
+
+    for (i, module) in modules.iter().enumerate() {
+        let mut map = ModuleMap::default();
+        if module.has_resolved_labels() {
+            return Err(String::from("Modules with resolved labels can't be linked."));
+        }
+
+        for global in module.globals.globals.iter() {
+            let name = global_name(&module.pool, *global)?;
+            if *defined.entry(name).or_insert(i) != i {
+                return Err(format!("Global '{}' is defined in multiple modules.", name));
+            }
+            globals.introduce_variable(linker.constant(module, &mut map, *global));
+        }
+
+        match module.pool.get(module.entry) {
+            Constant::Function {
+                locals: main_locals,
+                code,
+                ..
+            } => {
+                // Locals of the main function only live in the top level blocks,
+                // so the modules can share them.
+                locals = locals.max(*main_locals);
+                let code = linker.code(module, &mut map, code);
+                main.write_insts(code);
+            }
+            _ => return Err(String::from("Entry point is not a function.")),
+        }
+    }
+
+    let name = linker.pool.push(Constant::from(String::from("λ:")));
+    let entry = linker.pool.push(Constant::Function {
+        name,
+        parameters: 0,
+        locals,
+        code: main,
+    });
+
+    Ok(Program {
+        pool: linker.pool,
+        globals,
+        entry,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Identifier, IntoBoxed};
+    use crate::compiler::{compile, CompilerOptions};
+
+    fn module(global: &str) -> Program {
+        // var global = 1; while (false) null;
+        let ast = AST::Top(vec![
+            AST::Variable {
+                name: Identifier(String::from(global)),
+                value: AST::Integer(1).into_boxed(),
+            }
+            .into_boxed(),
+            AST::Loop {
+                condition: AST::Boolean(false).into_boxed(),
+                body: AST::Null.into_boxed(),
+            }
+            .into_boxed(),
+        ]);
+        compile(&ast, &CompilerOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn link_test() {
+        let program = link(&[module("a"), module("b")]).unwrap();
+        assert_eq!(program.globals.len(), 2);
+
+        let code = match program.pool.get(program.entry) {
+            Constant::Function { code, .. } => code,
+            _ => panic!("Entry point is not a function."),
+        };
+        let labels: Vec<_> = code
+            .insert_point
+            .iter()
+            .filter_map(|inst| match inst {
+                Bytecode::Label { name } => Some(*name),
+                _ => None,
+            })
+            .collect();
+        let unique: HashSet<_> = labels.iter().collect();
+        assert_eq!(labels.len(), unique.len());
+
+        assert!(link(&[module("a"), module("a")]).is_err());
+    }
+}
@@ -0,0 +1,697 @@
+use crate::ast::AST;
+use crate::compiler::{compile, CompilerOptions, Environments, VecEnvironments};
+use crate::debug::Spans;
+use crate::json::{Json, JsonParser, Spanned};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Kind {
+    Global,
+    Function,
+    Local,
+    Field,
+    Method,
+}
+
+#[derive(Debug)]
+struct Definition {
+    name: String,
+    kind: Kind,
+    start: usize,
+    end: usize,
+    parameters: Option<Vec<String>>,
+    // Where the name can be used, locals are visible only in their scope.
+    visible: (usize, usize),
+}
+
+#[derive(Debug)]
+struct Reference {
+    start: usize,
+    end: usize,
+    targets: Vec<usize>,
+}
+
+/**
+ * Definitions of all names in the document, and what each occurrence of
+ * a name refers to. Scopes are resolved the same way as in the compiler.
+ */
+#[derive(Default, Debug)]
+struct Analysis {
+    definitions: Vec<Definition>,
+    references: Vec<Reference>,
+    globals: HashMap<String, usize>,
+    members: HashMap<(String, bool), Vec<usize>>,
+    undeclared_fields: Vec<(usize, usize)>,
+}
+
+/**
+ * Environment of one function, definitions are indexed by local slots.
+ */
+struct LocalEnv {
+    env: VecEnvironments,
+    slots: HashMap<u16, usize>,
+    // Ends of the entered scopes
+    scope_ends: Vec<usize>,
+}
+
+impl LocalEnv {
+    fn new(end: usize) -> Self {
+        LocalEnv {
+            env: VecEnvironments::new(),
+            slots: HashMap::new(),
+            scope_ends: vec![end],
+        }
+    }
+}
+
+fn parameter_names(parameters: Option<&Spanned>) -> Vec<String> {
+    parameters
+        .map(|parameters| {
+            parameters
+                .items()
+                .iter()
+                .filter_map(|p| p.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl Analysis {
+    fn new(tree: &Spanned) -> Self {
+        let mut analysis = Analysis::default();
+        analysis.collect_globals(tree);
+        analysis.collect_members(tree);
+
+        let mut global_env = LocalEnv::new(tree.end);
+        analysis.walk(tree, &mut global_env, true);
+        analysis
+    }
+
+    fn define(&mut self, name: &Spanned, kind: Kind, visible: (usize, usize)) -> Option<usize> {
+        let id = self.definitions.len();
+        self.definitions.push(Definition {
+            name: String::from(name.as_str()?),
+            kind,
+            start: name.start,
+            end: name.end,
+            parameters: None,
+            visible,
+        });
+        self.references.push(Reference {
+            start: name.start,
+            end: name.end,
+            targets: vec![id],
+        });
+        Some(id)
+    }
+
+    fn refer(&mut self, name: &Spanned, targets: Vec<usize>) {
+        if !targets.is_empty() {
+            self.references.push(Reference {
+                start: name.start,
+                end: name.end,
+                targets,
+            });
+        }
+    }
+
+    fn collect_globals(&mut self, tree: &Spanned) {
+        let items = match tree.variant() {
+            Some(("Top", items)) => items.items(),
+            _ => return,
+        };
+        for item in items.iter() {
+            let (kind, content) = match item.variant() {
+                Some(("Function", content)) => (Kind::Function, content),
+                Some(("Variable", content)) => (Kind::Global, content),
+                _ => continue,
+            };
+            if let Some(name) = content.get("name") {
+                if let Some(id) = self.define(name, kind, (0, usize::MAX)) {
+                    if kind == Kind::Function {
+                        self.definitions[id].parameters =
+                            Some(parameter_names(content.get("parameters")));
+                    }
+                    self.globals.insert(self.definitions[id].name.clone(), id);
+                }
+            }
+        }
+    }
+
+    /**
+     * Object fields and methods can be accessed from anywhere.
+     */
+    fn collect_members(&mut self, node: &Spanned) {
+        if let Some(("Object", object)) = node.variant() {
+            for member in object.get("members").map_or(&[][..], |m| m.items()) {
+                let (kind, content) = match member.variant() {
+                    Some(("Function", content)) => (Kind::Method, content),
+                    Some(("Variable", content)) => (Kind::Field, content),
+                    _ => continue,
+                };
+                if let Some(name) = content.get("name") {
+                    if let Some(id) = self.define(name, kind, (0, usize::MAX)) {
+                        let definition = &mut self.definitions[id];
+                        if kind == Kind::Method {
+                            let mut parameters = vec![String::from("this")];
+                            parameters.extend(parameter_names(content.get("parameters")));
+                            definition.parameters = Some(parameters);
+                        }
+                        let key = (definition.name.clone(), kind == Kind::Method);
+                        self.members.entry(key).or_default().push(id);
+                    }
+                }
+            }
+        }
+        match &node.value {
+            Json::Array(items) => items.iter().for_each(|item| self.collect_members(item)),
+            Json::Object(members) => members
+                .iter()
+                .for_each(|(_, value)| self.collect_members(value)),
+            _ => (),
+        }
+    }
+
+    fn define_local(&mut self, name: &Spanned, env: &mut LocalEnv) {
+        let scope_end = *env.scope_ends.last().unwrap();
+        let slot = match name.as_str() {
+            Some(str) => env.env.introduce_variable(String::from(str)),
+            None => return,
+        };
+        if let Ok(slot) = slot {
+            if let Some(id) = self.define(name, Kind::Local, (name.end, scope_end)) {
+                env.slots.insert(slot, id);
+            }
+        }
+    }
+
+    fn refer_variable(&mut self, name: &Spanned, env: &LocalEnv) {
+        let str = match name.as_str() {
+            Some(str) => String::from(str),
+            None => return,
+        };
+        let target = match env.env.has_variable(&str) {
+            Some(slot) => env.slots.get(&slot).copied(),
+            None => self.globals.get(&str).copied(),
+        };
+        self.refer(name, target.into_iter().collect());
+    }
+
+    fn walk_function(&mut self, function: &Spanned, is_method: bool) {
+        let body = match function.get("body") {
+            Some(body) => body,
+            None => return,
+        };
+        let mut env = LocalEnv::new(body.end);
+        if is_method {
+            let _ = env.env.introduce_variable(String::from("this"));
+        }
+        for parameter in function.get("parameters").map_or(&[][..], |p| p.items()) {
+            let scope_end = body.end;
+            if let Some(str) = parameter.as_str() {
+                if let Ok(slot) = env.env.introduce_variable(String::from(str)) {
+                    if let Some(id) = self.define(parameter, Kind::Local, (body.start, scope_end)) {
+                        env.slots.insert(slot, id);
+                    }
+                }
+            }
+        }
+        self.walk(body, &mut env, false);
+    }
+
+    fn walk_all(&mut self, nodes: &[Spanned], env: &mut LocalEnv) {
+        for node in nodes.iter() {
+            self.walk(node, env, false);
+        }
+    }
+
+    fn walk_field(&mut self, content: &Spanned, key: &str, env: &mut LocalEnv) {
+        if let Some(node) = content.get(key) {
+            self.walk(node, env, false);
+        }
+    }
+
+    fn enter_scope(env: &mut LocalEnv, end: usize) {
+        env.env.enter_scope();
+        env.scope_ends.push(end);
+    }
+
+    fn leave_scope(env: &mut LocalEnv) {
+        let _ = env.env.leave_scope();
+        env.scope_ends.pop();
+    }
+
+    /**
+     * Resolves names in the node. Top level variables and functions are
+     * already defined as globals.
+     */
+    fn walk(&mut self, node: &Spanned, env: &mut LocalEnv, top: bool) {
+        let (variant, content) = match node.variant() {
+            Some(variant) => variant,
+            None => return,
+        };
+        match variant {
+            "Top" => {
+                for item in content.items() {
+                    self.walk(item, env, true);
+                }
+            }
+            "Variable" => {
+                self.walk_field(content, "value", env);
+                if !top {
+                    if let Some(name) = content.get("name") {
+                        self.define_local(name, env);
+                    }
+                }
+            }
+            "Function" if top => self.walk_function(content, false),
+            "Object" => {
+                self.walk_field(content, "extends", env);
+                for member in content.get("members").map_or(&[][..], |m| m.items()) {
+                    match member.variant() {
+                        Some(("Function", function)) => self.walk_function(function, true),
+                        Some(("Variable", variable)) => self.walk_field(variable, "value", env),
+                        _ => (),
+                    }
+                }
+            }
+            "AccessVariable" | "AssignVariable" => {
+                self.walk_field(content, "value", env);
+                if let Some(name) = content.get("name") {
+                    self.refer_variable(name, env);
+                }
+            }
+            "AccessField" | "AssignField" => {
+                self.walk_field(content, "object", env);
+                self.walk_field(content, "value", env);
+                if let Some(field) = content.get("field") {
+                    let key = (String::from(field.as_str().unwrap_or("")), false);
+                    match self.members.get(&key).cloned() {
+                        Some(targets) => self.refer(field, targets),
+                        None => self.undeclared_fields.push((field.start, field.end)),
+                    }
+                }
+            }
+            "CallFunction" => {
+                if let Some(name) = content.get("name") {
+                    let target = name.as_str().and_then(|name| self.globals.get(name));
+                    let target = target.copied().into_iter().collect();
+                    self.refer(name, target);
+                }
+                self.walk_all(content.get("arguments").map_or(&[], |a| a.items()), env);
+            }
+            "CallMethod" => {
+                self.walk_field(content, "object", env);
+                if let Some(name) = content.get("name") {
+                    let key = (String::from(name.as_str().unwrap_or("")), true);
+                    let targets = self.members.get(&key).cloned().unwrap_or_default();
+                    self.refer(name, targets);
+                }
+                self.walk_all(content.get("arguments").map_or(&[], |a| a.items()), env);
+            }
+            "Block" => {
+                Self::enter_scope(env, node.end);
+                self.walk_all(content.items(), env);
+                Self::leave_scope(env);
+            }
+            "For" | "ForEach" => {
+                self.walk_field(content, "from", env);
+                self.walk_field(content, "to", env);
+                self.walk_field(content, "array", env);
+                Self::enter_scope(env, node.end);
+                if let Some(variable) = content.get("variable") {
+                    self.define_local(variable, env);
+                }
+                self.walk_field(content, "body", env);
+                Self::leave_scope(env);
+            }
+            "Try" => {
+                self.walk_field(content, "body", env);
+                Self::enter_scope(env, content.get("handler").map_or(node.end, |h| h.end));
+                if let Some(variable) = content.get("catch_var") {
+                    self.define_local(variable, env);
+                }
+                self.walk_field(content, "handler", env);
+                Self::leave_scope(env);
+                self.walk_field(content, "finally", env);
+            }
+            _ => match &content.value {
+                // Everything else just contains other expressions
+                Json::Object(members) => {
+                    for (_, value) in members.iter() {
+                        match &value.value {
+                            Json::Array(items) => self.walk_all(items, env),
+                            _ => self.walk(value, env, false),
+                        }
+                    }
+                }
+                Json::Array(items) => self.walk_all(items, env),
+                _ => (),
+            },
+        }
+    }
+
+    fn reference_at(&self, offset: usize) -> Option<&Reference> {
+        self.references
+            .iter()
+            .find(|reference| reference.start <= offset && offset < reference.end)
+    }
+}
+
+/**
+ * Converts between byte offsets and LSP positions, which count lines
+ * and UTF-16 code units.
+ */
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+    json!({ "line": line, "character": character })
+}
+
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+    let line_start = text
+        .split_inclusive('\n')
+        .take(line)
+        .map(str::len)
+        .sum::<usize>();
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+/**
+ * Converts the line and column of a span, both counted from one, to a byte offset.
+ */
+fn span_offset(text: &str, line: u32, column: u32) -> usize {
+    let line_start = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1) as usize)
+        .map(str::len)
+        .sum::<usize>();
+    line_start + column.saturating_sub(1) as usize
+}
+
+fn range(text: &str, start: usize, end: usize) -> Value {
+    json!({ "start": position(text, start), "end": position(text, end) })
+}
+
+fn diagnostics(text: &str, analysis: Option<&Analysis>) -> Vec<Value> {
+    let tree = match JsonParser::parse(text) {
+        Ok(tree) => tree,
+        Err((offset, message)) => {
+            return vec![json!({
+                "range": range(text, offset, offset),
+                "severity": 1,
+                "message": message,
+            })]
+        }
+    };
+    let ast = match AST::from_json(text) {
+        Ok(ast) => ast,
+        Err(err) => {
+            let position = json!({ "line": err.line().saturating_sub(1), "character": err.column().saturating_sub(1) });
+            return vec![json!({
+                "range": { "start": position, "end": position },
+                "severity": 1,
+                "message": err.to_string(),
+            })];
+        }
+    };
+
+    let mut result = Vec::new();
+    // Spans point the error at the node which failed to compile.
+    let options = CompilerOptions {
+        debug: Some(Spans::new(text, &ast)),
+        ..CompilerOptions::default()
+    };
+    if let Err(error) = compile(&ast, &options) {
+        let (start, end) = match options.debug.as_ref().and_then(Spans::failed) {
+            Some(span) => (
+                span_offset(text, span.line, span.column),
+                span_offset(text, span.end_line, span.end_column),
+            ),
+            None => (tree.start, tree.start),
+        };
+        result.push(json!({
+            "range": range(text, start, end),
+            "severity": 1,
+            "message": error,
+        }));
+    }
+
+    if let Some(analysis) = analysis {
+        for (start, end) in analysis.undeclared_fields.iter() {
+            result.push(json!({
+                "range": range(text, *start, *end),
+                "severity": 2,
+                "message": format!("Field '{}' is not declared by any object.", &text[*start + 1..*end - 1]),
+            }));
+        }
+    }
+    result
+}
+
+struct Document {
+    text: String,
+    analysis: Option<Analysis>,
+}
+
+impl Document {
+    fn new(text: String) -> Self {
+        let analysis = JsonParser::parse(&text)
+            .ok()
+            .map(|tree| Analysis::new(&tree));
+        Document { text, analysis }
+    }
+
+    fn location(&self, uri: &Value, start: usize, end: usize) -> Value {
+        json!({ "uri": uri, "range": range(&self.text, start, end) })
+    }
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body).unwrap_or(Value::Null)))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/**
+ * Language server speaking LSP over stdin and stdout.
+ */
+pub fn serve() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    let mut documents: HashMap<String, Document> = HashMap::new();
+
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let uri = &params["textDocument"]["uri"];
+        let uri_key = uri.as_str().unwrap_or("").to_string();
+
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": "rfml" },
+            }),
+            "shutdown" => Value::Null,
+            "exit" => return Ok(()),
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let text = match method {
+                    "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+                    _ => params["contentChanges"][0]["text"].as_str(),
+                };
+                let document = Document::new(String::from(text.unwrap_or("")));
+                let diagnostics = diagnostics(&document.text, document.analysis.as_ref());
+                documents.insert(uri_key, document);
+                write_message(
+                    &mut output,
+                    &json!({
+                        "jsonrpc": "2.0",
+                        "method": "textDocument/publishDiagnostics",
+                        "params": { "uri": uri, "diagnostics": diagnostics },
+                    }),
+                )?;
+                continue;
+            }
+            "textDocument/didClose" => {
+                documents.remove(&uri_key);
+                continue;
+            }
+            "textDocument/definition"
+            | "textDocument/references"
+            | "textDocument/hover"
+            | "textDocument/completion" => match documents.get(&uri_key) {
+                Some(Document {
+                    text,
+                    analysis: Some(analysis),
+                }) => {
+                    let document = &documents[&uri_key];
+                    let offset = offset(text, &params["position"]);
+                    let targets = analysis
+                        .reference_at(offset)
+                        .map_or(vec![], |reference| reference.targets.clone());
+                    match method {
+                        "textDocument/definition" => targets
+                            .iter()
+                            .map(|id| {
+                                let definition = &analysis.definitions[*id];
+                                document.location(uri, definition.start, definition.end)
+                            })
+                            .collect(),
+                        "textDocument/references" => analysis
+                            .references
+                            .iter()
+                            .filter(|reference| {
+                                reference.targets.iter().any(|id| targets.contains(id))
+                            })
+                            .map(|reference| document.location(uri, reference.start, reference.end))
+                            .collect(),
+                        "textDocument/hover" => match targets.first() {
+                            Some(id) => {
+                                let definition = &analysis.definitions[*id];
+                                let description = match (&definition.parameters, definition.kind) {
+                                    (Some(parameters), kind) => format!(
+                                        "{} {}({}), arity {}",
+                                        if kind == Kind::Method {
+                                            "method"
+                                        } else {
+                                            "function"
+                                        },
+                                        definition.name,
+                                        parameters.join(", "),
+                                        parameters.len()
+                                    ),
+                                    (None, Kind::Global) => format!("global {}", definition.name),
+                                    (None, Kind::Field) => format!("field {}", definition.name),
+                                    (None, _) => format!("local {}", definition.name),
+                                };
+                                json!({ "contents": { "kind": "plaintext", "value": description } })
+                            }
+                            None => Value::Null,
+                        },
+                        _ => {
+                            let mut names: Vec<&Definition> = analysis
+                                .definitions
+                                .iter()
+                                .filter(|definition| {
+                                    matches!(definition.kind, Kind::Global | Kind::Function)
+                                        || (definition.kind == Kind::Local
+                                            && definition.visible.0 <= offset
+                                            && offset <= definition.visible.1)
+                                })
+                                .collect();
+                            names.sort_by(|a, b| a.name.cmp(&b.name));
+                            names.dedup_by(|a, b| a.name == b.name);
+                            names
+                                .iter()
+                                .map(|definition| {
+                                    let kind = match definition.kind {
+                                        Kind::Function => 3,
+                                        _ => 6,
+                                    };
+                                    json!({ "label": definition.name, "kind": kind })
+                                })
+                                .collect()
+                        }
+                    }
+                }
+                _ => Value::Null,
+            },
+            _ => Value::Null,
+        };
+
+        // Notifications don't get a response
+        if !message["id"].is_null() {
+            write_message(
+                &mut output,
+                &json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn analysis_test() {
+        let text = r#"{"Top": [
+            {"Function": {"name": "f", "parameters": ["x"], "body":
+                {"Block": [{"Variable": {"name": "y", "value": {"AccessVariable": {"name": "x"}}}},
+                           {"AccessVariable": {"name": "y"}}]}}},
+            {"CallFunction": {"name": "f", "arguments": [{"Integer": 1}]}}
+        ]}"#;
+        let tree = JsonParser::parse(text).unwrap();
+        let analysis = Analysis::new(&tree);
+
+        let definition_of = |needle: &str, nth: usize| {
+            let offset = text.match_indices(needle).nth(nth).unwrap().0 + 1;
+            let targets = &analysis.reference_at(offset).unwrap().targets;
+            let definition = &analysis.definitions[targets[0]];
+            (&text[definition.start..definition.end], definition.kind)
+        };
+        // The call refers to the function, uses of x and y to the locals
+        assert_eq!(definition_of("\"f\"", 1), ("\"f\"", Kind::Function));
+        assert_eq!(definition_of("\"x\"", 1).1, Kind::Local);
+        assert_eq!(definition_of("\"y\"", 1).1, Kind::Local);
+
+        let position = position(text, text.len());
+        assert_eq!(offset(text, &position), text.len());
+    }
+
+    #[test]
+    fn diagnostics_test() {
+        let text = r#"{"Top": [
+            {"Function": {"name": "f", "parameters": [], "body":
+                {"Block": [{"Variable": {"name": "y", "value": {"Integer": 1}}},
+                           {"Variable": {"name": "y", "value": {"Integer": 2}}}]}}}
+        ]}"#;
+        let diagnostics = diagnostics(text, None);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["message"], "Variable already exists.");
+        // The error points at the second definition, not at the whole program
+        let start = text.match_indices(r#"{"Variable""#).nth(1).unwrap().0;
+        assert_eq!(diagnostics[0]["range"]["start"], position(text, start));
+    }
+}
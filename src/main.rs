@@ -1,26 +1,255 @@
+pub mod assembler;
 pub mod ast;
 pub mod bytecode;
 pub mod compiler;
 pub mod constants;
 pub mod debug;
+pub mod decompiler;
+pub mod graph;
+pub mod interpreter;
+pub mod json;
+pub mod linker;
+pub mod lsp;
+pub mod optimizer;
+pub mod parser;
+pub mod prelude;
+pub mod printer;
+pub mod program;
+pub mod repl;
 pub mod serializer;
 
-use ast::AST;
-use compiler::compile;
+use ast::{IntoBoxed, AST};
+use compiler::{compile, undeclared_fields, CompilerOptions, FieldUsage, StreamingCompiler};
+use debug::Spans;
+use interpreter::Interpreter;
+use linker::{link, load_modules};
+use program::Program;
+use serializer::{Deserializable, Serializable};
 use std::env;
 use std::fs;
+use std::io;
+use std::path::Path;
+
+/**
+ * Writes the program to stdout, with the header unless the headerless layout
+ * of the standard FML runtimes is requested.
+ */
+fn write_program(program: &Program, flags: &[String]) -> io::Result<()> {
+    match flags.iter().any(|arg| arg == "--raw") {
+        true => program.serializable_byte(&mut io::stdout()),
+        false => program.serialize_with_header(&mut io::stdout()),
+    }
+}
+
+/**
+ * Compiles the program item by item while it's being read, for inputs too large
+ * to be kept in memory. Imports aren't supported and the debug information has
+ * no line tables, those need the whole program.
+ */
+fn compile_streamed(path: &str, flags: &[String]) -> io::Result<Program> {
+    let options = CompilerOptions {
+        branch_false: flags.iter().any(|arg| arg == "--branch-false"),
+        operators: flags.iter().any(|arg| arg == "--operators"),
+        superinstructions: flags.iter().any(|arg| arg == "--superinstructions"),
+        debug: flags.iter().any(|arg| arg == "-g").then(Spans::default),
+    };
+    let mut compiler = StreamingCompiler::new(&options);
+    let mut usage = prelude::Usage::default();
+    let mut fields = FieldUsage::default();
+    let file = fs::File::open(path)?;
+    AST::for_each_item(io::BufReader::new(file), |item| {
+        usage.add_item(item);
+        fields.add_item(item);
+        compiler.compile_item(item).map_err(String::from)
+    })
+    .unwrap_or_else(|err| panic!("Compilation of '{}' failed: {}", path, err));
+
+    // Functions are globals, the prelude ones can come after the program.
+    if !flags.iter().any(|arg| arg == "--no-prelude") {
+        for function in usage.used_functions() {
+            fields.add_item(&function);
+            compiler
+                .compile_item(&function)
+                .unwrap_or_else(|err| panic!("Compilation of the prelude failed: {}", err));
+        }
+    }
+
+    if flags.iter().any(|arg| arg == "--warn-fields") {
+        for field in fields.undeclared() {
+            eprintln!("Warning: Field '{}' is not declared by any object.", field);
+        }
+    }
+    Ok(compiler.finish())
+}
+
+/**
+ * Applies the optional passes to the compiled program and writes it.
+ */
+fn write_compiled(program: Program, flags: &[String]) -> io::Result<()> {
+    let program = match flags.iter().any(|arg| arg == "--strip-dead") {
+        true => optimizer::eliminate_dead_code(&program),
+        false => program,
+    };
+    let program = match flags.iter().any(|arg| arg == "--resolve-labels") {
+        true => optimizer::resolve_labels(&program)
+            .unwrap_or_else(|err| panic!("Resolving labels failed: {}", err)),
+        false => program,
+    };
+    write_program(&program, flags)
+}
 
 fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
+    if args.len() == 2 && args[1] == "repl" {
+        return repl::repl();
+    }
+    if args.len() == 2 && args[1] == "lsp" {
+        return lsp::serve();
+    }
     if args.len() < 3 {
-        panic!("Usage: fml command file");
+        panic!("Usage: fml command file [-g] [--raw] [--warn-fields] [--branch-false] [--operators] [--superinstructions] [--strip-dead] [--resolve-labels] [--no-prelude] [--stream]");
     }
 
-    if args[1] == "compile" {
-        let program = fs::read_to_string(&args[2]).unwrap();
-        let tree: AST = serde_json::from_str(&program).unwrap();
-        compile(&tree)
+    if args[1] == "compile" && args[3..].iter().any(|arg| arg == "--stream") {
+        let program = compile_streamed(&args[2], &args[3..])?;
+        write_compiled(program, &args[3..])
+    } else if args[1] == "compile" {
+        let flags = &args[3..];
+        let mut modules =
+            load_modules(Path::new(&args[2])).unwrap_or_else(|err| panic!("{}", err));
+        // Spans are matched with the trees before the prelude is added to them.
+        let debug = flags.iter().any(|arg| arg == "-g");
+        let mut spans = Vec::new();
+        for (path, tree) in modules.iter() {
+            spans.push(match debug {
+                true => Some(Spans::new(&fs::read_to_string(path)?, tree)),
+                false => None,
+            });
+        }
+        if !flags.iter().any(|arg| arg == "--no-prelude") {
+            let trees: Vec<&AST> = modules.iter().map(|(_, tree)| tree).collect();
+            let functions = prelude::used_functions(&trees);
+            // The first module doesn't import anything, so it's initialized first.
+            if let (_, AST::Top(items)) = &mut modules[0] {
+                items.splice(0..0, functions);
+            }
+        }
+        let mut programs = Vec::new();
+        for ((path, tree), spans) in modules.iter().zip(spans) {
+            let options = CompilerOptions {
+                branch_false: flags.iter().any(|arg| arg == "--branch-false"),
+                operators: flags.iter().any(|arg| arg == "--operators"),
+                superinstructions: flags.iter().any(|arg| arg == "--superinstructions"),
+                debug: spans,
+            };
+            let program = compile(tree, &options).unwrap_or_else(|err| {
+                panic!("Compilation of '{}' failed: {}", path.display(), err)
+            });
+            programs.push(program);
+        }
+
+        if flags.iter().any(|arg| arg == "--warn-fields") {
+            let tree = AST::Top(modules.into_iter().map(|(_, tree)| tree.into_boxed()).collect());
+            for field in undeclared_fields(&tree) {
+                eprintln!("Warning: Field '{}' is not declared by any object.", field);
+            }
+        }
+
+        let program = match programs.len() {
+            1 => programs.pop().unwrap(),
+            _ => link(&programs).unwrap_or_else(|err| panic!("Linking failed: {}", err)),
+        };
+        write_compiled(program, flags)
+    } else if args[1] == "link" {
+        let mut programs = Vec::new();
+        for path in args[2..].iter().filter(|arg| !arg.starts_with("--")) {
+            let mut file = fs::File::open(path)?;
+            programs.push(Program::deserialize(&mut file)?);
+        }
+        let program = link(&programs).unwrap_or_else(|err| panic!("Linking failed: {}", err));
+        write_program(&program, &args[2..])
+    } else if args[1] == "fmt" {
+        // Sources are parsed and printed back in place, the check only reports
+        // the ones which would change.
+        let check = args[2..].iter().any(|arg| arg == "--check");
+        let mut outdated = false;
+        for path in args[2..].iter().filter(|arg| *arg != "--check") {
+            let text = fs::read_to_string(path)?;
+            let tree = parser::parse(&text).unwrap_or_else(|(offset, message)| {
+                let line = text[..offset].matches('\n').count() + 1;
+                panic!("Parsing of '{}' failed on line {}: {}", path, line, message)
+            });
+            let source = printer::to_source(&tree);
+            if source == text {
+                continue;
+            }
+            if check {
+                eprintln!("'{}' is not formatted.", path);
+                outdated = true;
+            } else {
+                fs::write(path, source)?;
+            }
+        }
+        if outdated {
+            std::process::exit(1);
+        }
+        Ok(())
+    } else if args[1] == "assemble" {
+        let text = fs::read_to_string(&args[2])?;
+        let program = assembler::assemble(&text)
+            .unwrap_or_else(|err| panic!("Assembling of '{}' failed: {}", args[2], err));
+        write_program(&program, &args[3..])
+    } else if args[1] == "disassemble" {
+        let mut file = fs::File::open(&args[2])?;
+        let program = Program::deserialize(&mut file)?;
+        print!("{}", debug::disassemble(&program));
+        Ok(())
+    } else if args[1] == "decompile" {
+        let mut file = fs::File::open(&args[2])?;
+        let program = Program::deserialize(&mut file)?;
+        let tree = decompiler::decompile(&program)
+            .unwrap_or_else(|err| panic!("Decompilation of '{}' failed: {}", args[2], err));
+        match args[3..].iter().any(|arg| arg == "--json") {
+            true => println!("{}", tree.to_json()?),
+            false => print!("{}", printer::to_source(&tree)),
+        }
+        Ok(())
+    } else if args[1] == "cfg" || args[1] == "callgraph" {
+        let mut file = fs::File::open(&args[2])?;
+        let program = Program::deserialize(&mut file)?;
+        match args[1].as_str() {
+            "cfg" => print!("{}", graph::cfg(&program)),
+            _ => print!("{}", graph::callgraph(&program)),
+        }
+        Ok(())
+    } else if args[1] == "ngrams" {
+        // Length of the counted sequences is optional, pairs by default
+        let mut file = fs::File::open(&args[2])?;
+        let program = Program::deserialize(&mut file)?;
+        let length = match args.get(3) {
+            Some(length) => length
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid length '{}'.", length)),
+            None => 2,
+        };
+        for (sequence, count) in debug::ngrams(&program, length) {
+            println!("{}\t{}", count, sequence);
+        }
+        Ok(())
+    } else if args[1] == "run" {
+        let mut file = fs::File::open(&args[2])?;
+        let program = Program::deserialize(&mut file)?;
+        let mut interpreter = Interpreter::new(io::stdout());
+        interpreter.load_globals(&program.pool, &program.globals.globals);
+        if let Err(exception) = interpreter.run(&program.pool, program.entry) {
+            eprintln!("Uncaught exception: {}", exception);
+            std::process::exit(1);
+        }
+        Ok(())
     } else {
-        panic!("Following commands are supported: 'compile', received '{}'", args[1])
+        panic!(
+            "Following commands are supported: 'compile', 'link', 'assemble', 'disassemble', 'decompile', 'cfg', 'callgraph', 'ngrams', 'fmt', 'run', 'repl', 'lsp', received '{}'",
+            args[1]
+        )
     }
 }
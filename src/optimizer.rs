@@ -0,0 +1,587 @@
+use crate::bytecode::*;
+use crate::compiler::Globals;
+use crate::constants::{Constant, ConstantPool, ConstantPoolIndex};
+use crate::debug::LabelName;
+use crate::program::Program;
+use std::collections::{HashMap, HashSet};
+
+/**
+ * Fuses the sequences of counters and loop conditions, the most frequent ones
+ * according to `debug::ngrams`, into superinstructions. Each replaces only the first
+ * instruction of its sequence, so positions don't change and the rest of the
+ * sequence is still there for operands which aren't integers.
+ */
+pub fn use_superinstructions(pool: &mut ConstantPool) {
+    let mut methods: HashMap<ConstantPoolIndex, Bytecode> = HashMap::new();
+    let mut integers = HashSet::new();
+    for (index, constant) in pool.iter().enumerate() {
+        let index = index as ConstantPoolIndex;
+        match constant {
+            Constant::String(name) if name == "+" || name == "<" => {
+                methods.insert(index, Bytecode::operator(name).unwrap());
+            }
+            Constant::Integer(_) | Constant::Long(_) => {
+                integers.insert(index);
+            }
+            _ => (),
+        }
+    }
+    // Calls of the operator methods, or the operator instructions
+    let operator = |inst: Bytecode| match inst {
+        Bytecode::CallMethod { name, arguments: 2 } => methods.get(&name).copied(),
+        Bytecode::Add | Bytecode::Lt => Some(inst),
+        _ => None,
+    };
+
+    for code in pool.codes_mut() {
+        let insts = &mut code.insert_point;
+        for i in 0..insts.len() {
+            match insts[i..] {
+                [Bytecode::GetLocal { index }, Bytecode::Literal { index: amount }, add, Bytecode::SetLocal { index: target }, Bytecode::Drop, ..]
+                    if index == target
+                        && integers.contains(&amount)
+                        && operator(add) == Some(Bytecode::Add) =>
+                {
+                    insts[i] = Bytecode::IncLocal { index, amount };
+                }
+                [Bytecode::GetLocal { index: left }, Bytecode::GetLocal { index: right }, less, Bytecode::Branch { .. } | Bytecode::BranchTo { .. }, ..]
+                    if operator(less) == Some(Bytecode::Lt) =>
+                {
+                    insts[i] = Bytecode::CompareLocalsBranch { left, right };
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/**
+ * Replaces calls of the binary operator methods, such as `CallMethod "+" 2`,
+ * with the specialized instructions, which skip the method lookup when both
+ * operands are primitive. Instructions are replaced one for one, so positions
+ * don't change.
+ */
+pub fn use_operators(pool: &mut ConstantPool) {
+    let operators: HashMap<ConstantPoolIndex, Bytecode> = pool
+        .iter()
+        .enumerate()
+        .filter_map(|(index, constant)| match constant {
+            Constant::String(name) => Some((index as ConstantPoolIndex, Bytecode::operator(name)?)),
+            _ => None,
+        })
+        .collect();
+    for code in pool.codes_mut() {
+        for inst in code.insert_point.iter_mut() {
+            if let Bytecode::CallMethod { name, arguments: 2 } = inst {
+                if let Some(operator) = operators.get(name) {
+                    *inst = *operator;
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Returns the name of the global which the instruction refers to, if any.
+ */
+fn global_reference(inst: &Bytecode) -> Option<ConstantPoolIndex> {
+    match inst {
+        Bytecode::CallFunction { name, .. }
+        | Bytecode::GetGlobal { name }
+        | Bytecode::SetGlobal { name } => Some(*name),
+        _ => None,
+    }
+}
+
+/**
+ * Marks the constants reachable from the roots. Globals are reached by the names
+ * of called functions and accessed variables.
+ */
+fn mark(program: &Program, roots: Vec<ConstantPoolIndex>) -> Vec<bool> {
+    let pool = &program.pool;
+    let mut globals: HashMap<&str, Vec<ConstantPoolIndex>> = HashMap::new();
+    for global in program.globals.globals.iter() {
+        let name = match pool.get(*global) {
+            Constant::Slot { name } | Constant::Function { name, .. } => pool.get(*name),
+            _ => continue,
+        };
+        if let Constant::String(name) = name {
+            globals.entry(name.as_str()).or_default().push(*global);
+        }
+    }
+
+    let mut reachable = vec![false; pool.len().into()];
+    let mut worklist = roots;
+    while let Some(index) = worklist.pop() {
+        if std::mem::replace(&mut reachable[usize::from(index)], true) {
+            continue;
+        }
+        match pool.get(index) {
+            Constant::Slot { name } => worklist.push(*name),
+            Constant::Object { members } => worklist.extend(members),
+            Constant::Function { name, code, .. } => {
+                worklist.push(*name);
+                for inst in code.insert_point.iter() {
+                    inst.map_indices(|index, _| {
+                        worklist.push(index);
+                        index
+                    });
+                    let name = global_reference(inst).map(|name| pool.get(name));
+                    if let Some(Constant::String(name)) = name {
+                        worklist.extend(globals.get(name.as_str()).into_iter().flatten());
+                    }
+                }
+                for handler in code.handlers.iter() {
+                    worklist.extend([handler.start, handler.end, handler.handler]);
+                }
+            }
+            _ => (),
+        }
+    }
+    reachable
+}
+
+/**
+ * Removes the constants which are not marked, renumbering the rest of the pool.
+ */
+fn compact(program: &Program, reachable: &[bool]) -> Program {
+    // Kept constants preserve their order
+    let mut map = Vec::with_capacity(reachable.len());
+    let mut count: ConstantPoolIndex = 0;
+    for reachable in reachable.iter() {
+        map.push(count);
+        count += *reachable as ConstantPoolIndex;
+    }
+    let map = |index: ConstantPoolIndex| map[usize::from(index)];
+
+    let mut compacted = ConstantPool::new();
+    for (index, constant) in program.pool.iter().enumerate() {
+        if !reachable[index] {
+            continue;
+        }
+        compacted.add(match constant {
+            Constant::Slot { name } => Constant::Slot { name: map(*name) },
+            Constant::Object { members } => Constant::Object {
+                members: members.iter().map(|member| map(*member)).collect(),
+            },
+            Constant::Function {
+                name,
+                parameters,
+                locals,
+                code,
+            } => {
+                let mut code = code.clone();
+                for inst in code.insert_point.iter_mut() {
+                    *inst = inst.map_indices(|index, _| map(index));
+                }
+                for handler in code.handlers.iter_mut() {
+                    handler.start = map(handler.start);
+                    handler.end = map(handler.end);
+                    handler.handler = map(handler.handler);
+                }
+                Constant::Function {
+                    name: map(*name),
+                    parameters: *parameters,
+                    locals: *locals,
+                    code,
+                }
+            }
+            constant => constant.clone(),
+        });
+    }
+
+    let mut kept = Globals::new();
+    for global in program.globals.globals.iter() {
+        if reachable[usize::from(*global)] {
+            kept.introduce_variable(map(*global));
+        }
+    }
+    Program {
+        pool: compacted,
+        globals: kept,
+        entry: map(program.entry),
+    }
+}
+
+/**
+ * Removes global functions which are not reachable from the entry point and
+ * constants which are not referred to, renumbering the rest of the pool.
+ * All members of created objects are kept, because methods are called by name.
+ */
+pub fn eliminate_dead_code(program: &Program) -> Program {
+    compact(program, &mark(program, vec![program.entry]))
+}
+
+/**
+ * Removes the labels from the code, replacing jumps and exception handlers
+ * with ones which use instruction offsets. Names of the labels are kept in
+ * the debug information, if it's requested.
+ */
+fn resolve_code(code: &mut Code, pool: &ConstantPool, debug: bool) -> Result<(), String> {
+    let mut insts = Vec::with_capacity(code.insert_point.len());
+    // New positions of the instructions, for the debug information
+    let mut positions = Vec::with_capacity(code.insert_point.len() + 1);
+    let mut targets = HashMap::new();
+    for inst in code.insert_point.iter() {
+        let position = insts.len() as u32;
+        positions.push(position);
+        match inst {
+            Bytecode::Label { name } => {
+                targets.insert(*name, position);
+                if debug {
+                    code.labels.push(LabelName {
+                        instruction: position,
+                        name: match pool.get(*name) {
+                            Constant::String(name) => name.clone(),
+                            _ => format!("#{}", name),
+                        },
+                    });
+                }
+            }
+            inst => insts.push(*inst),
+        }
+    }
+    positions.push(insts.len() as u32);
+
+    let target = |label: ConstantPoolIndex| {
+        targets
+            .get(&label)
+            .copied()
+            .ok_or_else(|| format!("Label #{} is not defined.", label))
+    };
+    for inst in insts.iter_mut() {
+        *inst = match *inst {
+            Bytecode::Jump { label } => Bytecode::JumpTo {
+                target: target(label)?,
+            },
+            Bytecode::Branch { label } => Bytecode::BranchTo {
+                target: target(label)?,
+            },
+            Bytecode::BranchFalse { label } => Bytecode::BranchFalseTo {
+                target: target(label)?,
+            },
+            inst => inst,
+        };
+    }
+    for handler in code.handlers.drain(..) {
+        code.resolved_handlers.push(ResolvedHandler {
+            start: target(handler.start)?,
+            end: target(handler.end)?,
+            handler: target(handler.handler)?,
+            stack: handler.stack,
+        });
+    }
+
+    // Label names are already at their new positions
+    let labels = std::mem::take(&mut code.labels);
+    code.insert_point = insts;
+    code.map_positions(|position| positions[position as usize]);
+    code.labels = labels;
+    Ok(())
+}
+
+/**
+ * Resolves the labels of all functions to instruction offsets and removes
+ * the names of the labels from the constant pool. Programs with resolved
+ * labels can't be linked anymore.
+ */
+pub fn resolve_labels(program: &Program) -> Result<Program, String> {
+    let debug = program.has_debug_info();
+    let mut pool = ConstantPool::new();
+    for constant in program.pool.iter() {
+        pool.add(constant.clone());
+    }
+    for code in pool.codes_mut() {
+        resolve_code(code, &program.pool, debug)?;
+    }
+    let resolved = Program {
+        pool,
+        globals: Globals {
+            globals: program.globals.globals.clone(),
+        },
+        entry: program.entry,
+    };
+
+    // Only the strings which were used as labels are unreachable now.
+    let roots = (0..resolved.pool.len())
+        .filter(|index| !matches!(resolved.pool.get(*index), Constant::String(_)))
+        .collect();
+    Ok(compact(&resolved, &mark(&resolved, roots)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_code_test() {
+        use crate::ast::AST;
+        use crate::compiler::{compile, CompilerOptions};
+
+        let ast: AST = serde_json::from_str(
+            r#"{"Top": [
+                {"Function": {"name": "used", "parameters": [], "body": {"AccessVariable": {"name": "x"}}}},
+                {"Function": {"name": "dead", "parameters": [], "body": {"String": "unused"}}},
+                {"Variable": {"name": "x", "value": {"Integer": 42}}},
+                {"Print": {"format": "~\\n", "arguments": [{"CallFunction": {"name": "used", "arguments": []}}]}}
+            ]}"#,
+        )
+        .unwrap();
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+        let stripped = eliminate_dead_code(&program);
+
+        let has_string = |pool: &ConstantPool, string: &str| {
+            pool.iter()
+                .any(|constant| matches!(constant, Constant::String(s) if s == string))
+        };
+        assert!(has_string(&program.pool, "dead") && has_string(&program.pool, "unused"));
+        assert!(!has_string(&stripped.pool, "dead") && !has_string(&stripped.pool, "unused"));
+        assert!(has_string(&stripped.pool, "used") && has_string(&stripped.pool, "x"));
+        assert_eq!(stripped.globals.len(), 2);
+        assert!(matches!(
+            stripped.pool.get(stripped.entry),
+            Constant::Function { .. }
+        ));
+
+        let mut output = Vec::new();
+        let mut interpreter = crate::interpreter::Interpreter::new(&mut output);
+        interpreter.load_globals(&stripped.pool, &stripped.globals.globals);
+        interpreter.run(&stripped.pool, stripped.entry).unwrap();
+        drop(interpreter);
+        assert_eq!(String::from_utf8(output).unwrap(), "42\n");
+    }
+
+    #[test]
+    fn resolve_labels_test() {
+        use crate::ast::AST;
+        use crate::compiler::{compile, CompilerOptions};
+        use crate::debug::Spans;
+        use crate::serializer::Deserializable;
+
+        let text = r#"{"Top": [
+            {"Variable": {"name": "i", "value": {"Integer": 0}}},
+            {"Loop": {"condition": {"CallMethod": {"object": {"AccessVariable": {"name": "i"}},
+                "name": "<", "arguments": [{"Integer": 3}]}},
+                "body": {"Try": {
+                    "body": {"Conditional": {"condition": {"CallMethod": {
+                        "object": {"AccessVariable": {"name": "i"}}, "name": "==",
+                        "arguments": [{"Integer": 1}]}},
+                        "consequent": {"Throw": {"value": {"AccessVariable": {"name": "i"}}}},
+                        "alternative": {"Print": {"format": "~\\n", "arguments": [{"AccessVariable": {"name": "i"}}]}}}},
+                    "catch_var": "e",
+                    "handler": {"Print": {"format": "caught ~\\n", "arguments": [{"AccessVariable": {"name": "e"}}]}},
+                    "finally": {"AssignVariable": {"name": "i", "value": {"CallMethod": {
+                        "object": {"AccessVariable": {"name": "i"}}, "name": "+",
+                        "arguments": [{"Integer": 1}]}}}}}}}}
+        ]}"#;
+        let ast: AST = serde_json::from_str(text).unwrap();
+        let options = CompilerOptions {
+            branch_false: false,
+            operators: false,
+            superinstructions: false,
+            debug: Some(Spans::new(text, &ast)),
+        };
+        let program = compile(&ast, &options).unwrap();
+        let resolved = resolve_labels(&program).unwrap();
+
+        let run = |program: &Program| {
+            let mut output = Vec::new();
+            let mut interpreter = crate::interpreter::Interpreter::new(&mut output);
+            interpreter.load_globals(&program.pool, &program.globals.globals);
+            interpreter.run(&program.pool, program.entry).unwrap();
+            drop(interpreter);
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(run(&resolved), "0\ncaught 1\n2\n");
+        assert_eq!(run(&resolved), run(&program));
+
+        assert!(resolved.pool.len() < program.pool.len());
+        let code = match resolved.pool.get(resolved.entry) {
+            Constant::Function { code, .. } => code,
+            _ => unreachable!(),
+        };
+        assert!(code.handlers.is_empty() && code.resolved_handlers.len() == 2);
+        assert!(!code
+            .insert_point
+            .iter()
+            .any(|inst| matches!(inst, Bytecode::Label { .. } | Bytecode::Jump { .. })));
+        assert!(code
+            .labels
+            .iter()
+            .any(|label| label.name.starts_with("try_start")));
+
+        // Label names survive in the debug section
+        let mut bytes = Vec::new();
+        resolved.serialize_with_header(&mut bytes).unwrap();
+        let read = Program::deserialize(&mut bytes.as_slice()).unwrap();
+        let mut again = Vec::new();
+        read.serialize_with_header(&mut again).unwrap();
+        assert_eq!(again, bytes);
+        assert_eq!(run(&read), run(&program));
+    }
+
+    #[test]
+    fn operators_test() {
+        use crate::ast::AST;
+        use crate::compiler::{compile, CompilerOptions};
+        use crate::serializer::Deserializable;
+
+        let call = |object: &str, name: &str, argument: &str| {
+            format!(
+                r#"{{"CallMethod": {{"object": {}, "name": "{}", "arguments": [{}]}}}}"#,
+                object, name, argument
+            )
+        };
+        let print = |value: String| {
+            format!(
+                r#"{{"Print": {{"format": "~\n", "arguments": [{}]}}}}"#,
+                value
+            )
+        };
+        let mut items = vec![String::from(
+            r#"{"Variable": {"name": "o", "value": {"Object": {"extends": "Null", "members": [
+                {"Variable": {"name": "x", "value": {"Integer": 1}}},
+                {"Function": {"name": "+", "parameters": ["other"], "body": {"CallMethod": {
+                    "object": {"AccessField": {"object": {"AccessVariable": {"name": "this"}}, "field": "x"}},
+                    "name": "+", "arguments": [{"AccessVariable": {"name": "other"}}]}}}}
+            ]}}}}"#,
+        )];
+        for name in ["+", "-", "*", "/", "%", "<", "<=", ">", ">=", "==", "!="] {
+            items.push(print(call(r#"{"Integer": 7}"#, name, r#"{"Integer": 2}"#)));
+            items.push(print(call(r#"{"Float": 7.5}"#, name, r#"{"Integer": 2}"#)));
+        }
+        items.push(print(call(r#"{"String": "a"}"#, "+", r#"{"Integer": 1}"#)));
+        items.push(print(call(r#"{"Boolean": true}"#, "==", r#""Null""#)));
+        items.push(print(call(
+            r#"{"AccessVariable": {"name": "o"}}"#,
+            "+",
+            r#"{"Integer": 41}"#,
+        )));
+        items.push(print(call(
+            r#"{"AccessVariable": {"name": "o"}}"#,
+            "==",
+            r#"{"AccessVariable": {"name": "o"}}"#,
+        )));
+        let ast: AST =
+            serde_json::from_str(&format!(r#"{{"Top": [{}]}}"#, items.join(", "))).unwrap();
+
+        let run = |program: &Program| {
+            let mut output = Vec::new();
+            let mut interpreter = crate::interpreter::Interpreter::new(&mut output);
+            interpreter.load_globals(&program.pool, &program.globals.globals);
+            interpreter.run(&program.pool, program.entry).unwrap();
+            drop(interpreter);
+            String::from_utf8(output).unwrap()
+        };
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+        let options = CompilerOptions {
+            operators: true,
+            ..CompilerOptions::default()
+        };
+        let specialized = compile(&ast, &options).unwrap();
+        assert_eq!(run(&specialized), run(&program));
+        assert_eq!(
+            run(&program),
+            "9\n9.5\n5\n5.5\n14\n15.0\n3\n3.75\n1\n1.5\nfalse\nfalse\nfalse\nfalse\n\
+             true\ntrue\ntrue\ntrue\nfalse\nfalse\ntrue\ntrue\na1\nfalse\n42\ntrue\n"
+        );
+
+        // The method of the object calls '+' on its field, the main function has the rest.
+        let operators = specialized
+            .pool
+            .iter()
+            .filter_map(|constant| match constant {
+                Constant::Function { code, .. } => Some(code.insert_point.iter()),
+                _ => None,
+            })
+            .flatten()
+            .filter(|inst| inst.operator_name().is_some())
+            .count();
+        assert_eq!(operators, 2 * 11 + 5);
+
+        let mut bytes = Vec::new();
+        specialized.serialize_with_header(&mut bytes).unwrap();
+        let read = Program::deserialize(&mut bytes.as_slice()).unwrap();
+        assert_eq!(run(&read), run(&program));
+    }
+
+    #[test]
+    fn superinstructions_test() {
+        use crate::ast::AST;
+        use crate::compiler::{compile, CompilerOptions};
+
+        // Integers take the fast path, the float and the object the slow one.
+        let text = r#"{"Top": [
+            {"Function": {"name": "f", "parameters": ["n"], "body": {"Block": [
+                {"Variable": {"name": "s", "value": {"Integer": 0}}},
+                {"For": {"variable": "i", "from": {"Integer": 0}, "to": {"AccessVariable": {"name": "n"}},
+                    "body": {"AssignVariable": {"name": "s", "value": {"CallMethod": {
+                        "object": {"AccessVariable": {"name": "s"}}, "name": "+",
+                        "arguments": [{"AccessVariable": {"name": "i"}}]}}}}}},
+                {"Variable": {"name": "c", "value": {"Float": 0.5}}},
+                {"Variable": {"name": "k", "value": {"Integer": 0}}},
+                {"Loop": {"condition": {"CallMethod": {"object": {"AccessVariable": {"name": "c"}},
+                    "name": "<", "arguments": [{"AccessVariable": {"name": "n"}}]}},
+                    "body": {"Block": [
+                        {"AssignVariable": {"name": "c", "value": {"CallMethod": {
+                            "object": {"AccessVariable": {"name": "c"}}, "name": "+",
+                            "arguments": [{"Integer": 1}]}}}},
+                        {"AssignVariable": {"name": "k", "value": {"CallMethod": {
+                            "object": {"AccessVariable": {"name": "k"}}, "name": "+",
+                            "arguments": [{"Integer": 1}]}}}}]}}},
+                {"Variable": {"name": "o", "value": {"Object": {"extends": "Null", "members": [
+                    {"Function": {"name": "+", "parameters": ["x"], "body": {"Integer": 42}}}]}}}},
+                {"AssignVariable": {"name": "o", "value": {"CallMethod": {
+                    "object": {"AccessVariable": {"name": "o"}}, "name": "+",
+                    "arguments": [{"Integer": 1}]}}}},
+                {"Print": {"format": "~ ~ ~ ~\n", "arguments": [{"AccessVariable": {"name": "s"}},
+                    {"AccessVariable": {"name": "c"}}, {"AccessVariable": {"name": "k"}},
+                    {"AccessVariable": {"name": "o"}}]}}
+            ]}}},
+            {"CallFunction": {"name": "f", "arguments": [{"Integer": 10}]}}
+        ]}"#;
+        let ast: AST = serde_json::from_str(text).unwrap();
+
+        let run = |program: &Program| {
+            let mut output = Vec::new();
+            let mut interpreter = crate::interpreter::Interpreter::new(&mut output);
+            interpreter.load_globals(&program.pool, &program.globals.globals);
+            interpreter.run(&program.pool, program.entry).unwrap();
+            drop(interpreter);
+            String::from_utf8(output).unwrap()
+        };
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+        assert_eq!(run(&program), "45 10.5 10 42\n");
+
+        let counter = "get_local; literal; call_method \"+\" 2; set_local; drop";
+        let counters = crate::debug::ngrams(&program, 5)
+            .into_iter()
+            .find(|(sequence, _)| sequence == counter);
+        assert_eq!(counters.map(|(_, count)| count), Some(4));
+
+        for operators in [false, true] {
+            let options = CompilerOptions {
+                operators,
+                superinstructions: true,
+                ..CompilerOptions::default()
+            };
+            let fused = compile(&ast, &options).unwrap();
+            let code = fused
+                .pool
+                .iter()
+                .filter_map(|constant| match constant {
+                    Constant::Function { code, .. } => Some(code.insert_point.iter()),
+                    _ => None,
+                })
+                .flatten();
+            let (increments, comparisons) = code.fold((0, 0), |(a, b), inst| match inst {
+                Bytecode::IncLocal { .. } => (a + 1, b),
+                Bytecode::CompareLocalsBranch { .. } => (a, b + 1),
+                _ => (a, b),
+            });
+            assert_eq!((increments, comparisons), (4, 2));
+            assert_eq!(run(&fused), run(&program));
+            assert_eq!(run(&resolve_labels(&fused).unwrap()), run(&program));
+        }
+    }
+}
@@ -0,0 +1,671 @@
+use crate::ast::{ensure_stack, Identifier, IntoBoxed, AST};
+
+/**
+ * Byte offset of the error in the source and its description.
+ */
+pub type ParseError = (usize, &'static str);
+
+// Names of variables can be `array`, it only makes an array when it's called.
+const KEYWORDS: [&str; 24] = [
+    "let", "function", "begin", "end", "if", "then", "else", "while", "do", "for", "in", "object",
+    "extends", "print", "true", "false", "null", "break", "continue", "throw", "try", "catch",
+    "finally", "import",
+];
+
+// Longer symbols come first, so they are matched before their prefixes.
+const SYMBOLS: [&str; 27] = [
+    "<-", "->", "..", "&&", "||", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "&",
+    "|", "!", "=", "(", ")", "[", "]", ",", ".", ";",
+];
+
+// Operators of the method calls written in infix form, from the weakest binding.
+const OPERATORS: [&[&str]; 6] = [
+    &["|"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Number(String),
+    // Contents of the literal with the escape sequences left in
+    String(String),
+    Symbol(&'static str),
+    Newline,
+    End,
+}
+
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let start = pos;
+        let c = bytes[pos];
+        let token = if c == b'\n' || c == b';' {
+            pos += 1;
+            match c {
+                b'\n' => Token::Newline,
+                _ => Token::Symbol(";"),
+            }
+        } else if c.is_ascii_whitespace() {
+            pos += 1;
+            continue;
+        } else if c.is_ascii_digit() {
+            while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+                pos += 1;
+            }
+            // A dot followed by another one is a range
+            if bytes.get(pos) == Some(&b'.') && bytes.get(pos + 1).is_some_and(u8::is_ascii_digit) {
+                pos += 1;
+                while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+                    pos += 1;
+                }
+            }
+            if matches!(bytes.get(pos), Some(b'e' | b'E')) {
+                let digits = match bytes.get(pos + 1) {
+                    Some(b'+' | b'-') => pos + 2,
+                    _ => pos + 1,
+                };
+                if bytes.get(digits).is_some_and(u8::is_ascii_digit) {
+                    pos = digits;
+                    while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+                        pos += 1;
+                    }
+                }
+            }
+            Token::Number(text[start..pos].to_string())
+        } else if c.is_ascii_alphabetic() || c == b'_' || !c.is_ascii() {
+            while pos < bytes.len()
+                && (bytes[pos].is_ascii_alphanumeric()
+                    || bytes[pos] == b'_'
+                    || !bytes[pos].is_ascii())
+            {
+                pos += 1;
+            }
+            Token::Identifier(text[start..pos].to_string())
+        } else if c == b'"' {
+            pos += 1;
+            loop {
+                match bytes.get(pos) {
+                    None => return Err((start, "Unterminated string.")),
+                    Some(b'"') => break,
+                    Some(b'\\') => pos += 2,
+                    Some(_) => pos += 1,
+                }
+            }
+            pos += 1;
+            Token::String(text[start + 1..pos - 1].to_string())
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| text[pos..].starts_with(**symbol))
+                .ok_or((pos, "Unexpected character."))?;
+            pos += symbol.len();
+            Token::Symbol(symbol)
+        };
+        tokens.push((token, start));
+    }
+    tokens.push((Token::End, text.len()));
+    Ok(tokens)
+}
+
+/**
+ * Replaces the escape sequences of a string literal by the characters.
+ */
+fn unescape_string(literal: &str) -> String {
+    let mut result = String::new();
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(c) => result.push(c),
+                None => result.push('\\'),
+            },
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/**
+ * Print formats keep their escape sequences, except for the quotes.
+ */
+fn unescape_format(literal: &str) -> String {
+    literal.replace("\\\"", "\"")
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn token(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.token().clone();
+        if token != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn skip_newlines(&mut self) {
+        while *self.token() == Token::Newline {
+            self.pos += 1;
+        }
+    }
+
+    /**
+     * Checks whether the current token is the symbol or keyword.
+     */
+    fn is(&self, word: &str) -> bool {
+        match self.token() {
+            Token::Symbol(symbol) => *symbol == word,
+            Token::Identifier(name) => name == word && KEYWORDS.contains(&word),
+            _ => false,
+        }
+    }
+
+    fn eat(&mut self, word: &str) -> bool {
+        let found = self.is(word);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, word: &str, message: &'static str) -> Result<(), ParseError> {
+        self.skip_newlines();
+        match self.eat(word) {
+            true => Ok(()),
+            false => Err((self.offset(), message)),
+        }
+    }
+
+    /**
+     * Takes a keyword which continues the expression, even from the next line.
+     */
+    fn eat_continuation(&mut self, word: &str) -> bool {
+        let pos = self.pos;
+        self.skip_newlines();
+        if !self.eat(word) {
+            self.pos = pos;
+            return false;
+        }
+        true
+    }
+
+    fn identifier(&mut self) -> Result<Identifier, ParseError> {
+        self.skip_newlines();
+        match self.token() {
+            Token::Identifier(name) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(Identifier(name))
+            }
+            _ => Err((self.offset(), "Expected an identifier.")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.skip_newlines();
+        match self.advance() {
+            Token::String(literal) => Ok(literal),
+            _ => Err((self.offset(), "Expected a string.")),
+        }
+    }
+
+    /**
+     * Parses the expressions separated by new lines or semicolons, up to
+     * the keyword, or the end of the source if there is none.
+     */
+    fn statements(&mut self, end: Option<&str>) -> Result<Vec<AST>, ParseError> {
+        let mut items = Vec::new();
+        loop {
+            while *self.token() == Token::Newline || self.is(";") {
+                self.pos += 1;
+            }
+            match end {
+                Some(end) if self.eat(end) => return Ok(items),
+                None if *self.token() == Token::End => return Ok(items),
+                _ if *self.token() == Token::End => return Err((self.offset(), "Expected 'end'.")),
+                _ => (),
+            }
+            items.push(self.statement()?);
+            if !(*self.token() == Token::Newline
+                || self.is(";")
+                || end.is_some_and(|end| self.is(end))
+                || *self.token() == Token::End)
+            {
+                return Err((self.offset(), "Expected a new line or ';'."));
+            }
+        }
+    }
+
+    fn arguments(&mut self) -> Result<Vec<AST>, ParseError> {
+        let mut arguments = Vec::new();
+        self.skip_newlines();
+        if self.eat(")") {
+            return Ok(arguments);
+        }
+        loop {
+            arguments.push(self.statement()?);
+            self.skip_newlines();
+            if self.eat(")") {
+                return Ok(arguments);
+            }
+            self.expect(",", "Expected ',' or ')'.")?;
+        }
+    }
+
+    /**
+     * Checks whether the token can begin an expression, which is the value of break.
+     */
+    fn starts_expression(&self) -> bool {
+        match self.token() {
+            Token::Identifier(name) => !matches!(
+                name.as_str(),
+                "end" | "then" | "else" | "do" | "in" | "extends" | "catch" | "finally"
+            ),
+            Token::Number(_) | Token::String(_) => true,
+            Token::Symbol(symbol) => matches!(*symbol, "(" | "!" | "-"),
+            Token::Newline | Token::End => false,
+        }
+    }
+
+    fn statement(&mut self) -> Result<AST, ParseError> {
+        self.skip_newlines();
+        ensure_stack(|| self.unstacked_statement())
+    }
+
+    fn unstacked_statement(&mut self) -> Result<AST, ParseError> {
+        if self.eat("let") {
+            let name = self.identifier()?;
+            self.expect("=", "Expected '='.")?;
+            let value = self.statement()?.into_boxed();
+            Ok(AST::Variable { name, value })
+        } else if self.eat("function") {
+            let name = self.identifier()?;
+            self.expect("(", "Expected '('.")?;
+            let mut parameters = Vec::new();
+            self.skip_newlines();
+            if !self.eat(")") {
+                loop {
+                    parameters.push(self.identifier()?);
+                    self.skip_newlines();
+                    if self.eat(")") {
+                        break;
+                    }
+                    self.expect(",", "Expected ',' or ')'.")?;
+                }
+            }
+            self.expect("->", "Expected '->'.")?;
+            let body = self.statement()?.into_boxed();
+            Ok(AST::Function {
+                name,
+                parameters,
+                body,
+            })
+        } else if self.eat("while") {
+            let condition = self.statement()?.into_boxed();
+            self.expect("do", "Expected 'do'.")?;
+            let body = self.statement()?.into_boxed();
+            Ok(AST::Loop { condition, body })
+        } else if self.eat("for") {
+            let variable = self.identifier()?;
+            self.expect("in", "Expected 'in'.")?;
+            let from = self.or()?.into_boxed();
+            let to = match self.eat("..") {
+                true => Some(self.or()?.into_boxed()),
+                false => None,
+            };
+            self.expect("do", "Expected 'do'.")?;
+            let body = self.statement()?.into_boxed();
+            Ok(match to {
+                Some(to) => AST::For {
+                    variable,
+                    from,
+                    to,
+                    body,
+                },
+                None => AST::ForEach {
+                    variable,
+                    array: from,
+                    body,
+                },
+            })
+        } else if self.eat("if") {
+            let condition = self.statement()?.into_boxed();
+            self.expect("then", "Expected 'then'.")?;
+            let consequent = self.statement()?.into_boxed();
+            let alternative = match self.eat_continuation("else") {
+                true => self.statement()?,
+                false => AST::Null,
+            };
+            Ok(AST::Conditional {
+                condition,
+                consequent,
+                alternative: alternative.into_boxed(),
+            })
+        } else if self.eat("try") {
+            let body = self.statement()?.into_boxed();
+            self.expect("catch", "Expected 'catch'.")?;
+            let catch_var = self.identifier()?;
+            self.expect("->", "Expected '->'.")?;
+            let handler = self.statement()?.into_boxed();
+            let finally = match self.eat_continuation("finally") {
+                true => Some(self.statement()?.into_boxed()),
+                false => None,
+            };
+            Ok(AST::Try {
+                body,
+                catch_var,
+                handler,
+                finally,
+            })
+        } else if self.eat("throw") {
+            let value = self.statement()?.into_boxed();
+            Ok(AST::Throw { value })
+        } else if self.eat("break") {
+            let value = match self.starts_expression() {
+                true => Some(self.statement()?.into_boxed()),
+                false => None,
+            };
+            Ok(AST::Break { value })
+        } else if self.eat("import") {
+            let path = unescape_string(&self.string()?);
+            Ok(AST::Import { path })
+        } else {
+            let start = self.offset();
+            let mut target = self.or()?;
+            if !self.eat("<-") {
+                return Ok(target);
+            }
+            let value = self.statement()?.into_boxed();
+            let take = |ast: &mut Box<AST>| std::mem::replace(ast, AST::Null.into_boxed());
+            match &mut target {
+                AST::AccessVariable { name } => Ok(AST::AssignVariable {
+                    name: name.clone(),
+                    value,
+                }),
+                AST::AccessField { object, field } => Ok(AST::AssignField {
+                    object: take(object),
+                    field: field.clone(),
+                    value,
+                }),
+                AST::AccessArray { array, index } => Ok(AST::AssignArray {
+                    array: take(array),
+                    index: take(index),
+                    value,
+                }),
+                _ => Err((
+                    start,
+                    "Only variables, fields and elements can be assigned.",
+                )),
+            }
+        }
+    }
+
+    fn or(&mut self) -> Result<AST, ParseError> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            let right = self.and()?.into_boxed();
+            left = AST::Or {
+                left: left.into_boxed(),
+                right,
+            };
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<AST, ParseError> {
+        let mut left = self.binary(0)?;
+        while self.eat("&&") {
+            let right = self.binary(0)?.into_boxed();
+            left = AST::And {
+                left: left.into_boxed(),
+                right,
+            };
+        }
+        Ok(left)
+    }
+
+    /**
+     * Parses the operators of the level and the ones binding stronger.
+     * They are left associative.
+     */
+    fn binary(&mut self, level: usize) -> Result<AST, ParseError> {
+        if level == OPERATORS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(operator) = OPERATORS[level].iter().find(|operator| self.is(operator)) {
+            self.pos += 1;
+            let right = self.binary(level + 1)?.into_boxed();
+            left = AST::CallMethod {
+                object: left.into_boxed(),
+                name: Identifier(operator.to_string()),
+                arguments: vec![right],
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<AST, ParseError> {
+        self.skip_newlines();
+        if self.eat("!") {
+            let operand = ensure_stack(|| self.unary())?.into_boxed();
+            return Ok(AST::Not { operand });
+        }
+        let mut ast = self.primary()?;
+        loop {
+            if self.eat(".") {
+                let name = self.identifier()?;
+                ast = match self.eat("(") {
+                    true => AST::CallMethod {
+                        object: ast.into_boxed(),
+                        name,
+                        arguments: self.arguments()?.into_iter().map(Box::new).collect(),
+                    },
+                    false => AST::AccessField {
+                        object: ast.into_boxed(),
+                        field: name,
+                    },
+                };
+            } else if self.eat("[") {
+                let index = self.statement()?.into_boxed();
+                self.expect("]", "Expected ']'.")?;
+                ast = AST::AccessArray {
+                    array: ast.into_boxed(),
+                    index,
+                };
+            } else {
+                return Ok(ast);
+            }
+        }
+    }
+
+    fn number(&mut self, negative: bool) -> Result<AST, ParseError> {
+        let offset = self.offset();
+        let digits = match self.advance() {
+            Token::Number(digits) => digits,
+            _ => return Err((offset, "Expected a number.")),
+        };
+        let literal = match negative {
+            true => format!("-{}", digits),
+            false => digits,
+        };
+        if literal.contains(['.', 'e', 'E']) {
+            return literal
+                .parse()
+                .map(AST::Float)
+                .map_err(|_| (offset, "Invalid number."));
+        }
+        literal
+            .parse()
+            .map(AST::Integer)
+            .map_err(|_| (offset, "Integer is too large."))
+    }
+
+    fn primary(&mut self) -> Result<AST, ParseError> {
+        self.skip_newlines();
+        let offset = self.offset();
+        match self.token().clone() {
+            Token::Number(_) => self.number(false),
+            Token::Symbol("-") => {
+                self.pos += 1;
+                self.number(true)
+            }
+            Token::String(literal) => {
+                self.pos += 1;
+                Ok(AST::String(unescape_string(&literal)))
+            }
+            Token::Symbol("(") => {
+                self.pos += 1;
+                let ast = self.statement()?;
+                self.expect(")", "Expected ')'.")?;
+                Ok(ast)
+            }
+            Token::Identifier(name) => {
+                self.pos += 1;
+                match name.as_str() {
+                    "true" => Ok(AST::Boolean(true)),
+                    "false" => Ok(AST::Boolean(false)),
+                    "null" => Ok(AST::Null),
+                    "continue" => Ok(AST::Continue),
+                    "begin" => Ok(AST::Block(
+                        self.statements(Some("end"))?
+                            .into_iter()
+                            .map(Box::new)
+                            .collect(),
+                    )),
+                    "object" => {
+                        let extends = match self.eat_continuation("extends") {
+                            true => ensure_stack(|| self.unary())?,
+                            false => AST::Null,
+                        };
+                        self.expect("begin", "Expected 'begin'.")?;
+                        Ok(AST::Object {
+                            extends: extends.into_boxed(),
+                            members: self
+                                .statements(Some("end"))?
+                                .into_iter()
+                                .map(Box::new)
+                                .collect(),
+                        })
+                    }
+                    "array" if self.eat("(") => {
+                        let size = self.statement()?.into_boxed();
+                        self.expect(",", "Expected ','.")?;
+                        let value = self.statement()?.into_boxed();
+                        self.expect(")", "Expected ')'.")?;
+                        Ok(AST::Array { size, value })
+                    }
+                    "print" => {
+                        self.expect("(", "Expected '('.")?;
+                        let format = unescape_format(&self.string()?);
+                        self.skip_newlines();
+                        let arguments = match self.eat(",") {
+                            true => self.arguments()?.into_iter().map(Box::new).collect(),
+                            false => {
+                                self.expect(")", "Expected ',' or ')'.")?;
+                                Vec::new()
+                            }
+                        };
+                        Ok(AST::Print { format, arguments })
+                    }
+                    // Statements in the place of an operand take the rest of it.
+                    "let" | "function" | "while" | "for" | "if" | "try" | "throw" | "break"
+                    | "import" => {
+                        self.pos -= 1;
+                        self.statement()
+                    }
+                    _ if KEYWORDS.contains(&name.as_str()) => {
+                        Err((offset, "Expected an expression."))
+                    }
+                    _ if self.eat("(") => Ok(AST::CallFunction {
+                        name: Identifier(name),
+                        arguments: self.arguments()?.into_iter().map(Box::new).collect(),
+                    }),
+                    _ => Ok(AST::AccessVariable {
+                        name: Identifier(name),
+                    }),
+                }
+            }
+            _ => Err((offset, "Expected an expression.")),
+        }
+    }
+}
+
+/**
+ * Parses the FML source into the tree of the whole program.
+ */
+pub fn parse(text: &str) -> Result<AST, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    let items = parser.statements(None)?;
+    Ok(AST::Top(items.into_iter().map(Box::new).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer::to_source;
+
+    #[test]
+    fn round_trip_test() {
+        let source = "\
+import \"lib.fml\"
+let o = object extends p begin
+    let x = -1.5
+    function m(a) -> begin
+        print(\"a\\n~ \\\"~\\\"\", (a + 1) * this.x, \"\\\"q\\\"\")
+    end
+end
+
+function f(n) -> if n < 2 && !(n == 0) then n else try f(n - 1).m(n)[2] catch e -> e finally null
+
+a[0] <- o.x <- x <- array(3, for i in 0 .. n do if i % 2 == 0 then continue else break i)
+for y in a do while y | false do (let z = y) + -3
+throw begin end
+";
+        let tree = parse(source).unwrap();
+        assert_eq!(to_source(&tree), source);
+
+        // The tree of the source comes back from the printed source
+        let json = serde_json::to_string(&tree).unwrap();
+        let reparsed = serde_json::to_string(&parse(&to_source(&tree)).unwrap()).unwrap();
+        assert_eq!(json, reparsed);
+
+        let prelude = AST::from_json(include_str!("prelude.json")).unwrap();
+        let reparsed = parse(&to_source(&prelude)).unwrap();
+        assert_eq!(
+            serde_json::to_string(&prelude).unwrap(),
+            serde_json::to_string(&reparsed).unwrap()
+        );
+
+        let tree = parse("begin f(1); g(\n2)\nend").unwrap();
+        assert_eq!(to_source(&tree), "begin\n    f(1)\n    g(2)\nend\n");
+        assert_eq!(parse("let x = ").unwrap_err().1, "Expected an expression.");
+        assert_eq!(
+            parse("1 <- 2").unwrap_err(),
+            (0, "Only variables, fields and elements can be assigned.")
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::ast::{ensure_stack, AST};
+use std::collections::{HashMap, HashSet};
+
+/**
+ * Functions available to every program, unless compiled with `--no-prelude`.
+ */
+const PRELUDE: &str = include_str!("prelude.json");
+
+fn functions(ast: &AST) -> impl Iterator<Item = (&str, &AST)> {
+    let items = match ast {
+        AST::Top(items) => items.as_slice(),
+        _ => &[],
+    };
+    items.iter().filter_map(|item| match &**item {
+        AST::Function { name, .. } => Some((name.as_str(), &**item)),
+        _ => None,
+    })
+}
+
+fn called_functions<'a>(ast: &'a AST, called: &mut Vec<&'a str>) {
+    if let AST::CallFunction { name, .. } = ast {
+        called.push(name.as_str());
+    }
+    for child in ast.children() {
+        ensure_stack(|| called_functions(child, called));
+    }
+}
+
+/**
+ * Names of the globals a program defines and of the functions it calls,
+ * gathered item by item, so the program doesn't have to be in memory at once.
+ */
+#[derive(Default)]
+pub struct Usage {
+    defined: HashSet<String>,
+    called: HashSet<String>,
+}
+
+impl Usage {
+    pub fn add_item(&mut self, item: &AST) {
+        match item {
+            AST::Function { name, .. } | AST::Variable { name, .. } => {
+                self.defined.insert(name.0.clone());
+            }
+            _ => (),
+        }
+        self.add_calls(item);
+    }
+
+    fn add_calls(&mut self, ast: &AST) {
+        let mut called = Vec::new();
+        called_functions(ast, &mut called);
+        self.called.extend(called.into_iter().map(String::from));
+    }
+
+    /**
+     * Returns definitions of the prelude functions that the program calls, directly
+     * or through other prelude functions. Functions and global variables defined
+     * by the program take precedence over the prelude ones.
+     */
+    pub fn used_functions(&self) -> Vec<Box<AST>> {
+        let prelude: AST = serde_json::from_str(PRELUDE).expect("Prelude is not a valid AST");
+        let available: HashMap<&str, &AST> = functions(&prelude).collect();
+
+        let mut called: Vec<&str> = self.called.iter().map(String::as_str).collect();
+        let mut used = HashSet::new();
+        while let Some(name) = called.pop() {
+            if self.defined.contains(name) || used.contains(name) {
+                continue;
+            }
+            if let Some(function) = available.get(name) {
+                used.insert(name);
+                called_functions(function, &mut called);
+            }
+        }
+
+        // Keep the order of the prelude, so the output is deterministic.
+        functions(&prelude)
+            .filter(|(name, _)| used.contains(name))
+            .map(|(_, function)| Box::new(function.clone()))
+            .collect()
+    }
+}
+
+/**
+ * Returns definitions of the prelude functions that the modules call.
+ */
+pub fn used_functions(modules: &[&AST]) -> Vec<Box<AST>> {
+    let mut usage = Usage::default();
+    for module in modules.iter() {
+        match module {
+            AST::Top(items) => items.iter().for_each(|item| usage.add_item(item)),
+            module => usage.add_calls(module),
+        }
+    }
+    usage.used_functions()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Identifier, IntoBoxed};
+
+    fn call(name: &str) -> Box<AST> {
+        AST::CallFunction {
+            name: Identifier(String::from(name)),
+            arguments: vec![],
+        }
+        .into_boxed()
+    }
+
+    #[test]
+    fn used_functions_test() {
+        let program = AST::Top(vec![
+            AST::Function {
+                name: Identifier(String::from("min")),
+                parameters: vec![],
+                body: call("max"),
+            }
+            .into_boxed(),
+            call("min"),
+            call("print_array"),
+        ]);
+
+        let names: Vec<String> = used_functions(&[&program])
+            .iter()
+            .map(|function| match &**function {
+                AST::Function { name, .. } => name.0.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(names, vec!["max", "print_array"]);
+    }
+}
@@ -0,0 +1,466 @@
+use crate::ast::{ensure_stack, AST};
+
+const INDENT: &str = "    ";
+
+// Binding strength of expressions, parentheses are added around operands
+// binding weaker than their position requires.
+const STATEMENT: u8 = 0;
+const OR: u8 = 1;
+const AND: u8 = 2;
+const UNARY: u8 = 9;
+const POSTFIX: u8 = 10;
+
+/**
+ * Returns the binding strength of a method call written as an infix operator.
+ */
+fn operator_precedence(name: &str) -> Option<u8> {
+    match name {
+        "|" => Some(3),
+        "&" => Some(4),
+        "==" | "!=" => Some(5),
+        "<" | "<=" | ">" | ">=" => Some(6),
+        "+" | "-" => Some(7),
+        "*" | "/" | "%" => Some(8),
+        _ => None,
+    }
+}
+
+fn precedence(ast: &AST) -> u8 {
+    match ast {
+        AST::Or { .. } => OR,
+        AST::And { .. } => AND,
+        AST::Not { .. } => UNARY,
+        AST::CallMethod {
+            name, arguments, ..
+        } if arguments.len() == 1 => operator_precedence(name.as_str()).unwrap_or(POSTFIX),
+        AST::Integer(_)
+        | AST::Float(_)
+        | AST::Boolean(_)
+        | AST::String(_)
+        | AST::Null
+        | AST::Array { .. }
+        | AST::Object { .. }
+        | AST::AccessVariable { .. }
+        | AST::AccessField { .. }
+        | AST::AccessArray { .. }
+        | AST::CallFunction { .. }
+        | AST::CallMethod { .. }
+        | AST::Block(_)
+        | AST::Print { .. }
+        | AST::Continue => POSTFIX,
+        _ => STATEMENT,
+    }
+}
+
+/**
+ * Returns the innermost expression the rendering of the node ends with.
+ * If it is an unfinished if or try, a following else or finally would
+ * belong to it.
+ */
+fn trailing(ast: &AST) -> &AST {
+    match ast {
+        AST::Variable { value, .. }
+        | AST::AssignVariable { value, .. }
+        | AST::AssignField { value, .. }
+        | AST::AssignArray { value, .. }
+        | AST::Throw { value }
+        | AST::Break { value: Some(value) } => trailing(value),
+        AST::Function { body, .. }
+        | AST::Loop { body, .. }
+        | AST::For { body, .. }
+        | AST::ForEach { body, .. } => trailing(body),
+        AST::Conditional { alternative, .. } if !matches!(**alternative, AST::Null) => {
+            trailing(alternative)
+        }
+        AST::Try {
+            finally: Some(finally),
+            ..
+        } => trailing(finally),
+        _ => ast,
+    }
+}
+
+fn is_unfinished(ast: &AST) -> bool {
+    matches!(
+        trailing(ast),
+        AST::Conditional { .. } | AST::Try { finally: None, .. }
+    )
+}
+
+/**
+ * Escapes a string value so it reads back as the same value.
+ */
+fn escape_string(str: &str) -> String {
+    let mut result = String::new();
+    for c in str.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/**
+ * Print formats keep their escape sequences, only characters which can't
+ * appear in the literal as they are get escaped.
+ */
+fn escape_format(format: &str) -> String {
+    let mut result = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                result.push('\\');
+                result.push(chars.next().unwrap_or('\\'));
+            }
+            '"' => result.push_str("\\\""),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+struct Printer {
+    output: String,
+    indent: usize,
+}
+
+impl Printer {
+    fn newline(&mut self) {
+        self.output.push('\n');
+        for _ in 0..self.indent {
+            self.output.push_str(INDENT);
+        }
+    }
+
+    fn list(&mut self, items: &[Box<AST>]) {
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.output.push_str(", ");
+            }
+            self.expression(item, STATEMENT);
+        }
+    }
+
+    fn statements(&mut self, items: &[Box<AST>]) {
+        self.indent += 1;
+        for item in items.iter() {
+            self.newline();
+            self.expression(item, STATEMENT);
+        }
+        self.indent -= 1;
+        self.newline();
+    }
+
+    fn expression(&mut self, ast: &AST, required: u8) {
+        let parenthesize = precedence(ast) < required;
+        if parenthesize {
+            self.output.push('(');
+        }
+        ensure_stack(|| self.unparenthesized(ast));
+        if parenthesize {
+            self.output.push(')');
+        }
+    }
+
+    /**
+     * Prints a part followed by a keyword, which must not be taken by
+     * an if or try inside of it.
+     */
+    fn enclosed(&mut self, ast: &AST) {
+        match is_unfinished(ast) {
+            true => self.expression(ast, POSTFIX),
+            false => self.expression(ast, STATEMENT),
+        }
+    }
+
+    fn binary(&mut self, left: &AST, operator: &str, right: &AST, precedence: u8) {
+        // Operators are left associative
+        self.expression(left, precedence);
+        self.output.push(' ');
+        self.output.push_str(operator);
+        self.output.push(' ');
+        self.expression(right, precedence + 1);
+    }
+
+    fn unparenthesized(&mut self, ast: &AST) {
+        match ast {
+            AST::Integer(value) => self.output.push_str(&value.to_string()),
+            // Debug formatting keeps the decimal point
+            AST::Float(value) => self.output.push_str(&format!("{:?}", value)),
+            AST::Boolean(value) => self.output.push_str(&value.to_string()),
+            AST::String(value) => {
+                self.output.push('"');
+                self.output.push_str(&escape_string(value));
+                self.output.push('"');
+            }
+            AST::Null => self.output.push_str("null"),
+            AST::Variable { name, value } => {
+                self.output.push_str(&format!("let {} = ", name.as_str()));
+                self.expression(value, STATEMENT);
+            }
+            AST::Array { size, value } => {
+                self.output.push_str("array(");
+                self.expression(size, STATEMENT);
+                self.output.push_str(", ");
+                self.expression(value, STATEMENT);
+                self.output.push(')');
+            }
+            AST::Object { extends, members } => {
+                self.output.push_str("object ");
+                if !matches!(**extends, AST::Null) {
+                    self.output.push_str("extends ");
+                    self.expression(extends, POSTFIX);
+                    self.output.push(' ');
+                }
+                self.output.push_str("begin");
+                self.statements(members);
+                self.output.push_str("end");
+            }
+            AST::AccessVariable { name } => self.output.push_str(name.as_str()),
+            AST::AccessField { object, field } => {
+                self.expression(object, POSTFIX);
+                self.output.push('.');
+                self.output.push_str(field.as_str());
+            }
+            AST::AccessArray { array, index } => {
+                self.expression(array, POSTFIX);
+                self.output.push('[');
+                self.expression(index, STATEMENT);
+                self.output.push(']');
+            }
+            AST::AssignVariable { name, value } => {
+                self.output.push_str(&format!("{} <- ", name.as_str()));
+                self.expression(value, STATEMENT);
+            }
+            AST::AssignField {
+                object,
+                field,
+                value,
+            } => {
+                self.expression(object, POSTFIX);
+                self.output.push_str(&format!(".{} <- ", field.as_str()));
+                self.expression(value, STATEMENT);
+            }
+            AST::AssignArray {
+                array,
+                index,
+                value,
+            } => {
+                self.expression(array, POSTFIX);
+                self.output.push('[');
+                self.expression(index, STATEMENT);
+                self.output.push_str("] <- ");
+                self.expression(value, STATEMENT);
+            }
+            AST::Function {
+                name,
+                parameters,
+                body,
+            } => {
+                let parameters: Vec<&str> = parameters.iter().map(|p| p.as_str()).collect();
+                self.output.push_str(&format!(
+                    "function {}({}) -> ",
+                    name.as_str(),
+                    parameters.join(", ")
+                ));
+                self.expression(body, STATEMENT);
+            }
+            AST::CallFunction { name, arguments } => {
+                self.output.push_str(name.as_str());
+                self.output.push('(');
+                self.list(arguments);
+                self.output.push(')');
+            }
+            AST::CallMethod {
+                object,
+                name,
+                arguments,
+            } => match operator_precedence(name.as_str()) {
+                Some(precedence) if arguments.len() == 1 => {
+                    self.binary(object, name.as_str(), &arguments[0], precedence)
+                }
+                _ => {
+                    self.expression(object, POSTFIX);
+                    self.output.push('.');
+                    self.output.push_str(name.as_str());
+                    self.output.push('(');
+                    self.list(arguments);
+                    self.output.push(')');
+                }
+            },
+            AST::And { left, right } => self.binary(left, "&&", right, AND),
+            AST::Or { left, right } => self.binary(left, "||", right, OR),
+            AST::Not { operand } => {
+                self.output.push('!');
+                self.expression(operand, UNARY);
+            }
+            AST::Top(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    // Functions are separated by an empty line
+                    let function = |ast: &AST| matches!(ast, AST::Function { .. });
+                    if i > 0 && (function(item) || function(&items[i - 1])) {
+                        self.output.push('\n');
+                    }
+                    self.expression(item, STATEMENT);
+                    self.output.push('\n');
+                }
+            }
+            AST::Import { path } => self
+                .output
+                .push_str(&format!("import \"{}\"", escape_string(path))),
+            AST::Block(items) if items.is_empty() => self.output.push_str("begin end"),
+            AST::Block(items) => {
+                self.output.push_str("begin");
+                self.statements(items);
+                self.output.push_str("end");
+            }
+            AST::Loop { condition, body } => {
+                self.output.push_str("while ");
+                self.expression(condition, STATEMENT);
+                self.output.push_str(" do ");
+                self.expression(body, STATEMENT);
+            }
+            AST::For {
+                variable,
+                from,
+                to,
+                body,
+            } => {
+                self.output
+                    .push_str(&format!("for {} in ", variable.as_str()));
+                self.expression(from, OR);
+                self.output.push_str(" .. ");
+                self.expression(to, OR);
+                self.output.push_str(" do ");
+                self.expression(body, STATEMENT);
+            }
+            AST::ForEach {
+                variable,
+                array,
+                body,
+            } => {
+                self.output
+                    .push_str(&format!("for {} in ", variable.as_str()));
+                self.expression(array, OR);
+                self.output.push_str(" do ");
+                self.expression(body, STATEMENT);
+            }
+            AST::Break { value } => {
+                self.output.push_str("break");
+                if let Some(value) = value {
+                    self.output.push(' ');
+                    self.expression(value, STATEMENT);
+                }
+            }
+            AST::Continue => self.output.push_str("continue"),
+            AST::Conditional {
+                condition,
+                consequent,
+                alternative,
+            } => {
+                self.output.push_str("if ");
+                self.expression(condition, STATEMENT);
+                self.output.push_str(" then ");
+                match **alternative {
+                    AST::Null => self.expression(consequent, STATEMENT),
+                    _ => {
+                        self.enclosed(consequent);
+                        self.output.push_str(" else ");
+                        self.expression(alternative, STATEMENT);
+                    }
+                }
+            }
+            AST::Print { format, arguments } => {
+                self.output
+                    .push_str(&format!("print(\"{}\"", escape_format(format)));
+                if !arguments.is_empty() {
+                    self.output.push_str(", ");
+                    self.list(arguments);
+                }
+                self.output.push(')');
+            }
+            AST::Throw { value } => {
+                self.output.push_str("throw ");
+                self.expression(value, STATEMENT);
+            }
+            AST::Try {
+                body,
+                catch_var,
+                handler,
+                finally,
+            } => {
+                self.output.push_str("try ");
+                // Every try has a catch, so only a nested try takes it
+                match trailing(body) {
+                    AST::Try { .. } => self.expression(body, POSTFIX),
+                    _ => self.expression(body, STATEMENT),
+                }
+                self.output
+                    .push_str(&format!(" catch {} -> ", catch_var.as_str()));
+                match finally {
+                    Some(finally) => {
+                        self.enclosed(handler);
+                        self.output.push_str(" finally ");
+                        self.expression(finally, STATEMENT);
+                    }
+                    None => self.expression(handler, STATEMENT),
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Renders the tree as FML source indented by four spaces.
+ */
+pub fn to_source(ast: &AST) -> String {
+    let mut printer = Printer {
+        output: String::new(),
+        indent: 0,
+    };
+    printer.expression(ast, STATEMENT);
+    if !printer.output.ends_with('\n') {
+        printer.output.push('\n');
+    }
+    printer.output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_source_test() {
+        let ast: AST = serde_json::from_str(
+            r#"{"Top": [
+                {"Variable": {"name": "o", "value": {"Object": {"extends": {"AccessVariable": {"name": "p"}}, "members": [
+                    {"Variable": {"name": "x", "value": {"Integer": 1}}},
+                    {"Function": {"name": "m", "parameters": ["a"], "body": {"Block": [
+                        {"Print": {"format": "a\\n~", "arguments": [{"CallMethod": {"object":
+                            {"CallMethod": {"object": {"AccessVariable": {"name": "a"}}, "name": "+", "arguments": [{"Integer": 1}]}},
+                            "name": "*", "arguments": [{"AccessField": {"object": {"AccessVariable": {"name": "this"}}, "field": "x"}}]}}]}}
+                    ]}}}
+                ]}}}},
+                {"CallMethod": {"object": {"AccessVariable": {"name": "o"}}, "name": "m", "arguments": [{"String": "\"q\""}]}}
+            ]}"#,
+        )
+        .unwrap();
+        let expected = "\
+let o = object extends p begin
+    let x = 1
+    function m(a) -> begin
+        print(\"a\\n~\", (a + 1) * this.x)
+    end
+end
+o.m(\"\\\"q\\\"\")
+";
+        assert_eq!(to_source(&ast), expected);
+    }
+}
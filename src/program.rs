@@ -0,0 +1,406 @@
+use crate::bytecode::{Bytecode, Code};
+use crate::compiler::Globals;
+use crate::constants::{Constant, ConstantPool, ConstantPoolIndex};
+use crate::debug;
+use crate::serializer::*;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+// Bytecode files with a header start with these bytes. Headerless files
+// start with the length of the constant pool.
+pub const MAGIC: [u8; 4] = *b"\x7fFML";
+pub const VERSION: u16 = 1;
+
+// Feature flags of the header.
+pub const DEBUG_INFO: u16 = 0x0001;
+// Jumps and exception handlers use instruction offsets instead of labels.
+pub const RESOLVED_LABELS: u16 = 0x0004;
+const SUPPORTED_FLAGS: u16 = DEBUG_INFO | RESOLVED_LABELS;
+
+/**
+ * Compiled program, in the form in which it's written to the bytecode file.
+ */
+#[derive(Debug)]
+pub struct Program {
+    pub pool: ConstantPool,
+    pub globals: Globals,
+    // Index of the main function.
+    pub entry: ConstantPoolIndex,
+}
+
+impl Program {
+    pub fn has_debug_info(&self) -> bool {
+        self.pool.iter().any(|constant| {
+            matches!(constant, Constant::Function { code, .. } if !code.lines.is_empty() || !code.locals.is_empty() || !code.labels.is_empty())
+        })
+    }
+
+    pub fn has_resolved_labels(&self) -> bool {
+        self.pool.iter().any(|constant| match constant {
+            Constant::Function { code, .. } => {
+                !code.resolved_handlers.is_empty()
+                    || code.insert_point.iter().any(|inst| {
+                        matches!(
+                            inst,
+                            Bytecode::JumpTo { .. }
+                                | Bytecode::BranchTo { .. }
+                                | Bytecode::BranchFalseTo { .. }
+                        )
+                    })
+            }
+            _ => false,
+        })
+    }
+
+    /**
+     * Writes the program preceded by the header, which contains the magic
+     * bytes, format version, feature flags and length of the rest.
+     */
+    pub fn serialize_with_header<W: Write>(&self, output: &mut W) -> std::io::Result<()> {
+        let mut body = Vec::new();
+        self.serializable_byte(&mut body)?;
+        let mut flags = 0;
+        if self.has_debug_info() {
+            flags |= DEBUG_INFO;
+        }
+        if self.has_resolved_labels() {
+            flags |= RESOLVED_LABELS;
+        }
+
+        output.write_all(&MAGIC)?;
+        output.write_all(&VERSION.to_le_bytes())?;
+        output.write_all(&flags.to_le_bytes())?;
+        output.write_all(&(body.len() as u32).to_le_bytes())?;
+        output.write_all(&body)?;
+        Ok(())
+    }
+
+    fn deserialize_body<R: Read>(input: &mut R) -> std::io::Result<Self> {
+        let mut pool = ConstantPool::deserialize(input)?;
+        let globals = Globals::deserialize(input)?;
+        let entry = read_u16(input)?;
+        // Anything after the entry point is the debug section.
+        let mut rest = Vec::new();
+        input.read_to_end(&mut rest)?;
+        if !rest.is_empty() {
+            debug::read_section(&mut pool, &mut rest.as_slice())?;
+        }
+        let program = Program {
+            pool,
+            globals,
+            entry,
+        };
+        program.validate()?;
+        Ok(program)
+    }
+
+    /**
+     * Checks that the indexes in the program refer to constants of the right
+     * kind, and that jumps stay within their functions, so a malformed file is
+     * rejected when it's loaded instead of crashing the tools that use it.
+     */
+    fn validate(&self) -> std::io::Result<()> {
+        let pool = &self.pool;
+        if !matches!(constant(pool, self.entry), Some(Constant::Function { .. })) {
+            return Err(invalid_data("Entry point is not a function."));
+        }
+        for global in self.globals.globals.iter() {
+            if !matches!(
+                constant(pool, *global),
+                Some(Constant::Slot { .. } | Constant::Function { .. })
+            ) {
+                return Err(invalid_data("Global is neither a slot nor a function."));
+            }
+        }
+        for item in pool.iter() {
+            match item {
+                Constant::Slot { name } => expect_string(pool, *name)?,
+                Constant::Object { members } => {
+                    for member in members.iter() {
+                        if !matches!(
+                            constant(pool, *member),
+                            Some(Constant::Slot { .. } | Constant::Function { .. })
+                        ) {
+                            return Err(invalid_data("Member is neither a slot nor a method."));
+                        }
+                    }
+                }
+                Constant::Function {
+                    name,
+                    parameters,
+                    locals,
+                    code,
+                } => {
+                    expect_string(pool, *name)?;
+                    validate_code(pool, code, *parameters as u16 + *locals)?;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn constant(pool: &ConstantPool, index: ConstantPoolIndex) -> Option<&Constant> {
+    (index < pool.len()).then(|| pool.get(index))
+}
+
+fn expect_string(pool: &ConstantPool, index: ConstantPoolIndex) -> std::io::Result<()> {
+    match constant(pool, index) {
+        Some(Constant::String(_)) => Ok(()),
+        _ => Err(invalid_data("Name is not a string constant.")),
+    }
+}
+
+/**
+ * Checks the operands of the instructions and the exception handlers of the
+ * function, which has the given number of local slots.
+ */
+fn validate_code(pool: &ConstantPool, code: &Code, slots: u16) -> std::io::Result<()> {
+    let labels: HashSet<ConstantPoolIndex> = code
+        .insert_point
+        .iter()
+        .filter_map(|inst| match inst {
+            Bytecode::Label { name } => Some(*name),
+            _ => None,
+        })
+        .collect();
+    let expect_label = |label: &ConstantPoolIndex| match labels.contains(label) {
+        true => Ok(()),
+        false => Err(invalid_data("Jump to a label outside of the function.")),
+    };
+    let expect_target = |target: u32| match target as usize <= code.insert_point.len() {
+        true => Ok(()),
+        false => Err(invalid_data("Jump to an offset outside of the function.")),
+    };
+    let expect_literal = |index: ConstantPoolIndex| match constant(pool, index) {
+        Some(_) => Ok(()),
+        None => Err(invalid_data("Literal is not in the constant pool.")),
+    };
+    let expect_local = |index: u16| match index < slots {
+        true => Ok(()),
+        false => Err(invalid_data("Local index is out of the frame.")),
+    };
+
+    for (position, inst) in code.insert_point.iter().enumerate() {
+        if position + inst.fused_length() > code.insert_point.len() {
+            return Err(invalid_data("Superinstruction is truncated."));
+        }
+        match inst {
+            Bytecode::Literal { index } => expect_literal(*index)?,
+            Bytecode::IncLocal { index, amount } => {
+                expect_local(*index)?;
+                expect_literal(*amount)?;
+            }
+            Bytecode::GetLocal { index } | Bytecode::SetLocal { index } => expect_local(*index)?,
+            Bytecode::CompareLocalsBranch { left, right } => {
+                expect_local(*left)?;
+                expect_local(*right)?;
+                if !matches!(
+                    code.insert_point[position + 3],
+                    Bytecode::Branch { .. } | Bytecode::BranchTo { .. }
+                ) {
+                    return Err(invalid_data("Fused comparison has no branch."));
+                }
+            }
+            Bytecode::GetGlobal { name }
+            | Bytecode::SetGlobal { name }
+            | Bytecode::GetField { name }
+            | Bytecode::SetField { name }
+            | Bytecode::CallMethod { name, .. }
+            | Bytecode::CallFunction { name, .. }
+            | Bytecode::Print { format: name, .. }
+            | Bytecode::Label { name } => expect_string(pool, *name)?,
+            Bytecode::Object { class }
+                if !matches!(constant(pool, *class), Some(Constant::Object { .. })) =>
+            {
+                return Err(invalid_data("Object instruction refers to a non-object."));
+            }
+            Bytecode::Jump { label }
+            | Bytecode::Branch { label }
+            | Bytecode::BranchFalse { label } => expect_label(label)?,
+            Bytecode::JumpTo { target }
+            | Bytecode::BranchTo { target }
+            | Bytecode::BranchFalseTo { target } => expect_target(*target)?,
+            _ => (),
+        }
+    }
+    for handler in code.handlers.iter() {
+        expect_label(&handler.start)?;
+        expect_label(&handler.end)?;
+        expect_label(&handler.handler)?;
+    }
+    for handler in code.resolved_handlers.iter() {
+        expect_target(handler.start)?;
+        expect_target(handler.end)?;
+        expect_target(handler.handler)?;
+    }
+    Ok(())
+}
+
+impl Serializable for Program {
+    fn serializable_byte<W: Write>(&self, output: &mut W) -> std::io::Result<()> {
+        self.pool.serializable_byte(output)?;
+        self.globals.serializable_byte(output)?;
+        output.write_all(&self.entry.to_le_bytes())?;
+        // Debug section comes last, so runtimes which don't know it can stop reading.
+        if self.has_debug_info() {
+            debug::write_section(&self.pool, output)?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Reads the program either with the header, or in the headerless layout.
+ */
+impl Deserializable for Program {
+    fn deserialize<R: Read>(input: &mut R) -> std::io::Result<Self> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            // Headerless programs can start with any byte, a JSON AST only
+            // explains why the program couldn't be read.
+            return Program::deserialize_body(&mut magic.as_slice().chain(input)).map_err(|err| {
+                match magic[0] {
+                    b'{' | b'"' => invalid_data("Input is a JSON AST, not bytecode."),
+                    _ => err,
+                }
+            });
+        }
+
+        let version = read_u16(input)?;
+        if version > VERSION {
+            return Err(invalid_data("Unsupported bytecode format version."));
+        }
+        let flags = read_u16(input)?;
+        if flags & !SUPPORTED_FLAGS != 0 {
+            return Err(invalid_data("Bytecode uses unsupported features."));
+        }
+        let mut body = vec![0; read_u32(input)? as usize];
+        input
+            .read_exact(&mut body)
+            .map_err(|_| invalid_data("Bytecode file is truncated."))?;
+
+        let program = Program::deserialize_body(&mut body.as_slice())?;
+        if program.has_debug_info() != (flags & DEBUG_INFO != 0) {
+            return Err(invalid_data("Debug information doesn't match the header."));
+        }
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Identifier, IntoBoxed, AST};
+    use crate::compiler::{compile, CompilerOptions};
+
+    #[test]
+    fn round_trip_test() {
+        let ast = AST::Top(vec![AST::Try {
+            body: AST::Throw {
+                value: AST::Float(1.5).into_boxed(),
+            }
+            .into_boxed(),
+            catch_var: Identifier(String::from("e")),
+            handler: AST::Print {
+                format: String::from("~\\n"),
+                arguments: vec![AST::AccessVariable {
+                    name: Identifier(String::from("e")),
+                }
+                .into_boxed()],
+            }
+            .into_boxed(),
+            finally: None,
+        }
+        .into_boxed()]);
+        let program = compile(&ast, &CompilerOptions::default()).unwrap();
+
+        let mut bytes = Vec::new();
+        program.serializable_byte(&mut bytes).unwrap();
+        let loaded = Program::deserialize(&mut bytes.as_slice()).unwrap();
+        let mut reserialized = Vec::new();
+        loaded.serializable_byte(&mut reserialized).unwrap();
+
+        assert_eq!(bytes, reserialized);
+        assert_eq!(loaded.entry, program.entry);
+
+        let mut with_header = Vec::new();
+        program.serialize_with_header(&mut with_header).unwrap();
+        assert_eq!(with_header[..4], MAGIC);
+        let loaded = Program::deserialize(&mut with_header.as_slice()).unwrap();
+        assert_eq!(loaded.entry, program.entry);
+
+        with_header.pop();
+        assert!(Program::deserialize(&mut with_header.as_slice()).is_err());
+    }
+
+    #[test]
+    fn headerless_test() {
+        // The length of the pool is written first, 34 is the code of '"'.
+        let mut pool = ConstantPool::new();
+        for i in 0..32 {
+            pool.push(Constant::Integer(i));
+        }
+        let name = pool.push(Constant::from(String::from("main")));
+        let mut code = Code::new();
+        code.write_inst(Bytecode::Literal { index: 0 });
+        code.write_inst(Bytecode::Return);
+        let entry = pool.push(Constant::Function {
+            name,
+            parameters: 0,
+            locals: 0,
+            code,
+        });
+        let program = Program {
+            pool,
+            globals: Globals::new(),
+            entry,
+        };
+        let mut bytes = Vec::new();
+        program.serializable_byte(&mut bytes).unwrap();
+        assert_eq!(bytes[0], b'"');
+        let loaded = Program::deserialize(&mut bytes.as_slice()).unwrap();
+        assert_eq!(loaded.pool.len(), 34);
+
+        let json = br#"{"Top": []}"#;
+        let err = Program::deserialize(&mut json.as_slice()).unwrap_err();
+        assert_eq!(err.to_string(), "Input is a JSON AST, not bytecode.");
+    }
+
+    #[test]
+    fn invalid_index_test() {
+        let ast = AST::Top(vec![AST::Print {
+            format: String::from("~\\n"),
+            arguments: vec![AST::Integer(1).into_boxed()],
+        }
+        .into_boxed()]);
+        let load = |program: &Program| {
+            let mut bytes = Vec::new();
+            program.serializable_byte(&mut bytes).unwrap();
+            Program::deserialize(&mut bytes.as_slice())
+        };
+        let corrupt = |inst: Bytecode| {
+            let mut program = compile(&ast, &CompilerOptions::default()).unwrap();
+            program.pool.code_mut(program.entry).unwrap().insert_point[0] = inst;
+            load(&program)
+        };
+
+        let mut program = compile(&ast, &CompilerOptions::default()).unwrap();
+        assert!(load(&program).is_ok());
+        program.entry = program.pool.len();
+        assert!(load(&program).is_err());
+
+        let index = 0xFFFF;
+        assert!(corrupt(Bytecode::Literal { index }).is_err());
+        assert!(corrupt(Bytecode::CallFunction {
+            name: index,
+            arguments: 0
+        })
+        .is_err());
+        assert!(corrupt(Bytecode::Jump { label: 0 }).is_err());
+        assert!(corrupt(Bytecode::JumpTo { target: 100 }).is_err());
+        assert!(corrupt(Bytecode::GetLocal { index: 0 }).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use crate::ast::AST;
+use crate::compiler::IncrementalCompiler;
+use crate::interpreter::{Interpreter, Value};
+use crate::parser;
+use std::io::{self, BufRead, Write};
+
+/**
+ * Reads statements as FML source, one or more lines each, and executes
+ * them right away. Globals defined by the statements stay defined. Input
+ * starting with `{` is read as a JSON AST instead.
+ */
+pub fn repl() -> io::Result<()> {
+    let mut compiler = IncrementalCompiler::new();
+    let mut interpreter = Interpreter::new(io::stdout());
+    let mut input = String::new();
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "... " });
+        io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        input.push_str(&line);
+        input.push('\n');
+
+        let statement = if input.trim_start().starts_with('{') {
+            match AST::from_json(&input) {
+                Ok(statement) => statement,
+                // Statement continues on the next line
+                Err(err) if err.is_eof() => continue,
+                Err(err) => {
+                    eprintln!("Parse error: {}", err);
+                    input.clear();
+                    continue;
+                }
+            }
+        } else {
+            match parser::parse(&input) {
+                Ok(statement) => statement,
+                // Statement continues on the next line
+                Err((offset, message))
+                    if offset >= input.trim_end().len() || message == "Unterminated string." =>
+                {
+                    continue
+                }
+                Err((offset, message)) => {
+                    let line = input[..offset].matches('\n').count() + 1;
+                    eprintln!("Parse error on line {}: {}", line, message);
+                    input.clear();
+                    continue;
+                }
+            }
+        };
+        input.clear();
+
+        let mut statement = statement;
+        let statements = match &mut statement {
+            AST::Top(statements) => std::mem::take(statements).into_iter().map(|s| *s).collect(),
+            _ => vec![statement],
+        };
+        for statement in statements.iter() {
+            let function = match compiler.compile_statement(statement) {
+                Ok(function) => function,
+                Err(err) => {
+                    eprintln!("Compilation error: {}", err);
+                    break;
+                }
+            };
+            interpreter.load_globals(&compiler.pool, &compiler.globals.globals);
+            match interpreter.run(&compiler.pool, function) {
+                Ok(Value::Null) => (),
+                Ok(value) => println!("{}", value),
+                Err(exception) => {
+                    eprintln!("Uncaught exception: {}", exception);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::io::Write;
+use std::io::{Read, Write};
 
 pub trait Serializable {
     /**
@@ -6,3 +6,50 @@ pub trait Serializable {
      */
     fn serializable_byte<W: Write>(&self, output: &mut W) -> std::io::Result<()>;
 }
+
+pub trait Deserializable: Sized {
+    /**
+     * Reads back what `Serializable` wrote.
+     */
+    fn deserialize<R: Read>(input: &mut R) -> std::io::Result<Self>;
+}
+
+pub fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub fn read_u8<R: Read>(input: &mut R) -> std::io::Result<u8> {
+    let mut buf = [0; 1];
+    input.read_exact(&mut buf)?;
+    Ok(u8::from_le_bytes(buf))
+}
+
+pub fn read_u16<R: Read>(input: &mut R) -> std::io::Result<u16> {
+    let mut buf = [0; 2];
+    input.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+pub fn read_u32<R: Read>(input: &mut R) -> std::io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_i32<R: Read>(input: &mut R) -> std::io::Result<i32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+pub fn read_i64<R: Read>(input: &mut R) -> std::io::Result<i64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(i64::from_le_bytes(buf))
+}
+
+pub fn read_f64<R: Read>(input: &mut R) -> std::io::Result<f64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}